@@ -0,0 +1,19 @@
+use anyhow::Result;
+use crag_web::{async_server::Server, handler, request, response};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let server = Server::build()
+        .register_handler(request::Request::GET(String::from("/hello")), hello_handler)
+        .register_error_handler(|req| async { handler::default_error_404_handler(req) })?
+        .finalize(("127.0.0.1", 12345))
+        .await?;
+
+    server.run().await?;
+
+    Ok(())
+}
+
+async fn hello_handler(_req: request::Request) -> anyhow::Result<response::Response> {
+    Ok(response::Response::Ok("Hello world".to_string()))
+}
@@ -1,8 +1,7 @@
 use anyhow::Result;
 use crag_web::{handler, request, response, server::Server};
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     let server = Server::build()
         .register_handler(request::Request::GET(String::from("/hello")), hello_handler)
         .register_error_handler(handler::default_error_404_handler)?
@@ -0,0 +1,330 @@
+use crate::cache::{Lookup, ResponseCache};
+use crate::compression::Compression;
+use crate::conditional::Preconditions;
+use crate::handler::AsyncHandler;
+use crate::range::RangeRequest;
+use crate::request::Request;
+use crate::response::{self, Body, Chunks, Response};
+use crate::server::{self, HandlerMap, Handlers, Mount, RequestBuffer};
+use crate::static_files::ServeDir;
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tracing::error;
+
+/// Same routing, mounts and request parsing as `server::Server`, but
+/// connections run as tokio tasks and handlers are `async fn`s. Streamed
+/// request bodies and connection upgrades need the threaded server.
+/// Must be run from within a tokio runtime.
+pub struct Server {
+    tcp_listener: TcpListener,
+    handlers: Arc<Handlers<AsyncHandler>>,
+}
+
+pub struct ServerBuilder {
+    handlers: HandlerMap<AsyncHandler>,
+    mounts: Vec<(Request, Mount)>,
+    error_handler: Option<AsyncHandler>,
+    max_body_size: usize,
+    compression: Option<Compression>,
+    cache: Option<ResponseCache>,
+}
+
+impl ServerBuilder {
+    /// Finalize the server builder and create a server instance.
+    /// an error handler must always be defined or this will err.
+    pub async fn finalize(self, addr: impl ToSocketAddrs) -> Result<Server> {
+        let mut handlers = Handlers::new(self.handlers, self.error_handler)?;
+        handlers.set_mounts(self.mounts);
+        handlers.max_body_size = self.max_body_size;
+        handlers.compression = self.compression;
+        handlers.cache = self.cache;
+        let handlers = Arc::new(handlers);
+        let tcp_listener = TcpListener::bind(addr).await?;
+
+        Ok(Server {
+            tcp_listener,
+            handlers,
+        })
+    }
+
+    /// Refuse requests whose decoded body is larger than `max_body_size`
+    /// bytes, as with `server::ServerBuilder::max_body_size`
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Compress responses for clients that accept it, as with
+    /// `server::ServerBuilder::compression`
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Answer GET requests with the responses `cache` kept, as with
+    /// `server::ServerBuilder::cache`
+    pub fn cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn register_handler<F, Fut>(mut self, r: Request, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response>> + Send + 'static,
+    {
+        self.handlers.insert(r.route_key(), boxed(handler));
+        self
+    }
+
+    /// Serve the files of `dir` for GET requests under `prefix`, as with
+    /// `server::ServerBuilder::mount`. Files are looked up on tokio's
+    /// blocking threads.
+    pub fn mount(mut self, prefix: impl AsRef<str>, dir: ServeDir) -> Self {
+        server::add_mount(&mut self.mounts, prefix.as_ref(), dir);
+        self
+    }
+
+    pub fn register_error_handler<F, Fut>(mut self, handler: F) -> Result<Self>
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response>> + Send + 'static,
+    {
+        if self.error_handler.is_some() {
+            anyhow::bail!("Error handler already registered");
+        }
+        self.error_handler = Some(boxed(handler));
+        Ok(self)
+    }
+}
+
+fn boxed<F, Fut>(handler: F) -> AsyncHandler
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Response>> + Send + 'static,
+{
+    Box::new(move |req| Box::pin(handler(req)))
+}
+
+impl Server {
+    pub fn build() -> ServerBuilder {
+        ServerBuilder {
+            handlers: HandlerMap::new(),
+            mounts: Vec::new(),
+            error_handler: None,
+            max_body_size: server::DEFAULT_MAX_BODY_SIZE,
+            compression: None,
+            cache: None,
+        }
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        loop {
+            let (mut stream, _) = self.tcp_listener.accept().await?;
+            let handlers = self.handlers.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(&handlers, &mut stream).await {
+                    // Error boundary for the task handling the connection
+                    error!("Error handling connection: {e:?}");
+                    _ = stream.write_all(&server::error_response(&e)).await;
+                }
+            });
+        }
+    }
+}
+
+async fn handle_connection<S>(handlers: &Arc<Handlers<AsyncHandler>>, stream: &mut S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let req = read_and_parse_request(stream, handlers.max_body_size)
+        .await
+        .context("Error parsing request")?;
+
+    // build response
+    let preconditions = Preconditions::new(&req);
+    let range = RangeRequest::new(&req);
+    let accept_encoding = req.header("Accept-Encoding").map(str::to_string);
+    let response = match handlers.lookup(&req) {
+        Lookup::Hit(response) => response,
+        lookup => lookup.store(server::without_upgrade(run_handler(handlers, req).await?)?),
+    };
+    let response = handlers.respond(&preconditions, &range, accept_encoding.as_deref(), response);
+
+    // write response into TcpStream
+    write_response(stream, response).await
+}
+
+/// Run the handler registered for the request, or the mount the request
+/// falls under, or the error handler
+async fn run_handler(handlers: &Arc<Handlers<AsyncHandler>>, req: Request) -> Result<Response> {
+    if let Some(handler) = handlers.route(&req) {
+        return handler(req).await;
+    }
+    if handlers.mount_for(&req).is_none() {
+        return (handlers.error_handler())(req).await;
+    }
+    // serving a file opens and inspects it, which may block
+    let mounted = Arc::clone(handlers);
+    let (response, req) = tokio::task::spawn_blocking(move || {
+        let response = mounted.mount_for(&req).map(|mount| mount(&req));
+        (response, req)
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("Mount panicked"))?;
+    match response.transpose()?.flatten() {
+        Some(response) => Ok(response),
+        None => (handlers.error_handler())(req).await,
+    }
+}
+
+/// Write `response` as it is produced. A body that has to be read, like a
+/// file, is pulled a chunk at a time on tokio's blocking threads, so the
+/// runtime is never blocked on it and it is never held in memory whole.
+async fn write_response<S>(stream: &mut S, response: Response) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let (status, headers, body) = response.into_parts();
+    let mut out = BufWriter::new(stream);
+    out.write_all(&response::http1_head(status, &headers, &body))
+        .await?;
+    let (mut chunks, trailers, chunked) = match body {
+        Body::Full(body) => {
+            out.write_all(&body).await?;
+            out.flush().await?;
+            return Ok(());
+        }
+        Body::Stream { chunks, trailers } => (chunks, trailers, true),
+        Body::Seekable {
+            reader,
+            offset,
+            len,
+        } => (response::seekable_chunks(reader, offset, len), None, false),
+        Body::File { file, offset, len } => (
+            response::seekable_chunks(Box::new(file), offset, len),
+            None,
+            false,
+        ),
+        Body::Takeover(_) => {
+            anyhow::bail!(
+                "Responses taking over the connection are refused before they are written"
+            )
+        }
+    };
+
+    loop {
+        let (rest, next) = next_chunk(chunks).await?;
+        chunks = rest;
+        let chunk = match next {
+            Some(Ok(chunk)) => chunk,
+            None => break,
+            // the head is out, so the client can only learn about it from
+            // the body ending early
+            Some(Err(e)) => {
+                error!("Error producing response body: {e:?}");
+                out.flush().await?;
+                return Ok(());
+            }
+        };
+        if !chunked {
+            out.write_all(&chunk).await?;
+        } else if !chunk.is_empty() {
+            // an empty chunk would end the body early
+            out.write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+                .await?;
+            out.write_all(&chunk).await?;
+            out.write_all(b"\r\n").await?;
+        }
+    }
+
+    if chunked {
+        let trailers = match trailers {
+            Some(trailers) => tokio::task::spawn_blocking(trailers)
+                .await
+                .map_err(|_| anyhow::anyhow!("Response trailers panicked"))?,
+            None => Vec::new(),
+        };
+        out.write_all(b"0\r\n").await?;
+        for (name, value) in response::valid_trailers(trailers) {
+            out.write_all(format!("{name}: {value}\r\n").as_bytes())
+                .await?;
+        }
+        out.write_all(b"\r\n").await?;
+    }
+    out.flush().await?;
+    Ok(())
+}
+
+/// Produce the next chunk of a body on a blocking thread, since reading
+/// it may block
+async fn next_chunk(mut chunks: Chunks) -> Result<(Chunks, Option<std::io::Result<Vec<u8>>>)> {
+    tokio::task::spawn_blocking(move || {
+        let next = chunks.next();
+        (chunks, next)
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("Response body panicked"))
+}
+
+async fn read_and_parse_request(
+    stream: &mut (impl AsyncRead + Unpin),
+    max_body_size: usize,
+) -> Result<Request> {
+    let mut buffer = RequestBuffer::new(max_body_size);
+    let mut chunk = [0; 4096];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Connection closed before the request was complete");
+        }
+        buffer.extend(&chunk[..n]);
+
+        if let Some((req, _)) = buffer.parse()? {
+            return Ok(req);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler;
+
+    #[tokio::test]
+    async fn test_no_error_handler_fails() {
+        let server = Server::build()
+            .register_handler(Request::GET("/".to_owned()), |_req| async {
+                Ok(Response::Ok("Hello, Crag-Web!".to_string()))
+            })
+            .finalize(("127.0.0.1", 0))
+            .await;
+        assert!(server.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handle_connection_over_any_stream() -> Result<()> {
+        let server = Server::build()
+            .register_handler(Request::GET("/hello".to_owned()), |_req| async {
+                Ok(Response::Ok("Hello, Crag-Web!".to_string()))
+            })
+            .register_error_handler(|req| async { handler::default_error_404_handler(req) })?
+            .finalize(("127.0.0.1", 0))
+            .await?;
+
+        let (mut client, mut stream) = tokio::io::duplex(1024);
+        client.write_all(b"GET /hello HTTP/1.1\r\n\r\n").await?;
+        handle_connection(&server.handlers, &mut stream).await?;
+        drop(stream);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("Hello, Crag-Web!"), "{response}");
+        Ok(())
+    }
+}
@@ -0,0 +1,386 @@
+use std::io::{self, BufRead, Read};
+
+/// Longest chunk size or trailer line accepted in a chunked body
+const MAX_CHUNK_LINE: u64 = 8 * 1024;
+
+/// Most bytes of trailer fields accepted after a chunked body, as much as
+/// a request head may take
+const MAX_TRAILERS_SIZE: usize = 64 * 1024;
+
+/// How the end of a request body is found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Framing {
+    Length(usize),
+    Chunked,
+}
+
+/// What the HTTP/2 connection forwards of a stream's body
+#[cfg(feature = "http2")]
+pub(crate) enum Received {
+    Data(Vec<u8>),
+    Trailers(Vec<(String, String)>),
+}
+
+/// A request body read straight from the connection as the handler asks
+/// for it, for uploads too large to hold in memory. Reading stops at the
+/// end of the body, however it is framed; a client that goes away before
+/// sending all of it is an `UnexpectedEof` error.
+///
+/// ```no_run
+/// # use crag_web::{body::BodyReader, request::Request, response::Response};
+/// fn upload(req: Request, mut body: BodyReader) -> anyhow::Result<Response> {
+///     let mut file = std::fs::File::create("/tmp/artifact")?;
+///     let written = std::io::copy(&mut body, &mut file)?;
+///     Ok(Response::Ok(format!("{written} bytes")))
+/// }
+/// ```
+pub struct BodyReader<'a> {
+    source: Source<'a>,
+    content_length: Option<u64>,
+    trailers: Vec<(String, String)>,
+}
+
+enum Source<'a> {
+    Length {
+        reader: &'a mut (dyn BufRead + Send),
+        remaining: u64,
+    },
+    Chunked {
+        reader: &'a mut (dyn BufRead + Send),
+        state: Chunk,
+    },
+    #[cfg(feature = "http2")]
+    Channel {
+        received: tokio::sync::mpsc::Receiver<io::Result<Received>>,
+        data: io::Cursor<Vec<u8>>,
+    },
+}
+
+/// Where a chunked body is at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chunk {
+    Size,
+    Data(u64),
+    Done,
+}
+
+impl<'a> BodyReader<'a> {
+    /// The body framed by `framing` that `reader` is positioned at
+    pub(crate) fn new(reader: &'a mut (dyn BufRead + Send), framing: Framing) -> BodyReader<'a> {
+        let (source, content_length) = match framing {
+            Framing::Length(length) => (
+                Source::Length {
+                    reader,
+                    remaining: length as u64,
+                },
+                Some(length as u64),
+            ),
+            Framing::Chunked => (
+                Source::Chunked {
+                    reader,
+                    state: Chunk::Size,
+                },
+                None,
+            ),
+        };
+        BodyReader {
+            source,
+            content_length,
+            trailers: Vec::new(),
+        }
+    }
+
+    /// The body of an HTTP/2 stream, forwarded by its connection
+    #[cfg(feature = "http2")]
+    pub(crate) fn channel(
+        received: tokio::sync::mpsc::Receiver<io::Result<Received>>,
+        content_length: Option<u64>,
+    ) -> BodyReader<'static> {
+        BodyReader {
+            source: Source::Channel {
+                received,
+                data: io::Cursor::default(),
+            },
+            content_length,
+            trailers: Vec::new(),
+        }
+    }
+
+    /// The size the client announced, `None` for a chunked body
+    pub fn content_length(&self) -> Option<u64> {
+        self.content_length
+    }
+
+    /// Trailers sent after a chunked body, available once it was read to
+    /// the end
+    pub fn trailers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.trailers.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// Value of the first trailer named `name`, compared case-insensitively
+    pub fn trailer(&self, name: &str) -> Option<&str> {
+        self.trailers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub(crate) fn into_trailers(self) -> Vec<(String, String)> {
+        self.trailers
+    }
+}
+
+/// A chunked body decoded as its bytes arrive, for callers that can't
+/// block on the stream. Framing lines are only used up once they are
+/// complete, so decoding picks up where it stopped when more has arrived.
+pub(crate) struct ChunkedDecoder {
+    state: Chunk,
+    body: Vec<u8>,
+    trailers: Vec<(String, String)>,
+}
+
+impl ChunkedDecoder {
+    pub(crate) fn new() -> ChunkedDecoder {
+        ChunkedDecoder {
+            state: Chunk::Size,
+            body: Vec::new(),
+            trailers: Vec::new(),
+        }
+    }
+
+    /// Decode as much of `input` as is complete, returning how many of its
+    /// bytes were used. What is left over is less than a framing line or
+    /// the trailer section, so it stays small.
+    pub(crate) fn decode(&mut self, input: &[u8]) -> io::Result<usize> {
+        let mut rest = input;
+        loop {
+            let mut reader = rest;
+            let state = match self.state {
+                Chunk::Size => match read_chunk_size(&mut reader) {
+                    Ok(0) => read_trailers(&mut reader).map(|trailers| {
+                        self.trailers = trailers;
+                        Chunk::Done
+                    }),
+                    Ok(size) => Ok(Chunk::Data(size)),
+                    Err(e) => Err(e),
+                },
+                // the line ending the chunk's data
+                Chunk::Data(0) => read_chunk_line(&mut reader).and_then(|line| {
+                    if !line.is_empty() {
+                        return Err(invalid("Chunk longer than its size"));
+                    }
+                    Ok(Chunk::Size)
+                }),
+                Chunk::Data(remaining) if !reader.is_empty() => {
+                    let n = usize::try_from(remaining)
+                        .map_or(reader.len(), |remaining| remaining.min(reader.len()));
+                    self.body.extend_from_slice(&reader[..n]);
+                    reader = &reader[n..];
+                    Ok(Chunk::Data(remaining - n as u64))
+                }
+                Chunk::Data(_) | Chunk::Done => return Ok(input.len() - rest.len()),
+            };
+            match state {
+                Ok(state) => {
+                    self.state = state;
+                    rest = reader;
+                }
+                // the rest of the line hasn't arrived yet
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(input.len() - rest.len())
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Whether the whole body and its trailers were decoded
+    pub(crate) fn is_done(&self) -> bool {
+        self.state == Chunk::Done
+    }
+
+    /// How much of the body was decoded so far
+    pub(crate) fn body_len(&self) -> usize {
+        self.body.len()
+    }
+
+    pub(crate) fn into_parts(self) -> (Vec<u8>, Vec<(String, String)>) {
+        (self.body, self.trailers)
+    }
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match &mut self.source {
+            Source::Length { reader, remaining } => {
+                if *remaining == 0 {
+                    return Ok(0);
+                }
+                let n = reader.read(limit(buf, *remaining))?;
+                if n == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                *remaining -= n as u64;
+                Ok(n)
+            }
+            Source::Chunked { reader, state } => loop {
+                match *state {
+                    Chunk::Size => {
+                        let size = read_chunk_size(*reader)?;
+                        if size == 0 {
+                            self.trailers = read_trailers(*reader)?;
+                            *state = Chunk::Done;
+                        } else {
+                            *state = Chunk::Data(size);
+                        }
+                    }
+                    Chunk::Data(remaining) => {
+                        let n = reader.read(limit(buf, remaining))?;
+                        if n == 0 {
+                            return Err(io::ErrorKind::UnexpectedEof.into());
+                        }
+                        *state = match remaining - n as u64 {
+                            0 => {
+                                if !read_chunk_line(*reader)?.is_empty() {
+                                    return Err(invalid("Chunk longer than its size"));
+                                }
+                                Chunk::Size
+                            }
+                            remaining => Chunk::Data(remaining),
+                        };
+                        return Ok(n);
+                    }
+                    Chunk::Done => return Ok(0),
+                }
+            },
+            #[cfg(feature = "http2")]
+            Source::Channel { received, data } => loop {
+                let n = data.read(buf)?;
+                if n > 0 {
+                    return Ok(n);
+                }
+                match received.blocking_recv() {
+                    Some(Ok(Received::Data(chunk))) => *data = io::Cursor::new(chunk),
+                    Some(Ok(Received::Trailers(trailers))) => self.trailers = trailers,
+                    Some(Err(e)) => return Err(e),
+                    None => return Ok(0),
+                }
+            },
+        }
+    }
+}
+
+/// At most `remaining` bytes of `buf`
+fn limit(buf: &mut [u8], remaining: u64) -> &mut [u8] {
+    let len = usize::try_from(remaining).map_or(buf.len(), |remaining| remaining.min(buf.len()));
+    &mut buf[..len]
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The size line starting a chunk. Chunk extensions are skipped since
+/// nothing here understands them.
+fn read_chunk_size(reader: &mut dyn BufRead) -> io::Result<u64> {
+    let line = read_chunk_line(reader)?;
+    let size = line.split(';').next().unwrap_or_default().trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid(&format!("Invalid chunk size: {size}")));
+    }
+    u64::from_str_radix(size, 16).map_err(|_| invalid(&format!("Chunk size too large: {size}")))
+}
+
+/// The trailer section ending a chunked body
+fn read_trailers(reader: &mut dyn BufRead) -> io::Result<Vec<(String, String)>> {
+    let mut trailers = Vec::new();
+    let mut size = 0;
+    loop {
+        let line = read_chunk_line(reader)?;
+        if line.is_empty() {
+            return Ok(trailers);
+        }
+        size += line.len();
+        if size > MAX_TRAILERS_SIZE {
+            return Err(invalid("Trailer section too large"));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid(&format!("Invalid trailer line: {line}")))?;
+        trailers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+/// One line of chunk framing without its line ending. Running out of
+/// input before the line ends is an `UnexpectedEof` error.
+fn read_chunk_line(reader: &mut dyn BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    reader.take(MAX_CHUNK_LINE).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        if line.len() as u64 == MAX_CHUNK_LINE {
+            return Err(invalid("Chunk line too long"));
+        }
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    line.truncate(line.trim_end_matches(['\r', '\n']).len());
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunked_reader() -> io::Result<()> {
+        let mut raw: &[u8] =
+            b"5;name=value\r\nhello\r\n7\r\n, world\r\n0\r\nX-Checksum: 42\r\n\r\nnext";
+        let mut body = BodyReader::new(&mut raw, Framing::Chunked);
+        assert_eq!(body.content_length(), None);
+
+        // small reads don't lose track of the chunk boundaries
+        let mut decoded = Vec::new();
+        let mut buf = [0; 3];
+        loop {
+            match body.read(&mut buf)? {
+                0 => break,
+                n => decoded.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(decoded, b"hello, world");
+        assert_eq!(body.trailer("x-checksum"), Some("42"));
+        assert_eq!(raw, b"next");
+        Ok(())
+    }
+
+    #[test]
+    fn test_trailer_section_is_limited() {
+        let mut raw = b"0\r\n".to_vec();
+        while raw.len() <= 2 * MAX_TRAILERS_SIZE {
+            raw.extend_from_slice(b"X-Filler: aaaaaaaaaaaaaaaa\r\n");
+        }
+        raw.extend_from_slice(b"\r\n");
+        let mut raw = &raw[..];
+        let mut body = BodyReader::new(&mut raw, Framing::Chunked);
+        let e = io::read_to_string(&mut body).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_length_reader() -> io::Result<()> {
+        let mut raw: &[u8] = b"hellonext";
+        let mut body = BodyReader::new(&mut raw, Framing::Length(5));
+        assert_eq!(body.content_length(), Some(5));
+        assert_eq!(io::read_to_string(&mut body)?, "hello");
+        assert_eq!(raw, b"next");
+
+        // the client went away early
+        let mut raw: &[u8] = b"hel";
+        let mut body = BodyReader::new(&mut raw, Framing::Length(5));
+        let e = io::read_to_string(&mut body).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+}
@@ -0,0 +1,632 @@
+use crate::conditional;
+use crate::request::{Method, Request};
+use crate::response::{Body, Response};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Bytes of responses kept unless changed with `ResponseCache::max_bytes`
+pub const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// Statuses whose responses can be reused for later requests
+const CACHEABLE_STATUSES: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// Keeps the responses of GET handlers that allow it in memory, and
+/// answers the same requests with them instead of running the handler
+/// again. Enabled with `ServerBuilder::cache`.
+///
+/// A handler opts in with `Cache-Control: max-age=<seconds>`, or
+/// `s-maxage` to tell shared caches apart from browsers. Responses with
+/// `no-store`, `no-cache`, `private` or `Set-Cookie` are never kept, and
+/// neither are requests with `Authorization`. Responses are keyed on the
+/// method, path and query, along with the request headers they name in
+/// `Vary`. They are kept with their `ETag` but uncompressed, and
+/// compressed for each client as they are served. Once the responses kept
+/// take more than `max_bytes`, the least recently used are dropped.
+///
+/// The cache is shared by its clones, so handlers that change what a
+/// page shows can drop it right away:
+///
+/// ```no_run
+/// # use crag_web::{cache::ResponseCache, handler, request::Request, response::Response, server::Server};
+/// let cache = ResponseCache::new().max_bytes(16 * 1024 * 1024);
+/// let posts = cache.clone();
+/// let server = Server::build()
+///     .cache(cache)
+///     .register_handler(Request::GET("/posts"), |_req| {
+///         Ok(Response::Ok("<ul>...</ul>").with_header("Cache-Control", "max-age=60"))
+///     })
+///     .register_handler(Request::POST("/posts", ""), move |_req| {
+///         posts.invalidate_prefix("/posts");
+///         Ok(Response::new(201))
+///     })
+///     .register_error_handler(handler::default_error_404_handler)?
+///     .finalize(("127.0.0.1", 8080), 4)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct ResponseCache {
+    state: Arc<Mutex<State>>,
+}
+
+impl std::fmt::Debug for ResponseCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.lock();
+        f.debug_struct("ResponseCache")
+            .field("max_bytes", &state.max_bytes)
+            .field("size", &state.size)
+            .field("len", &state.len())
+            .finish()
+    }
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        ResponseCache {
+            state: Arc::new(Mutex::new(State {
+                max_bytes: DEFAULT_MAX_BYTES,
+                size: 0,
+                tick: 0,
+                resources: HashMap::new(),
+                recency: BTreeMap::new(),
+            })),
+        }
+    }
+}
+
+impl ResponseCache {
+    /// An empty cache keeping up to `DEFAULT_MAX_BYTES` of responses
+    pub fn new() -> ResponseCache {
+        ResponseCache::default()
+    }
+
+    /// Keep up to `max_bytes` of responses, counting their bodies and
+    /// headers. Responses larger than that are never kept.
+    pub fn max_bytes(self, max_bytes: usize) -> ResponseCache {
+        {
+            let mut state = self.lock();
+            state.max_bytes = max_bytes;
+            state.evict();
+        }
+        self
+    }
+
+    /// Drop every response kept for `GET target`, a path with an optional
+    /// query, whatever headers they vary on
+    pub fn invalidate(&self, target: &str) {
+        self.lock().remove(|resource| resource.target == target);
+    }
+
+    /// Drop every response kept for a path at or below `prefix`, going by
+    /// whole segments, whatever its query: `/posts` drops `/posts`,
+    /// `/posts/42` and `/posts?page=2`, but not `/postscript`
+    pub fn invalidate_prefix(&self, prefix: &str) {
+        self.lock()
+            .remove(|resource| under_prefix(prefix, &resource.target));
+    }
+
+    /// Drop every response
+    pub fn clear(&self) {
+        self.lock().remove(|_| true);
+    }
+
+    /// Number of responses kept
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes taken by the responses kept
+    pub fn size(&self) -> usize {
+        self.lock().size
+    }
+
+    /// The response kept for `req` if it is still fresh, with its `Age`,
+    /// or the way to keep the one its handler makes
+    pub(crate) fn lookup(&self, req: &Request) -> Lookup {
+        if req.method() != Method::GET || req.header("Authorization").is_some() {
+            return Lookup::Uncached;
+        }
+        let directives = directives(req.headers());
+        if has(&directives, "no-store") {
+            return Lookup::Uncached;
+        }
+        let resource = Resource {
+            method: req.method(),
+            target: req.uri().to_string(),
+        };
+        let headers: Vec<(String, String)> = req
+            .headers()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        // asked to go to the handler, but what it makes can be kept
+        if !has(&directives, "no-cache") {
+            let mut state = self.lock();
+            if let Some(response) = state.get(&resource, &headers) {
+                return Lookup::Hit(response);
+            }
+        }
+        Lookup::Miss(Miss {
+            cache: self.clone(),
+            resource,
+            headers,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// What the cache has for a request
+pub(crate) enum Lookup {
+    /// A fresh response kept for it
+    Hit(Response),
+    /// Nothing yet, but the handler's response may be kept
+    Miss(Miss),
+    /// Nothing, and the handler's response must not be kept
+    Uncached,
+}
+
+impl Lookup {
+    /// Keep `response` for later requests if the lookup missed and the
+    /// response allows it
+    pub(crate) fn store(self, response: Response) -> Response {
+        match self {
+            Lookup::Miss(miss) => miss.store(response),
+            Lookup::Hit(_) | Lookup::Uncached => response,
+        }
+    }
+}
+
+/// A request the cache had nothing for
+pub(crate) struct Miss {
+    cache: ResponseCache,
+    resource: Resource,
+    headers: Vec<(String, String)>,
+}
+
+impl Miss {
+    /// Kept with the `ETag` it would get anyway, so a hit isn't hashed
+    /// again every time it is served
+    fn store(self, response: Response) -> Response {
+        let Some(max_age) = max_age(&response) else {
+            return response;
+        };
+        let response = conditional::with_etag(response);
+        let (status, headers, body) = response.into_parts();
+        let Body::Full(body) = body else {
+            return Response::from_parts(status, headers, body);
+        };
+        let vary: Vec<String> = headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Vary"))
+            .flat_map(|(_, value)| value.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        if vary.iter().any(|name| name == "*") {
+            return Response::from_parts(status, headers, Body::Full(body));
+        }
+
+        let values = vary_values(&vary, &self.headers);
+        let entry = Entry {
+            status,
+            headers: headers.clone(),
+            body: body.clone(),
+            stored: Instant::now(),
+            max_age,
+            size: 0,
+            used: 0,
+        };
+        self.cache.lock().insert(self.resource, vary, values, entry);
+        Response::from_parts(status, headers, Body::Full(body))
+    }
+}
+
+/// How long `response` may be reused, if it can be kept at all
+fn max_age(response: &Response) -> Option<Duration> {
+    if !CACHEABLE_STATUSES.contains(&response.status()) || response.header("Set-Cookie").is_some() {
+        return None;
+    }
+    let directives = directives(response.headers());
+    if ["no-store", "no-cache", "private"]
+        .iter()
+        .any(|name| has(&directives, name))
+    {
+        return None;
+    }
+    // a shared cache goes by s-maxage over max-age
+    let seconds = |name| {
+        directives
+            .iter()
+            .find(|(directive, _)| directive.eq_ignore_ascii_case(name))
+            .and_then(|(_, value)| value.as_deref()?.parse::<u64>().ok())
+    };
+    let seconds = seconds("s-maxage").or_else(|| seconds("max-age"))?;
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+/// The `Cache-Control` directives among `headers`, with their values
+/// unquoted
+fn directives<'a>(
+    headers: impl Iterator<Item = (&'a str, &'a str)>,
+) -> Vec<(String, Option<String>)> {
+    headers
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Cache-Control"))
+        .flat_map(|(_, value)| value.split(','))
+        .filter_map(|directive| {
+            let directive = directive.trim();
+            if directive.is_empty() {
+                return None;
+            }
+            Some(match directive.split_once('=') {
+                Some((name, value)) => (
+                    name.trim().to_string(),
+                    Some(value.trim().trim_matches('"').to_string()),
+                ),
+                None => (directive.to_string(), None),
+            })
+        })
+        .collect()
+}
+
+fn has(directives: &[(String, Option<String>)], name: &str) -> bool {
+    directives
+        .iter()
+        .any(|(directive, _)| directive.eq_ignore_ascii_case(name))
+}
+
+/// The values of the request headers named `vary`, all of them joined
+/// for a header sent more than once
+fn vary_values(vary: &[String], headers: &[(String, String)]) -> Vec<Option<String>> {
+    vary.iter()
+        .map(|name| {
+            let values: Vec<&str> = headers
+                .iter()
+                .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim())
+                .collect();
+            (!values.is_empty()).then(|| values.join(","))
+        })
+        .collect()
+}
+
+/// Whether the path of `target`, a path with an optional query, is
+/// `prefix` or below it
+fn under_prefix(prefix: &str, target: &str) -> bool {
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'))
+}
+
+/// What responses are kept for: a method and a path with its query
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Resource {
+    method: Method,
+    target: String,
+}
+
+/// The responses kept for a resource, one for each combination of the
+/// request headers they vary on
+struct Variants {
+    /// Lowercase names from the `Vary` of the last response kept
+    vary: Vec<String>,
+    responses: HashMap<Vec<Option<String>>, Entry>,
+}
+
+struct Entry {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    stored: Instant,
+    max_age: Duration,
+    /// Bytes counted against `max_bytes`
+    size: usize,
+    /// When it was last used, as a key into `State::recency`
+    used: u64,
+}
+
+struct State {
+    max_bytes: usize,
+    size: usize,
+    /// Counts up on every use, to order entries by recency
+    tick: u64,
+    resources: HashMap<Resource, Variants>,
+    /// Entries from least to most recently used
+    recency: BTreeMap<u64, (Resource, Vec<Option<String>>)>,
+}
+
+impl State {
+    fn len(&self) -> usize {
+        self.recency.len()
+    }
+
+    /// A copy of the fresh response kept for `resource` that matches the
+    /// request `headers`, marked most recently used. A stale one is
+    /// dropped.
+    fn get(&mut self, resource: &Resource, headers: &[(String, String)]) -> Option<Response> {
+        let variants = self.resources.get_mut(resource)?;
+        let values = vary_values(&variants.vary, headers);
+        let entry = variants.responses.get_mut(&values)?;
+
+        let age = entry.stored.elapsed();
+        if age >= entry.max_age {
+            let used = entry.used;
+            self.remove_entry(used);
+            return None;
+        }
+        self.tick += 1;
+        let key = self.recency.remove(&entry.used)?;
+        entry.used = self.tick;
+        self.recency.insert(self.tick, key);
+
+        let mut headers = entry.headers.clone();
+        headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Age"));
+        headers.push(("Age".to_string(), age.as_secs().to_string()));
+        Some(Response::from_parts(
+            entry.status,
+            headers,
+            Body::Full(entry.body.clone()),
+        ))
+    }
+
+    /// Keep `entry` for `resource` and the request header `values` it
+    /// varies on, then drop the least recently used entries over the limit
+    fn insert(
+        &mut self,
+        resource: Resource,
+        vary: Vec<String>,
+        values: Vec<Option<String>>,
+        mut entry: Entry,
+    ) {
+        entry.size = resource.target.len()
+            + entry.body.len()
+            + entry
+                .headers
+                .iter()
+                .map(|(name, value)| name.len() + value.len())
+                .sum::<usize>()
+            + values.iter().flatten().map(String::len).sum::<usize>();
+        if entry.size > self.max_bytes {
+            return;
+        }
+
+        if let Some(variants) = self.resources.get(&resource) {
+            // responses varying on other headers can't be told apart
+            // anymore, so they all go
+            let replaced: Vec<u64> = if variants.vary != vary {
+                variants
+                    .responses
+                    .values()
+                    .map(|entry| entry.used)
+                    .collect()
+            } else {
+                variants
+                    .responses
+                    .get(&values)
+                    .map(|entry| entry.used)
+                    .into_iter()
+                    .collect()
+            };
+            for used in replaced {
+                self.remove_entry(used);
+            }
+        }
+
+        self.tick += 1;
+        entry.used = self.tick;
+        self.size += entry.size;
+        self.recency
+            .insert(self.tick, (resource.clone(), values.clone()));
+        self.resources
+            .entry(resource)
+            .or_insert_with(|| Variants {
+                vary,
+                responses: HashMap::new(),
+            })
+            .responses
+            .insert(values, entry);
+        self.evict();
+    }
+
+    /// Drop the least recently used entries until they fit in `max_bytes`
+    fn evict(&mut self) {
+        while self.size > self.max_bytes {
+            let Some((&used, _)) = self.recency.first_key_value() else {
+                break;
+            };
+            self.remove_entry(used);
+        }
+    }
+
+    /// Drop the entry last used at `used`
+    fn remove_entry(&mut self, used: u64) {
+        let Some((resource, values)) = self.recency.remove(&used) else {
+            return;
+        };
+        let Some(variants) = self.resources.get_mut(&resource) else {
+            return;
+        };
+        if let Some(entry) = variants.responses.remove(&values) {
+            self.size -= entry.size;
+        }
+        if variants.responses.is_empty() {
+            self.resources.remove(&resource);
+        }
+    }
+
+    /// Drop every entry of the resources `matches` picks
+    fn remove(&mut self, matches: impl Fn(&Resource) -> bool) {
+        let used: Vec<u64> = self
+            .resources
+            .iter()
+            .filter(|(resource, _)| matches(resource))
+            .flat_map(|(_, variants)| variants.responses.values().map(|entry| entry.used))
+            .collect();
+        for used in used {
+            self.remove_entry(used);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get;
+
+    fn page(body: &str, cache_control: &str) -> Response {
+        Response::Ok(body).with_header("Cache-Control", cache_control)
+    }
+
+    /// The response `lookup` hit, after storing `response` on a miss
+    fn serve(cache: &ResponseCache, req: &Request, response: Response) -> Option<Response> {
+        match cache.lookup(req) {
+            Lookup::Hit(response) => Some(response),
+            lookup => {
+                lookup.store(response);
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_age() {
+        let cache = ResponseCache::new();
+        let req = get("/report?year=2024", &[]);
+        assert!(serve(&cache, &req, page("fresh", "public, max-age=60")).is_none());
+        let hit = serve(&cache, &req, page("rendered again", "max-age=60")).unwrap();
+        assert_eq!(hit.body(), b"fresh");
+        assert_eq!(hit.header("Age"), Some("0"));
+        // kept with the ETag its body gets, rather than hashing it per hit
+        assert_eq!(
+            hit.header("ETag"),
+            Some(conditional::strong_etag(b"fresh").as_str())
+        );
+        // another query is another page
+        assert!(serve(
+            &cache,
+            &get("/report?year=2023", &[]),
+            page("", "max-age=60")
+        )
+        .is_none());
+
+        for cache_control in [
+            "no-store, max-age=60",
+            "private, max-age=60",
+            "max-age=0",
+            "public",
+        ] {
+            let req = get("/uncached", &[]);
+            serve(&cache, &req, page("once", cache_control));
+            assert!(
+                serve(&cache, &req, page("twice", cache_control)).is_none(),
+                "{cache_control}"
+            );
+        }
+        assert_eq!(cache.len(), 2);
+
+        // the client can skip the cache or keep the response out of it
+        let req = get("/report?year=2024", &[("Cache-Control", "no-cache")]);
+        assert!(serve(&cache, &req, page("newer", "max-age=60")).is_none());
+        let hit = serve(
+            &cache,
+            &get("/report?year=2024", &[]),
+            page("", "max-age=60"),
+        );
+        assert_eq!(hit.unwrap().body(), b"newer");
+        let req = get("/private", &[("Authorization", "Bearer token")]);
+        serve(&cache, &req, page("mine", "max-age=60"));
+        assert!(matches!(cache.lookup(&req), Lookup::Uncached));
+
+        // s-maxage is for caches like this one
+        let req = get("/shared", &[]);
+        serve(&cache, &req, page("stale", "max-age=60, s-maxage=0"));
+        assert!(serve(&cache, &req, page("", "max-age=60")).is_none());
+    }
+
+    #[test]
+    fn test_vary() {
+        let cache = ResponseCache::new();
+        let page =
+            |language: &str| page(language, "max-age=60").with_header("Vary", "Accept-Language");
+        let english = get("/", &[("Accept-Language", "en")]);
+        let german = get("/", &[("accept-language", "de")]);
+        serve(&cache, &english, page("hello"));
+        serve(&cache, &german, page("hallo"));
+        assert_eq!(serve(&cache, &english, page("")).unwrap().body(), b"hello");
+        assert_eq!(serve(&cache, &german, page("")).unwrap().body(), b"hallo");
+        assert!(serve(&cache, &get("/", &[]), page("")).is_none());
+        assert_eq!(cache.len(), 3);
+
+        let everything = get("/any", &[]);
+        serve(&cache, &everything, page("").with_header("Vary", "*"));
+        assert!(serve(&cache, &everything, page("")).is_none());
+    }
+
+    #[test]
+    fn test_eviction_and_invalidation() {
+        let body = "x".repeat(1000);
+        let cache = ResponseCache::new().max_bytes(3500);
+        for path in ["/a", "/b", "/c"] {
+            serve(&cache, &get(path, &[]), page(&body, "max-age=60"));
+        }
+        assert_eq!(cache.len(), 3);
+        // using /a makes /b the least recently used
+        assert!(serve(&cache, &get("/a", &[]), page("", "max-age=60")).is_some());
+        serve(&cache, &get("/d", &[]), page(&body, "max-age=60"));
+        assert_eq!(cache.len(), 3);
+        assert!(cache.size() <= 3500);
+        assert!(matches!(cache.lookup(&get("/b", &[])), Lookup::Miss(_)));
+        assert!(matches!(cache.lookup(&get("/a", &[])), Lookup::Hit(_)));
+
+        // too large to keep at all
+        serve(
+            &cache,
+            &get("/huge", &[]),
+            page(&"x".repeat(4000), "max-age=60"),
+        );
+        assert!(matches!(cache.lookup(&get("/huge", &[])), Lookup::Miss(_)));
+
+        let cache = ResponseCache::new();
+        for path in ["/posts", "/posts/1", "/posts/2?draft=1", "/postscript"] {
+            serve(&cache, &get(path, &[]), page("post", "max-age=60"));
+        }
+        cache.invalidate("/posts/1");
+        assert!(matches!(
+            cache.lookup(&get("/posts/1", &[])),
+            Lookup::Miss(_)
+        ));
+        assert!(matches!(cache.lookup(&get("/posts", &[])), Lookup::Hit(_)));
+        cache.invalidate_prefix("/posts/");
+        assert!(matches!(
+            cache.lookup(&get("/posts/2?draft=1", &[])),
+            Lookup::Miss(_)
+        ));
+        assert!(matches!(cache.lookup(&get("/posts", &[])), Lookup::Hit(_)));
+        assert!(matches!(
+            cache.lookup(&get("/postscript", &[])),
+            Lookup::Hit(_)
+        ));
+        serve(
+            &cache,
+            &get("/posts?page=2", &[]),
+            page("post", "max-age=60"),
+        );
+        cache.invalidate_prefix("/posts");
+        for path in ["/posts", "/posts?page=2"] {
+            assert!(matches!(cache.lookup(&get(path, &[])), Lookup::Miss(_)));
+        }
+        assert!(matches!(
+            cache.lookup(&get("/postscript", &[])),
+            Lookup::Hit(_)
+        ));
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.size(), 0);
+    }
+}
@@ -0,0 +1,498 @@
+use crate::response::{self, Body, Chunks, Response};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::io::{self, Write};
+
+/// Bodies smaller than this are sent as they are unless changed with
+/// `Compression::min_size`; compressing them gains next to nothing
+pub const DEFAULT_MIN_SIZE: u64 = 1024;
+
+/// Brotli quality for compressing on the fly, fast rather than small
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+/// A content coding responses can be compressed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// Every encoding, the ones compressing better first
+    pub const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+    /// The name used in `Accept-Encoding` and `Content-Encoding`
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// The extension of a file precompressed with this encoding
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Gzip => Some("gz"),
+            Encoding::Deflate => None,
+        }
+    }
+}
+
+/// The encoding out of `available` the client prefers according to its
+/// `Accept-Encoding`, going by the order of `available` when it likes
+/// several as much. `None` if it accepts none of them, or only `identity`.
+///
+/// ```
+/// # use crag_web::compression::{negotiate, Encoding};
+/// let accept = "gzip;q=0.8, br;q=0.9, *;q=0";
+/// assert_eq!(negotiate(accept, &Encoding::ALL), Some(Encoding::Brotli));
+/// assert_eq!(negotiate(accept, &[Encoding::Deflate]), None);
+/// ```
+pub fn negotiate(accept_encoding: &str, available: &[Encoding]) -> Option<Encoding> {
+    let mut wildcard = None;
+    let mut listed = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        if coding.is_empty() {
+            continue;
+        }
+        let mut quality = 1.0;
+        for param in params {
+            if let Some((name, value)) = param.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("q") {
+                    quality = value.trim().parse::<f32>().unwrap_or(0.0);
+                }
+            }
+        }
+        match coding.as_str() {
+            "*" => wildcard = Some(quality),
+            "x-gzip" => listed.push(("gzip".to_string(), quality)),
+            _ => listed.push((coding, quality)),
+        }
+    }
+
+    let quality = |encoding: Encoding| {
+        listed
+            .iter()
+            .find(|(coding, _)| coding == encoding.name())
+            .map(|(_, quality)| *quality)
+            .or(wildcard)
+            .unwrap_or(0.0)
+    };
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in available {
+        let quality = quality(encoding);
+        if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether a body of `content_type` gets smaller when compressed: text
+/// and text based formats, but not event streams, whose events would be
+/// held back until enough of them came together.
+pub fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match essence.split_once('/') {
+        Some(("text", "event-stream")) => false,
+        Some(("text", _)) => true,
+        Some((_, subtype)) if subtype.ends_with("+json") || subtype.ends_with("+xml") => true,
+        Some(("application", subtype)) => {
+            matches!(subtype, "json" | "javascript" | "xml" | "wasm" | "x-tar")
+        }
+        Some(("font", subtype)) => matches!(subtype, "ttf" | "otf"),
+        Some(("image", subtype)) => matches!(subtype, "x-icon" | "bmp"),
+        _ => false,
+    }
+}
+
+/// Compresses responses for the clients that accept it, enabled with
+/// `ServerBuilder::compression`. Only bodies of a compressible
+/// `Content-Type` and at least `min_size` bytes are compressed, and
+/// none that already have a `Content-Encoding`, e.g. precompressed files
+/// served by `ServeDir`.
+///
+/// ```no_run
+/// # use crag_web::{compression::{Compression, Encoding}, handler, server::Server};
+/// let server = Server::build()
+///     .compression(
+///         Compression::new()
+///             .min_size(4096)
+///             .encodings([Encoding::Gzip]),
+///     )
+///     .register_error_handler(handler::default_error_404_handler)?
+///     .finalize(("127.0.0.1", 8080), 4)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Compression {
+    min_size: u64,
+    encodings: Vec<Encoding>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            min_size: DEFAULT_MIN_SIZE,
+            encodings: Encoding::ALL.to_vec(),
+        }
+    }
+}
+
+impl Compression {
+    /// Every encoding, for bodies of at least `DEFAULT_MIN_SIZE` bytes
+    pub fn new() -> Compression {
+        Compression::default()
+    }
+
+    /// Leave bodies smaller than `min_size` bytes as they are
+    pub fn min_size(mut self, min_size: u64) -> Compression {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Only offer `encodings`, preferred in the order given when the
+    /// client likes several as much
+    pub fn encodings(mut self, encodings: impl IntoIterator<Item = Encoding>) -> Compression {
+        self.encodings = encodings.into_iter().collect();
+        self
+    }
+
+    /// Settle how `response` is sent to a client with `accept_encoding`
+    /// without compressing it yet: the encoding to compress it with, if
+    /// any, `Vary: Accept-Encoding` when it could have one, and a weak
+    /// `ETag` when it gets one. Done before its preconditions are checked,
+    /// so a `304` has the validator and `Vary` of the response it stands
+    /// in for.
+    pub(crate) fn prepare(
+        &self,
+        accept_encoding: Option<&str>,
+        response: Response,
+    ) -> (Response, Option<Encoding>) {
+        let status = response.status();
+        if !(200..300).contains(&status) || matches!(status, 204 | 206) || response.takes_over() {
+            return (response, None);
+        }
+        if response.header("Content-Encoding").is_some()
+            || !response.header("Content-Type").is_some_and(is_compressible)
+            || response.body_len().is_some_and(|len| len < self.min_size)
+        {
+            return (response, None);
+        }
+
+        let (status, mut headers, body) = response.into_parts();
+        add_vary(&mut headers);
+        let encoding = accept_encoding.and_then(|accept| negotiate(accept, &self.encodings));
+        if encoding.is_some() {
+            // the compressed bytes differ from what the handler made, but
+            // not in meaning: a weak ETag still validates it for caches
+            for (name, value) in headers.iter_mut() {
+                if name.eq_ignore_ascii_case("ETag") && !value.starts_with("W/") {
+                    *value = format!("W/{value}");
+                }
+            }
+        }
+        (Response::from_parts(status, headers, body), encoding)
+    }
+}
+
+/// Compress the body of `response` with the `encoding` picked by
+/// `Compression::prepare`, unless it no longer carries the whole body,
+/// e.g. because it became a `304` or answers a `Range` request
+pub(crate) fn encode(encoding: Option<Encoding>, response: Response) -> Response {
+    let Some(encoding) = encoding else {
+        return response;
+    };
+    let status = response.status();
+    if !(200..300).contains(&status) || matches!(status, 204 | 206) {
+        return response;
+    }
+
+    let (status, mut headers, body) = response.into_parts();
+    let body = match body {
+        Body::Full(body) => match compress(encoding, &body) {
+            Ok(compressed) => Body::Full(compressed),
+            Err(e) => {
+                tracing::error!("Error compressing response body: {e:?}");
+                return Response::from_parts(status, headers, Body::Full(body));
+            }
+        },
+        Body::Seekable {
+            reader,
+            offset,
+            len,
+        } => Body::Stream {
+            chunks: compress_chunks(
+                encoding,
+                response::seekable_chunks(reader, offset, len),
+                false,
+            ),
+            trailers: None,
+        },
+        // the kernel can't compress while it sends, so the file is read
+        Body::File { file, offset, len } => Body::Stream {
+            chunks: compress_chunks(
+                encoding,
+                response::seekable_chunks(Box::new(file), offset, len),
+                false,
+            ),
+            trailers: None,
+        },
+        // flushed chunk by chunk, so nothing is held back that the
+        // handler meant to send right away
+        Body::Stream { chunks, trailers } => Body::Stream {
+            chunks: compress_chunks(encoding, chunks, true),
+            trailers,
+        },
+        // the connection is handed over as it is
+        body @ Body::Takeover(_) => return Response::from_parts(status, headers, body),
+    };
+
+    headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Accept-Ranges"));
+    headers.push(("Content-Encoding".to_string(), encoding.name().to_string()));
+    Response::from_parts(status, headers, body)
+}
+
+/// Add `Vary: Accept-Encoding` unless it is already listed
+pub(crate) fn add_vary(headers: &mut Vec<(String, String)>) {
+    let listed = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Vary"))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|field| {
+            let field = field.trim();
+            field == "*" || field.eq_ignore_ascii_case("Accept-Encoding")
+        });
+    if !listed {
+        headers.push(("Vary".to_string(), "Accept-Encoding".to_string()));
+    }
+}
+
+/// `body` compressed as a whole
+fn compress(encoding: Encoding, body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = Encoder::new(encoding);
+    encoder.write(body, false)?;
+    encoder.finish()
+}
+
+/// `chunks` compressed as they are produced, `flush`ing the compressed
+/// bytes of each chunk rather than waiting for more
+fn compress_chunks(encoding: Encoding, chunks: Chunks, flush: bool) -> Chunks {
+    let mut chunks = chunks;
+    let mut encoder = Some(Encoder::new(encoding));
+    Box::new(std::iter::from_fn(move || loop {
+        let current = encoder.as_mut()?;
+        match chunks.next() {
+            Some(Ok(chunk)) => {
+                if let Err(e) = current.write(&chunk, flush) {
+                    return Some(Err(e));
+                }
+                // unless it is flushed, an encoder collects a fair amount
+                // before it has anything to show
+                let compressed = current.take();
+                if !compressed.is_empty() {
+                    return Some(Ok(compressed));
+                }
+            }
+            Some(Err(e)) => return Some(Err(e)),
+            None => return Some(encoder.take()?.finish()),
+        }
+    }))
+}
+
+/// Compresses into a buffer that is taken out as it fills up
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Encoder {
+        match encoding {
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Default::default())),
+            // the `deflate` content coding is the zlib format
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(Vec::new(), Default::default())),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8], flush: bool) -> io::Result<()> {
+        let writer: &mut dyn Write = match self {
+            Encoder::Brotli(encoder) => encoder.as_mut(),
+            Encoder::Gzip(encoder) => encoder,
+            Encoder::Deflate(encoder) => encoder,
+        };
+        writer.write_all(bytes)?;
+        if flush {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// What was compressed so far
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(match self {
+            Encoder::Brotli(encoder) => encoder.get_mut(),
+            Encoder::Gzip(encoder) => encoder.get_mut(),
+            Encoder::Deflate(encoder) => encoder.get_mut(),
+        })
+    }
+
+    /// The rest of the compressed bytes
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// Compress `response` for a client with `accept_encoding`
+    fn apply(
+        compression: &Compression,
+        accept_encoding: Option<&str>,
+        response: Response,
+    ) -> Response {
+        let (response, encoding) = compression.prepare(accept_encoding, response);
+        encode(encoding, response)
+    }
+
+    fn decompress(encoding: &str, bytes: &[u8]) -> Vec<u8> {
+        let mut decompressed = Vec::new();
+        match encoding {
+            "br" => brotli::Decompressor::new(bytes, 4096)
+                .read_to_end(&mut decompressed)
+                .unwrap(),
+            "gzip" => flate2::read::GzDecoder::new(bytes)
+                .read_to_end(&mut decompressed)
+                .unwrap(),
+            "deflate" => flate2::read::ZlibDecoder::new(bytes)
+                .read_to_end(&mut decompressed)
+                .unwrap(),
+            _ => panic!("unknown encoding {encoding}"),
+        };
+        decompressed
+    }
+
+    fn css() -> Response {
+        Response::new(200)
+            .with_header("Content-Type", "text/css")
+            .with_header("ETag", "\"v1\"")
+            .with_body("body { color: blue }\n".repeat(100))
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let all = &Encoding::ALL;
+        assert_eq!(negotiate("gzip, deflate, br", all), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip, br;q=0.5", all), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiate("GZIP;Q=0.3, deflate;q=0.2", all),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate("x-gzip", all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*", all), Some(Encoding::Brotli));
+        assert_eq!(negotiate("br;q=0, *;q=0.1", all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("identity", all), None);
+        assert_eq!(negotiate("br;q=0, gzip;q=0", all), None);
+        assert_eq!(negotiate("", all), None);
+        assert_eq!(
+            negotiate("gzip, br", &[Encoding::Gzip]),
+            Some(Encoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/json"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(is_compressible("application/manifest+json"));
+        assert!(!is_compressible("text/event-stream"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/gzip"));
+    }
+
+    #[test]
+    fn test_apply() {
+        let compression = Compression::new();
+        for encoding in ["br", "gzip", "deflate"] {
+            let response = apply(&compression, Some(encoding), css());
+            assert_eq!(response.header("content-encoding"), Some(encoding));
+            assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+            assert_eq!(response.header("etag"), Some("W/\"v1\""));
+            assert!(response.body().len() < css().body().len());
+            assert_eq!(decompress(encoding, response.body()), css().body());
+        }
+
+        // the client doesn't take it, but a cache has to know it could
+        let response = apply(&compression, Some("identity"), css());
+        assert_eq!(response.header("content-encoding"), None);
+        assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body(), css().body());
+
+        // small, already compressed, or not compressible
+        let small = Response::new(200)
+            .with_header("Content-Type", "text/css")
+            .with_body("body {}");
+        let gzipped = css().with_header("Content-Encoding", "gzip");
+        let png = Response::new(200)
+            .with_header("Content-Type", "image/png")
+            .with_body(vec![0; 4096]);
+        for response in [small, gzipped, png] {
+            let body = response.body().to_vec();
+            let encoding = response.header("content-encoding").map(str::to_string);
+            let response = apply(&compression, Some("gzip"), response);
+            assert_eq!(response.header("content-encoding"), encoding.as_deref());
+            assert_eq!(response.header("vary"), None);
+            assert_eq!(response.body(), body);
+        }
+    }
+
+    #[test]
+    fn test_streamed_body() {
+        let lines = (0..1000).map(|n| format!("line {n}\n"));
+        let expected: String = lines.clone().collect();
+        let response = Response::new(200)
+            .with_header("Content-Type", "text/plain")
+            .with_body_chunks(lines)
+            .with_trailers(|| vec![("X-Lines".to_string(), "1000".to_string())]);
+
+        let response = apply(&Compression::new(), Some("gzip"), response);
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        let (_, _, body) = response.into_parts();
+        let Body::Stream { chunks, trailers } = body else {
+            panic!("expected a streamed body");
+        };
+        let compressed: Vec<u8> = chunks.flat_map(|chunk| chunk.unwrap()).collect();
+        assert_eq!(decompress("gzip", &compressed), expected.as_bytes());
+        assert!(trailers.is_some());
+    }
+}
@@ -0,0 +1,340 @@
+use crate::request::{Method, Request};
+use crate::response::Response;
+use sha1::{Digest, Sha1};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Headers a `304 Not Modified` keeps from the response it replaces
+const NOT_MODIFIED_HEADERS: &[&str] = &[
+    "Cache-Control",
+    "Content-Location",
+    "Date",
+    "ETag",
+    "Expires",
+    "Last-Modified",
+    "Vary",
+];
+
+/// A strong validator for a representation: a hash of its bytes
+pub fn strong_etag(bytes: &[u8]) -> String {
+    let digest = Sha1::digest(bytes);
+    let hex: String = digest[..16].iter().map(|b| format!("{b:02x}")).collect();
+    format!("\"{hex}\"")
+}
+
+/// A weak validator for a file, from its size and modification time.
+/// Cheap, since the file isn't read, but two versions written within the
+/// same second with the same size can't be told apart.
+pub fn weak_etag(size: u64, modified: SystemTime) -> String {
+    let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("W/\"{size:x}-{:x}\"", modified.as_secs())
+}
+
+/// `time` in the format of `Last-Modified`, e.g.
+/// `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(time: SystemTime) -> String {
+    httpdate::fmt_http_date(time)
+}
+
+/// The validators of a request, `If-Match`, `If-None-Match`,
+/// `If-Modified-Since` and `If-Unmodified-Since`, to check against the
+/// current state of what it asks for.
+///
+/// GET requests are checked against the response of their handler
+/// automatically. Handlers of other methods should check before changing
+/// anything, e.g. so an upload doesn't overwrite a version the client
+/// hasn't seen:
+///
+/// ```
+/// # use crag_web::{conditional::Preconditions, request::Request, response::Response};
+/// fn update(req: Request) -> anyhow::Result<Response> {
+///     let current = "\"v2\"";
+///     if let Some(failed) = Preconditions::new(&req).check(Some(current), None) {
+///         return Ok(failed);
+///     }
+///     // ... store the update
+///     Ok(Response::new(204))
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Preconditions {
+    method: Method,
+    if_match: Option<String>,
+    if_none_match: Option<String>,
+    if_modified_since: Option<SystemTime>,
+    if_unmodified_since: Option<SystemTime>,
+}
+
+impl Preconditions {
+    pub fn new(req: &Request) -> Preconditions {
+        let date = |name| req.header(name).and_then(parse_http_date);
+        Preconditions {
+            method: req.method(),
+            if_match: req.header("If-Match").map(str::to_string),
+            if_none_match: req.header("If-None-Match").map(str::to_string),
+            if_modified_since: date("If-Modified-Since"),
+            if_unmodified_since: date("If-Unmodified-Since"),
+        }
+    }
+
+    /// Whether the request has any validators at all
+    pub fn is_empty(&self) -> bool {
+        self.if_match.is_none()
+            && self.if_none_match.is_none()
+            && self.if_modified_since.is_none()
+            && self.if_unmodified_since.is_none()
+    }
+
+    /// The response to send instead, `304 Not Modified` or
+    /// `412 Precondition Failed`, when the validators don't hold for the
+    /// current `etag` and `last_modified`. Evaluated in the order of
+    /// RFC 9110, section 13.2.2. Without an `etag` there is taken to be
+    /// no current representation, so `If-Match: *` fails.
+    pub fn check(&self, etag: Option<&str>, last_modified: Option<SystemTime>) -> Option<Response> {
+        let status = self.failed_status(etag.is_some(), etag, last_modified)?;
+        let mut response = Response::new(status);
+        if status == 304 {
+            if let Some(etag) = etag {
+                response = response.with_header("ETag", etag);
+            }
+            if let Some(last_modified) = last_modified {
+                response = response.with_header("Last-Modified", http_date(last_modified));
+            }
+        }
+        Some(response)
+    }
+
+    /// `exists` tells whether there is a current representation at all
+    fn failed_status(
+        &self,
+        exists: bool,
+        etag: Option<&str>,
+        last_modified: Option<SystemTime>,
+    ) -> Option<u16> {
+        if let Some(if_match) = &self.if_match {
+            if !matches_any(if_match, exists, etag, strong_match) {
+                return Some(412);
+            }
+        } else if let (Some(since), Some(modified)) = (self.if_unmodified_since, last_modified) {
+            if truncate(modified) > since {
+                return Some(412);
+            }
+        }
+
+        let safe = self.method == Method::GET;
+        if let Some(if_none_match) = &self.if_none_match {
+            if matches_any(if_none_match, exists, etag, weak_match) {
+                return Some(if safe { 304 } else { 412 });
+            }
+        } else if let (true, Some(since), Some(modified)) =
+            (safe, self.if_modified_since, last_modified)
+        {
+            if truncate(modified) <= since {
+                return Some(304);
+            }
+        }
+        None
+    }
+
+    /// Give a `200 OK` to a GET request with a buffered body but no
+    /// validator a strong `ETag` from its bytes
+    pub(crate) fn with_etag(&self, response: Response) -> Response {
+        if self.method != Method::GET {
+            return response;
+        }
+        with_etag(response)
+    }
+
+    /// Check a handler's response to a GET request, giving it an `ETag`
+    /// with `with_etag` first. Only successful responses are validated;
+    /// errors pass through unchanged.
+    pub(crate) fn apply(&self, response: Response) -> Response {
+        if self.method != Method::GET || !(200..300).contains(&response.status()) {
+            return response;
+        }
+        let response = self.with_etag(response);
+        if self.is_empty() {
+            return response;
+        }
+
+        let last_modified = response.header("Last-Modified").and_then(parse_http_date);
+        match self.failed_status(true, response.header("ETag"), last_modified) {
+            Some(304) => {
+                let mut not_modified = Response::new(304);
+                for (name, value) in response.headers() {
+                    if NOT_MODIFIED_HEADERS
+                        .iter()
+                        .any(|kept| kept.eq_ignore_ascii_case(name))
+                    {
+                        not_modified = not_modified.with_header(name, value);
+                    }
+                }
+                not_modified
+            }
+            Some(status) => Response::new(status),
+            None => response,
+        }
+    }
+}
+
+/// A `200 OK` with a buffered body but no validator, with a strong `ETag`
+/// from its bytes
+pub(crate) fn with_etag(response: Response) -> Response {
+    if response.status() != 200 || response.header("ETag").is_some() || response.body().is_empty() {
+        return response;
+    }
+    let etag = strong_etag(response.body());
+    response.with_header("ETag", etag)
+}
+
+pub(crate) fn parse_http_date(value: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(value).ok()
+}
+
+/// HTTP dates have whole seconds, file times usually don't
+fn truncate(time: SystemTime) -> SystemTime {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs())
+}
+
+/// Whether the `If-Match` or `If-None-Match` list `header` holds `etag`.
+/// `*` matches whenever there is a current representation.
+fn matches_any(
+    header: &str,
+    exists: bool,
+    etag: Option<&str>,
+    matches: fn(&str, &str) -> bool,
+) -> bool {
+    if header.trim() == "*" {
+        return exists;
+    }
+    let Some(etag) = etag else {
+        return false;
+    };
+    entity_tags(header).any(|candidate| matches(candidate, etag))
+}
+
+/// Strong comparison: neither may be weak
+pub(crate) fn strong_match(a: &str, b: &str) -> bool {
+    !a.starts_with("W/") && !b.starts_with("W/") && a == b
+}
+
+/// Weak comparison: the opaque tags are the same
+fn weak_match(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+/// The entity tags of a comma separated list. Commas may appear inside
+/// of a tag, so the quotes are followed rather than splitting on them.
+fn entity_tags(header: &str) -> impl Iterator<Item = &str> {
+    let mut rest = header;
+    std::iter::from_fn(move || {
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+        let start = rest.strip_prefix("W/").unwrap_or(rest);
+        let opaque = start.strip_prefix('"')?;
+        let end = opaque.find('"')?;
+        let tag_len = rest.len() - opaque.len() + end + 1;
+        let (tag, tail) = rest.split_at(tag_len);
+        rest = tail;
+        Some(tag)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    fn get(headers: &[(&str, &str)]) -> Preconditions {
+        Preconditions::new(&test_util::get("/", headers))
+    }
+
+    #[test]
+    fn test_entity_tags() {
+        let tags: Vec<&str> = entity_tags(r#""a", W/"b,c" ,"d""#).collect();
+        assert_eq!(tags, [r#""a""#, r#"W/"b,c""#, r#""d""#]);
+        assert_eq!(entity_tags("garbage").count(), 0);
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let etag = Some(r#""v1""#);
+        let status =
+            |value| get(&[("If-None-Match", value)]).failed_status(etag.is_some(), etag, None);
+        assert_eq!(status(r#""v1""#), Some(304));
+        assert_eq!(status(r#""v0", W/"v1""#), Some(304));
+        assert_eq!(status("*"), Some(304));
+        assert_eq!(status(r#""v2""#), None);
+
+        // a POST that would create what already exists
+        let mut req = Request::POST("/", "");
+        req.add_header("If-None-Match", "*");
+        assert_eq!(
+            Preconditions::new(&req).failed_status(etag.is_some(), etag, None),
+            Some(412)
+        );
+    }
+
+    #[test]
+    fn test_if_match() {
+        let status = |value, etag: Option<&str>| {
+            get(&[("If-Match", value)]).failed_status(etag.is_some(), etag, None)
+        };
+        assert_eq!(status(r#""v1""#, Some(r#""v1""#)), None);
+        assert_eq!(status(r#""v1""#, Some(r#""v2""#)), Some(412));
+        // weak tags never match strongly
+        assert_eq!(status(r#"W/"v1""#, Some(r#"W/"v1""#)), Some(412));
+        assert_eq!(status("*", Some(r#""v1""#)), None);
+        assert_eq!(status("*", None), Some(412));
+    }
+
+    #[test]
+    fn test_dates() {
+        let modified = UNIX_EPOCH + Duration::from_millis(784_111_777_500);
+        let date = http_date(modified);
+        assert_eq!(date, "Sun, 06 Nov 1994 08:49:37 GMT");
+
+        let since =
+            |name, value: &str| get(&[(name, value)]).failed_status(true, None, Some(modified));
+        assert_eq!(since("If-Modified-Since", &date), Some(304));
+        assert_eq!(
+            since("If-Modified-Since", "Sun, 06 Nov 1994 08:49:36 GMT"),
+            None
+        );
+        assert_eq!(since("If-Modified-Since", "not a date"), None);
+        assert_eq!(since("If-Unmodified-Since", &date), None);
+        assert_eq!(
+            since("If-Unmodified-Since", "Sun, 06 Nov 1994 08:49:36 GMT"),
+            Some(412)
+        );
+
+        // If-None-Match takes precedence over the date
+        let preconditions = get(&[("If-None-Match", r#""v2""#), ("If-Modified-Since", &date)]);
+        assert_eq!(
+            preconditions.failed_status(true, Some(r#""v1""#), Some(modified)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let response = Response::new(200)
+            .with_header("Cache-Control", "max-age=60")
+            .with_header("X-Other", "dropped")
+            .with_body("hello");
+        let etag = strong_etag(b"hello");
+
+        let tagged = get(&[]).apply(Response::new(200).with_body("hello"));
+        assert_eq!(tagged.header("etag"), Some(etag.as_str()));
+
+        let not_modified = get(&[("If-None-Match", &etag)]).apply(response);
+        assert_eq!(not_modified.status(), 304);
+        assert_eq!(not_modified.header("etag"), Some(etag.as_str()));
+        assert_eq!(not_modified.header("cache-control"), Some("max-age=60"));
+        assert_eq!(not_modified.header("x-other"), None);
+        assert!(not_modified.body().is_empty());
+
+        // errors aren't validated
+        let missing = get(&[("If-Match", &etag)]).apply(Response::new(404).with_body("gone"));
+        assert_eq!(missing.status(), 404);
+    }
+}
@@ -0,0 +1,324 @@
+use crate::compression::{self, Encoding};
+use crate::conditional;
+use crate::mime::MimeTypes;
+use anyhow::Result;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// A directory compiled into the binary by `Embed`, to be served with
+/// `ServeDir::embedded`. Everything about its files that doesn't change
+/// was worked out at build time.
+#[derive(Debug)]
+pub struct EmbeddedDir {
+    /// Sorted by path
+    pub files: &'static [EmbeddedFile],
+}
+
+/// A file of an `EmbeddedDir`
+#[derive(Debug)]
+pub struct EmbeddedFile {
+    /// Relative to the embedded directory, separated by `/`
+    pub path: &'static str,
+    pub contents: &'static [u8],
+    pub content_type: &'static str,
+    /// Strong validator of `contents`
+    pub etag: &'static str,
+    /// The contents compressed with brotli, if that made them smaller
+    pub brotli: Option<&'static [u8]>,
+    /// The contents compressed with gzip, if that made them smaller
+    pub gzip: Option<&'static [u8]>,
+}
+
+impl EmbeddedDir {
+    /// The file at `path`, relative to the directory and separated by `/`
+    pub fn get(&self, path: &str) -> Option<&'static EmbeddedFile> {
+        let files = self.files;
+        let i = files.binary_search_by(|file| file.path.cmp(path)).ok()?;
+        Some(&files[i])
+    }
+
+    /// Whether `path` is a directory of embedded files, which the root
+    /// `""` always is
+    pub fn is_dir(&self, path: &str) -> bool {
+        if path.is_empty() {
+            return true;
+        }
+        let prefix = format!("{path}/");
+        // the files under the directory sort right after its name
+        let first = self
+            .files
+            .partition_point(|file| file.path < prefix.as_str());
+        self.files
+            .get(first)
+            .is_some_and(|file| file.path.starts_with(&prefix))
+    }
+}
+
+impl EmbeddedFile {
+    /// The precompressed versions of the file
+    pub fn precompressed(&self) -> Vec<(Encoding, &'static [u8])> {
+        [(Encoding::Brotli, self.brotli), (Encoding::Gzip, self.gzip)]
+            .into_iter()
+            .filter_map(|(encoding, contents)| Some((encoding, contents?)))
+            .collect()
+    }
+
+    /// The strong validator of the file compressed with `encoding`
+    pub fn encoded_etag(&self, encoding: Encoding) -> String {
+        let opaque = self.etag.trim_end_matches('"');
+        format!("{opaque}-{}\"", encoding.name())
+    }
+}
+
+/// Embeds a directory into the binary from a build script. Writes a
+/// Rust expression for an `EmbeddedDir` that `include_bytes!`s every
+/// file under the directory, to be `include!`d where it is served:
+///
+/// ```no_run
+/// // build.rs, with crag-web under [build-dependencies]
+/// fn main() -> anyhow::Result<()> {
+///     let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR")?);
+///     crag_web::embed::Embed::new("static/css")
+///         .precompress(true)
+///         .generate(out_dir.join("css.rs"))
+/// }
+/// ```
+///
+/// ```ignore
+/// // main.rs
+/// static CSS: EmbeddedDir = include!(concat!(env!("OUT_DIR"), "/css.rs"));
+///
+/// let server = Server::build().mount("/css", ServeDir::embedded(&CSS));
+/// ```
+///
+/// Cargo is told to run the build script again whenever something in the
+/// directory changes.
+#[derive(Debug, Clone)]
+pub struct Embed {
+    dir: PathBuf,
+    precompress: bool,
+    mime_types: MimeTypes,
+}
+
+impl Embed {
+    /// Embed the files under `dir`, relative to the package being built
+    pub fn new(dir: impl Into<PathBuf>) -> Embed {
+        Embed {
+            dir: dir.into(),
+            precompress: false,
+            mime_types: MimeTypes::new(),
+        }
+    }
+
+    /// Also embed brotli and gzip compressed versions of the files whose
+    /// type compresses, compressed as small as they get. Served to the
+    /// clients that accept them without compressing on every request.
+    pub fn precompress(mut self, precompress: bool) -> Embed {
+        self.precompress = precompress;
+        self
+    }
+
+    /// Look up `Content-Type`s in `mime_types` instead of the built in
+    /// table
+    pub fn mime_types(mut self, mime_types: MimeTypes) -> Embed {
+        self.mime_types = mime_types;
+        self
+    }
+
+    /// Write the `EmbeddedDir` expression to `out`, usually a file in
+    /// `OUT_DIR`. Precompressed files are written next to it.
+    pub fn generate(&self, out: impl AsRef<Path>) -> Result<()> {
+        let out = out.as_ref();
+        let dir = self.dir.canonicalize()?;
+        println!("cargo:rerun-if-changed={}", dir.display());
+
+        let mut files = Vec::new();
+        collect_files(&dir, &mut files)?;
+        let mut files: Vec<(String, PathBuf)> = files
+            .into_iter()
+            .map(|file| {
+                let relative = file.strip_prefix(&dir)?;
+                let path = relative
+                    .components()
+                    .map(|component| component.as_os_str().to_str())
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| anyhow::anyhow!("Not a UTF-8 path: {}", file.display()))?;
+                Ok((path.join("/"), file))
+            })
+            .collect::<Result<_>>()?;
+        files.sort();
+
+        let compressed_dir = out.with_extension("precompressed");
+        if self.precompress {
+            _ = fs::remove_dir_all(&compressed_dir);
+            fs::create_dir_all(&compressed_dir)?;
+        }
+
+        let mut code = String::from("crag_web::embed::EmbeddedDir {\n    files: &[\n");
+        for (i, (path, file)) in files.iter().enumerate() {
+            let contents = fs::read(file)?;
+            let content_type = self.mime_types.content_type(file, &contents);
+            let mut variants = [(Encoding::Brotli, None), (Encoding::Gzip, None)];
+            if self.precompress && compression::is_compressible(&content_type) {
+                for (encoding, variant) in &mut variants {
+                    let compressed = compress_best(*encoding, &contents)?;
+                    if compressed.len() < contents.len() {
+                        let name = format!("{i}.{}", encoding.extension().unwrap_or_default());
+                        let compressed_file = compressed_dir.join(name);
+                        fs::write(&compressed_file, compressed)?;
+                        *variant = Some(compressed_file);
+                    }
+                }
+            }
+            let [(_, brotli), (_, gzip)] = variants;
+
+            writeln!(code, "        crag_web::embed::EmbeddedFile {{")?;
+            writeln!(code, "            path: {path:?},")?;
+            writeln!(
+                code,
+                "            contents: include_bytes!({:?}),",
+                file.display().to_string()
+            )?;
+            writeln!(code, "            content_type: {content_type:?},")?;
+            writeln!(
+                code,
+                "            etag: {:?},",
+                conditional::strong_etag(&contents)
+            )?;
+            for (field, variant) in [("brotli", brotli), ("gzip", gzip)] {
+                match variant {
+                    Some(variant) => writeln!(
+                        code,
+                        "            {field}: Some(include_bytes!({:?})),",
+                        variant.display().to_string()
+                    )?,
+                    None => writeln!(code, "            {field}: None,")?,
+                }
+            }
+            writeln!(code, "        }},")?;
+        }
+        code.push_str("    ],\n}\n");
+
+        // leave the file alone if nothing changed, so the crate including
+        // it isn't rebuilt for nothing
+        if fs::read_to_string(out).ok().as_deref() != Some(code.as_str()) {
+            fs::write(out, code)?;
+        }
+        Ok(())
+    }
+}
+
+/// The files under `dir`, following symlinks
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// `contents` compressed as small as `encoding` gets it, taking the time
+/// that a build can spare but a request can't
+fn compress_best(encoding: Encoding, contents: &[u8]) -> Result<Vec<u8>> {
+    let mut compressed = Vec::new();
+    match encoding {
+        Encoding::Brotli => {
+            let mut encoder = brotli::CompressorReader::new(contents, 4096, 11, 22);
+            encoder.read_to_end(&mut compressed)?;
+        }
+        Encoding::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(&mut compressed, flate2::Compression::best());
+            encoder.write_all(contents)?;
+            encoder.finish()?;
+        }
+        Encoding::Deflate => {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(&mut compressed, flate2::Compression::best());
+            encoder.write_all(contents)?;
+            encoder.finish()?;
+        }
+    }
+    Ok(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mime;
+    use crate::test_util::TempDir;
+
+    const fn file(path: &'static str) -> EmbeddedFile {
+        EmbeddedFile {
+            path,
+            contents: b"",
+            content_type: mime::TEXT_PLAIN,
+            etag: "\"e\"",
+            brotli: None,
+            gzip: None,
+        }
+    }
+
+    static DIR: EmbeddedDir = EmbeddedDir {
+        files: &[
+            file("css/blue.css"),
+            file("css0.txt"),
+            file("docs/api/index.html"),
+            file("index.html"),
+        ],
+    };
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(
+            DIR.get("css/blue.css").map(|file| file.path),
+            Some("css/blue.css")
+        );
+        assert!(DIR.get("css").is_none());
+        assert!(DIR.is_dir(""));
+        assert!(DIR.is_dir("css"));
+        assert!(DIR.is_dir("docs"));
+        assert!(DIR.is_dir("docs/api"));
+        assert!(!DIR.is_dir("doc"));
+        assert!(!DIR.is_dir("index.html"));
+        assert_eq!(DIR.files[0].encoded_etag(Encoding::Gzip), "\"e-gzip\"");
+    }
+
+    #[test]
+    fn test_generate() -> Result<()> {
+        let root = TempDir::new("embed")?;
+        fs::create_dir_all(root.join("site/css"))?;
+        fs::write(
+            root.join("site/css/blue.css"),
+            "body { color: blue }\n".repeat(50),
+        )?;
+        fs::write(root.join("site/logo.png"), b"\x89PNG\r\n\x1a\n")?;
+
+        let out = root.join("site.rs");
+        Embed::new(root.join("site"))
+            .precompress(true)
+            .generate(&out)?;
+        let code = fs::read_to_string(&out)?;
+        assert!(code.starts_with("crag_web::embed::EmbeddedDir {"), "{code}");
+        assert!(code.contains("path: \"css/blue.css\""), "{code}");
+        assert!(
+            code.contains("content_type: \"text/css; charset=utf-8\""),
+            "{code}"
+        );
+        assert!(code.contains("brotli: Some(include_bytes!("), "{code}");
+        // images aren't compressed
+        let logo = &code[code.find("\"logo.png\"").unwrap()..];
+        assert!(
+            logo.contains("brotli: None,\n            gzip: None,"),
+            "{code}"
+        );
+        assert!(root.join("site.precompressed/0.gz").is_file());
+        Ok(())
+    }
+}
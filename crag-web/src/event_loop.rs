@@ -0,0 +1,301 @@
+use crate::server::{self, Handlers, RequestBuffer};
+use crate::threadpool::ThreadPool;
+use anyhow::Result;
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, error};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+
+/// Most bytes read from one connection before the others get their turn
+const READ_BUDGET: usize = 64 * 1024;
+
+/// Responses produced on the pool, waiting for the event loop to write them
+type Finished = Arc<Mutex<Vec<(Token, Vec<u8>)>>>;
+
+enum State {
+    /// Collecting bytes until a complete request has arrived
+    Reading(Box<RequestBuffer>),
+    /// The request is running on the pool
+    Handling,
+    /// Writing the response back without blocking
+    Writing { response: Vec<u8>, written: usize },
+}
+
+struct Connection {
+    stream: TcpStream,
+    state: State,
+}
+
+/// Whether a connection stays registered after it was driven
+enum Step {
+    Keep,
+    Close,
+}
+
+/// Accept connections and do all their I/O on the calling thread.
+/// Only handler execution goes to the pool.
+pub(crate) fn run(
+    listener: std::net::TcpListener,
+    pool: &ThreadPool,
+    handlers: &Arc<Handlers>,
+) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener);
+
+    let mut poll = Poll::new()?;
+    poll.registry()
+        .register(&mut listener, LISTENER, Interest::READABLE)?;
+    // lets pool threads tell the loop that a response is ready
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+    let finished: Finished = Arc::default();
+
+    let mut connections: HashMap<Token, Connection> = HashMap::new();
+    let mut next_token = WAKER.0 + 1;
+    let mut events = Events::with_capacity(1024);
+
+    loop {
+        if let Err(e) = poll.poll(&mut events, None) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e.into());
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => loop {
+                    match listener.accept() {
+                        Ok((mut stream, _)) => {
+                            let token = Token(next_token);
+                            next_token += 1;
+                            if let Err(e) =
+                                poll.registry()
+                                    .register(&mut stream, token, Interest::READABLE)
+                            {
+                                // dropping the stream closes the connection
+                                error!("Error registering connection: {e:?}");
+                                continue;
+                            }
+                            let state = State::Reading(Box::new(RequestBuffer::new(
+                                handlers.max_body_size,
+                            )));
+                            connections.insert(token, Connection { stream, state });
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            error!("Error accepting connection: {e:?}");
+                            break;
+                        }
+                    }
+                },
+                WAKER => {
+                    let ready = std::mem::take(&mut *finished.lock().unwrap());
+                    for (token, response) in ready {
+                        let Some(conn) = connections.get_mut(&token) else {
+                            continue;
+                        };
+                        let step = start_writing(conn, token, response, &poll);
+                        let step = match step {
+                            Step::Keep => {
+                                drive(token, conn, pool, handlers, &finished, &waker, &poll)
+                            }
+                            Step::Close => Step::Close,
+                        };
+                        close_if_done(step, token, &mut connections, &poll);
+                    }
+                }
+                token => {
+                    if let Some(conn) = connections.get_mut(&token) {
+                        let step = drive(token, conn, pool, handlers, &finished, &waker, &poll);
+                        close_if_done(step, token, &mut connections, &poll);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn close_if_done(
+    step: Step,
+    token: Token,
+    connections: &mut HashMap<Token, Connection>,
+    poll: &Poll,
+) {
+    if let Step::Close = step {
+        if let Some(mut conn) = connections.remove(&token) {
+            if let Err(e) = poll.registry().deregister(&mut conn.stream) {
+                debug!("Error deregistering connection: {e:?}");
+            }
+            // dropping the stream closes the connection
+        }
+    }
+}
+
+/// Make as much progress on a connection as its socket allows
+fn drive(
+    token: Token,
+    conn: &mut Connection,
+    pool: &ThreadPool,
+    handlers: &Arc<Handlers>,
+    finished: &Finished,
+    waker: &Arc<Waker>,
+    poll: &Poll,
+) -> Step {
+    match &mut conn.state {
+        State::Reading(buffer) => {
+            // parsed as it arrives, so a request is refused as soon as it
+            // grows too large rather than once the client stops sending
+            let mut read = 0;
+            let parsed = loop {
+                match read_once(&mut conn.stream, buffer) {
+                    // what was read before was parsed, so a request that
+                    // arrived along with the peer closing its side is running
+                    Ok(Some(0)) => return Step::Close,
+                    Ok(Some(n)) => read += n,
+                    Ok(None) => return Step::Keep,
+                    Err(e) => {
+                        debug!("Error reading from connection: {e:?}");
+                        return Step::Close;
+                    }
+                }
+                match buffer.parse() {
+                    Ok(None) if read < READ_BUDGET => continue,
+                    parsed => break parsed,
+                }
+            };
+            match parsed {
+                Ok(Some((req, _))) => {
+                    conn.state = State::Handling;
+                    let handlers = Arc::clone(handlers);
+                    let finished = Arc::clone(finished);
+                    let waker = Arc::clone(waker);
+                    pool.execute(move || {
+                        // a panicking handler still has to hand back a
+                        // response, or the connection waits forever
+                        let response = panic::catch_unwind(AssertUnwindSafe(|| {
+                            handlers.dispatch(req).and_then(server::without_upgrade)
+                        }))
+                        .unwrap_or_else(|_| Err(anyhow::anyhow!("Handler panicked")));
+                        let response = match response {
+                            Ok(response) => Vec::<u8>::from(response),
+                            Err(e) => {
+                                error!("Error handling connection: {e:?}");
+                                server::INTERNAL_SERVER_ERROR.to_vec()
+                            }
+                        };
+                        finished.lock().unwrap().push((token, response));
+                        if let Err(e) = waker.wake() {
+                            error!("Could not wake event loop: {e:?}");
+                        }
+                    });
+                    Step::Keep
+                }
+                // other connections get their turn before the rest is read;
+                // registering again reports the socket if it is still readable
+                Ok(None) => {
+                    match poll
+                        .registry()
+                        .reregister(&mut conn.stream, token, Interest::READABLE)
+                    {
+                        Ok(()) => Step::Keep,
+                        Err(e) => {
+                            error!("Error reregistering connection: {e:?}");
+                            Step::Close
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Error handling connection: Error parsing request: {e:?}");
+                    let response = server::error_response(&e);
+                    respond_with_error(conn, token, response, poll)
+                }
+            }
+        }
+        State::Handling => Step::Keep,
+        State::Writing { response, written } => {
+            match write_available(&mut conn.stream, response, written) {
+                Ok(true) => Step::Close,
+                Ok(false) => Step::Keep,
+                Err(e) => {
+                    debug!("Error writing to connection: {e:?}");
+                    Step::Close
+                }
+            }
+        }
+    }
+}
+
+/// Switch a connection to writing `response` once its socket is writable.
+/// A connection that can't be reregistered is closed.
+fn start_writing(conn: &mut Connection, token: Token, response: Vec<u8>, poll: &Poll) -> Step {
+    conn.state = State::Writing {
+        response,
+        written: 0,
+    };
+    match poll
+        .registry()
+        .reregister(&mut conn.stream, token, Interest::WRITABLE)
+    {
+        Ok(()) => Step::Keep,
+        Err(e) => {
+            error!("Error reregistering connection: {e:?}");
+            Step::Close
+        }
+    }
+}
+
+/// Switch a connection to writing the response to a request that failed,
+/// starting right away since no handler has to run first
+fn respond_with_error(conn: &mut Connection, token: Token, response: Vec<u8>, poll: &Poll) -> Step {
+    if let Step::Close = start_writing(conn, token, response, poll) {
+        return Step::Close;
+    }
+    let State::Writing { response, written } = &mut conn.state else {
+        unreachable!()
+    };
+    match write_available(&mut conn.stream, response, written) {
+        Ok(false) => Step::Keep,
+        Ok(true) | Err(_) => Step::Close,
+    }
+}
+
+/// Read what the socket has, up to a chunk. `Some(0)` once the peer has
+/// closed its side, `None` if there is nothing to read for now.
+fn read_once(stream: &mut TcpStream, buffer: &mut RequestBuffer) -> io::Result<Option<usize>> {
+    let mut chunk = [0; 4096];
+    loop {
+        match stream.read(&mut chunk) {
+            Ok(n) => {
+                buffer.extend(&chunk[..n]);
+                return Ok(Some(n));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Write until the socket would block. Returns true once everything is written.
+fn write_available(
+    stream: &mut TcpStream,
+    response: &[u8],
+    written: &mut usize,
+) -> io::Result<bool> {
+    while *written < response.len() {
+        match stream.write(&response[*written..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => *written += n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
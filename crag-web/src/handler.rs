@@ -1,21 +1,27 @@
+use crate::body::BodyReader;
 use crate::request::Request;
 use crate::response;
 
 pub type Handler =
     Box<dyn Fn(Request) -> anyhow::Result<response::Response> + Send + Sync + 'static>;
 
-/// Default handler for 404 errors
-pub fn default_error_404_handler(_request: Request) -> anyhow::Result<response::Response> {
-    let bytes = include_bytes!("../static/html/404.html");
-    let status_line = "HTTP/1.1 404 Not Found";
-    let len = bytes.len();
-
-    // format http response
-    let response =
-        format!("{status_line}\r\nContent-Type: text/html\r\nContent-Length: {len}\r\n\r\n");
+/// Handler that reads the request body itself through a `BodyReader`
+pub type StreamingHandler =
+    Box<dyn Fn(Request, BodyReader) -> anyhow::Result<response::Response> + Send + Sync + 'static>;
 
-    let mut full_response = response.into_bytes();
-    full_response.extend(bytes);
+/// Handler for the async server, boxed so different `async fn`s can share a table
+#[cfg(feature = "tokio")]
+pub type AsyncHandler = Box<
+    dyn Fn(
+            Request,
+        ) -> std::pin::Pin<
+            Box<dyn std::future::Future<Output = anyhow::Result<response::Response>> + Send>,
+        > + Send
+        + Sync
+        + 'static,
+>;
 
-    Ok(response::Response::NotFound("not found".to_string()))
+/// Default handler for 404 errors, answering with the bundled 404 page
+pub fn default_error_404_handler(_request: Request) -> anyhow::Result<response::Response> {
+    Ok(response::Response::NotFound("Could not find resource"))
 }
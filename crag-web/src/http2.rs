@@ -0,0 +1,470 @@
+use crate::body::{BodyReader, Received};
+use crate::request::Request;
+use crate::response::{self, Body, Chunks, Response, Trailers};
+use crate::server::{self, Handlers};
+use crate::threadpool::ThreadPool;
+#[cfg(feature = "tls")]
+use crate::tls;
+use anyhow::Result;
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use std::io;
+use std::net::TcpStream;
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tracing::{debug, error};
+
+/// What a client sends first when it speaks HTTP/2 without negotiating it
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Headers that only mean something on an HTTP/1.1 connection and must
+/// not be sent over HTTP/2
+const CONNECTION_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// How many chunks of a streamed request body wait for the handler
+const FORWARDED_CHUNKS: usize = 4;
+
+/// How long a client that started sending the preface gets to finish it
+pub(crate) const PREFACE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A TLS connection that picked `h2`
+#[cfg(feature = "tls")]
+pub(crate) struct TlsIo {
+    pub(crate) stream: tokio_rustls::server::TlsStream<tokio::net::TcpStream>,
+}
+
+/// What every stream of a connection needs to be served
+struct Context {
+    handlers: Arc<Handlers>,
+    pool: Arc<ThreadPool>,
+    #[cfg(feature = "tls")]
+    peer_certificate: Option<Arc<tls::PeerCertificate>>,
+}
+
+/// The runtime that drives the I/O of every HTTP/2 connection, started
+/// with the first one
+pub(crate) fn runtime() -> io::Result<&'static Runtime> {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    if let Some(runtime) = RUNTIME.get() {
+        return Ok(runtime);
+    }
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .thread_name("crag-h2")
+        .enable_io()
+        .enable_time()
+        .build()?;
+    // a runtime built by a racing thread is dropped again
+    Ok(RUNTIME.get_or_init(|| runtime))
+}
+
+/// Whether the client opened a cleartext connection with the HTTP/2
+/// preface, i.e. h2c with prior knowledge. Nothing is consumed from the
+/// stream. A client that doesn't finish the preface within `timeout` is
+/// treated as speaking HTTP/1.1.
+pub(crate) fn is_prior_knowledge(stream: &TcpStream, timeout: Duration) -> io::Result<bool> {
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0; PREFACE.len()];
+    let result = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break Ok(false);
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        let n = match stream.peek(&mut buffer) {
+            Ok(n) => n,
+            // nothing more arrived in time, Unix reports WouldBlock
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => break Ok(false),
+            Err(e) => break Err(e),
+        };
+        if n == 0 || buffer[..n] != PREFACE[..n] {
+            break Ok(false);
+        }
+        if n == PREFACE.len() {
+            break Ok(true);
+        }
+        // only part of the preface has arrived, peek returns right away
+        thread::sleep(Duration::from_millis(1));
+    };
+    stream.set_read_timeout(None)?;
+    result
+}
+
+/// Serve an h2c connection whose preface was seen by `is_prior_knowledge`
+pub(crate) fn serve_cleartext(
+    stream: TcpStream,
+    handlers: Arc<Handlers>,
+    pool: Arc<ThreadPool>,
+) -> Result<()> {
+    let runtime = runtime()?;
+    stream.set_nonblocking(true)?;
+    let stream = {
+        let _runtime_context = runtime.enter();
+        tokio::net::TcpStream::from_std(stream)?
+    };
+    let context = Context {
+        handlers,
+        pool,
+        #[cfg(feature = "tls")]
+        peer_certificate: None,
+    };
+    spawn_connection(runtime, stream, context);
+    Ok(())
+}
+
+/// Serve a TLS connection that negotiated `h2` through ALPN
+#[cfg(feature = "tls")]
+pub(crate) fn serve_tls(io: TlsIo, handlers: Arc<Handlers>, pool: Arc<ThreadPool>) -> Result<()> {
+    let context = Context {
+        handlers,
+        pool,
+        peer_certificate: tls::peer_certificate(io.stream.get_ref().1),
+    };
+    spawn_connection(runtime()?, io.stream, context);
+    Ok(())
+}
+
+/// HTTP/2 connections are long lived and carry many requests at once, so
+/// rather than tying up a pool thread the connection becomes a task on the
+/// shared runtime and only its streams are handed to the pool.
+fn spawn_connection<IO>(runtime: &Runtime, io: IO, context: Context)
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let context = Arc::new(context);
+    runtime.spawn(async move {
+        if let Err(e) = serve_connection(io, context).await {
+            debug!("Error handling HTTP/2 connection: {e:?}");
+        }
+    });
+}
+
+async fn serve_connection<IO>(io: IO, context: Arc<Context>) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut connection = h2::server::handshake(io).await?;
+    while let Some(stream) = connection.accept().await {
+        let (request, respond) = stream?;
+        tokio::spawn(serve_stream(request, respond, Arc::clone(&context)));
+    }
+    Ok(())
+}
+
+/// Error boundary for a single stream
+async fn serve_stream(
+    request: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    context: Arc<Context>,
+) {
+    let response = match handle_stream(request, &context).await {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Error handling stream: {e:?}");
+            Response::new(server::error_status(&e))
+        }
+    };
+    if let Err(e) = send_response(&mut respond, response, &context.pool).await {
+        debug!("Error writing HTTP/2 response: {e:?}");
+    }
+}
+
+async fn handle_stream(request: http::Request<RecvStream>, context: &Context) -> Result<Response> {
+    let (parts, body) = request.into_parts();
+    #[allow(unused_mut)]
+    let mut req = read_head(&parts)?;
+    #[cfg(feature = "tls")]
+    req.set_peer_certificate(context.peer_certificate.clone());
+
+    let handlers = Arc::clone(&context.handlers);
+    let response = if handlers.streams_body(&req) {
+        let content_length = parts
+            .headers
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse().ok());
+        let (sender, received) = mpsc::channel(FORWARDED_CHUNKS);
+        let forwarding = tokio::spawn(forward_body(body, sender));
+        let body = BodyReader::channel(received, content_length);
+        let response = context
+            .pool
+            .spawn(move || handlers.dispatch_streaming(req, body))
+            .await;
+        // whatever the handler left unread isn't needed anymore
+        forwarding.abort();
+        response
+    } else {
+        read_body(body, &mut req, handlers.max_body_size).await?;
+        context.pool.spawn(move || handlers.dispatch(req)).await
+    };
+    let response = response.map_err(|_| anyhow::anyhow!("Handler panicked"))??;
+    server::without_upgrade(response)
+}
+
+/// Turn a stream's headers into the `Request` handlers expect
+fn read_head(parts: &http::request::Parts) -> Result<Request> {
+    let uri = parts
+        .uri
+        .path_and_query()
+        .map_or("/", |path| path.as_str())
+        .to_string();
+    let mut req = match &parts.method {
+        &http::Method::GET => Request::GET(uri),
+        &http::Method::POST => Request::POST(uri, String::default()),
+        method => anyhow::bail!("Invalid method {method}"),
+    };
+
+    // :authority takes the place of the Host header
+    if let Some(authority) = parts.uri.authority() {
+        if !parts.headers.contains_key(http::header::HOST) {
+            req.add_header("Host", authority.as_str());
+        }
+    }
+    for (name, value) in &parts.headers {
+        req.add_header(name.as_str(), value.to_str()?);
+    }
+    Ok(req)
+}
+
+/// Read a stream's whole body and trailers into `req`
+async fn read_body(mut body: RecvStream, req: &mut Request, max_body_size: usize) -> Result<()> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        body.flow_control().release_capacity(chunk.len())?;
+        if bytes.len() + chunk.len() > max_body_size {
+            return Err(server::ClientError::too_large(max_body_size));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    for (name, value) in body.trailers().await?.iter().flatten() {
+        req.add_trailer(name.as_str(), value.to_str()?);
+    }
+    req.add_body(String::from_utf8(bytes)?);
+    Ok(())
+}
+
+/// Hand a stream's body to a streaming handler on the pool as it arrives.
+/// The channel only holds a few chunks, so a slow handler keeps the
+/// client's flow control window from being opened further.
+async fn forward_body(mut body: RecvStream, sender: mpsc::Sender<io::Result<Received>>) {
+    while let Some(chunk) = body.data().await {
+        let received = chunk.map_err(io::Error::other).and_then(|chunk| {
+            body.flow_control()
+                .release_capacity(chunk.len())
+                .map_err(io::Error::other)?;
+            Ok(Received::Data(chunk.to_vec()))
+        });
+        let failed = received.is_err();
+        if sender.send(received).await.is_err() || failed {
+            return;
+        }
+    }
+
+    let trailers = match body.trailers().await {
+        Ok(Some(trailers)) => trailers
+            .iter()
+            .map(|(name, value)| {
+                let value = value.to_str().map_err(io::Error::other)?;
+                Ok((name.to_string(), value.to_string()))
+            })
+            .collect::<io::Result<Vec<_>>>()
+            .map(Received::Trailers),
+        Ok(None) => return,
+        Err(e) => Err(io::Error::other(e)),
+    };
+    _ = sender.send(trailers).await;
+}
+
+async fn send_response(
+    respond: &mut SendResponse<Bytes>,
+    response: Response,
+    pool: &ThreadPool,
+) -> Result<()> {
+    let (status, headers, body) = response.into_parts();
+
+    let mut builder = http::Response::builder().status(status);
+    for (name, value) in headers {
+        // the length is given from the body below
+        if name.eq_ignore_ascii_case("Content-Length")
+            || CONNECTION_HEADERS
+                .iter()
+                .any(|header| name.eq_ignore_ascii_case(header))
+        {
+            continue;
+        }
+        builder = builder.header(name, value);
+    }
+
+    match body {
+        Body::Full(body) => {
+            if response::has_content_length(status) {
+                builder = builder.header("content-length", body.len());
+            }
+            let head = builder.body(())?;
+            let mut stream = respond.send_response(head, body.is_empty())?;
+            if !body.is_empty() {
+                stream.send_data(Bytes::from(body), true)?;
+            }
+        }
+        Body::Stream { chunks, trailers } => {
+            let mut stream = respond.send_response(builder.body(())?, false)?;
+            if let Err(e) = send_chunks(&mut stream, chunks, trailers, pool).await {
+                stream.send_reset(h2::Reason::INTERNAL_ERROR);
+                return Err(e);
+            }
+        }
+        Body::Seekable {
+            reader,
+            offset,
+            len,
+        } => {
+            let chunks = response::seekable_chunks(reader, offset, len);
+            send_sized(respond, builder, status, chunks, len, pool).await?;
+        }
+        // frames are written by h2, so files are read like any other body
+        Body::File { file, offset, len } => {
+            let chunks = response::seekable_chunks(Box::new(file), offset, len);
+            send_sized(respond, builder, status, chunks, len, pool).await?;
+        }
+        Body::Takeover(_) => {
+            anyhow::bail!("Responses taking over the connection can't be sent over HTTP/2")
+        }
+    }
+    Ok(())
+}
+
+/// Send a body of `len` bytes, known up front, as it is read
+async fn send_sized(
+    respond: &mut SendResponse<Bytes>,
+    mut builder: http::response::Builder,
+    status: u16,
+    chunks: Chunks,
+    len: u64,
+    pool: &ThreadPool,
+) -> Result<()> {
+    if response::has_content_length(status) {
+        builder = builder.header("content-length", len);
+    }
+    let mut stream = respond.send_response(builder.body(())?, len == 0)?;
+    if len > 0 {
+        if let Err(e) = send_chunks(&mut stream, chunks, None, pool).await {
+            stream.send_reset(h2::Reason::INTERNAL_ERROR);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Send a streamed body as DATA frames, then its trailers. Producing a
+/// chunk may block, so each one is pulled on the pool; a chunk is only
+/// sent as fast as the client's flow control window allows.
+async fn send_chunks(
+    stream: &mut SendStream<Bytes>,
+    mut chunks: Chunks,
+    trailers: Option<Trailers>,
+    pool: &ThreadPool,
+) -> Result<()> {
+    loop {
+        let (rest, next) = pool
+            .spawn(move || {
+                let next = chunks.next();
+                (chunks, next)
+            })
+            .await
+            .map_err(|_| anyhow::anyhow!("Response body panicked"))?;
+        chunks = rest;
+        let Some(chunk) = next else {
+            break;
+        };
+
+        let mut chunk = Bytes::from(chunk?);
+        while !chunk.is_empty() {
+            stream.reserve_capacity(chunk.len());
+            let granted = std::future::poll_fn(|cx| stream.poll_capacity(cx))
+                .await
+                .ok_or_else(|| anyhow::anyhow!("Stream closed while sending the body"))??;
+            stream.send_data(chunk.split_to(granted.min(chunk.len())), false)?;
+        }
+    }
+
+    let trailers = match trailers {
+        Some(trailers) => pool
+            .spawn(trailers)
+            .await
+            .map_err(|_| anyhow::anyhow!("Response trailers panicked"))?,
+        None => Vec::new(),
+    };
+    let trailers = response::valid_trailers(trailers);
+    if trailers.is_empty() {
+        stream.send_data(Bytes::new(), true)?;
+    } else {
+        let mut map = http::HeaderMap::new();
+        for (name, value) in trailers {
+            map.append(
+                http::HeaderName::from_bytes(name.as_bytes())?,
+                http::HeaderValue::from_str(&value)?,
+            );
+        }
+        stream.send_trailers(map)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_prior_knowledge_detection() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+
+        let mut client = TcpStream::connect(addr)?;
+        client.write_all(PREFACE)?;
+        let (server, _) = listener.accept()?;
+        assert!(is_prior_knowledge(&server, PREFACE_TIMEOUT)?);
+        // the preface is still there for the HTTP/2 connection to read
+        assert!(is_prior_knowledge(&server, PREFACE_TIMEOUT)?);
+
+        let mut client = TcpStream::connect(addr)?;
+        client.write_all(b"POST / HTTP/1.1\r\n\r\n")?;
+        let (server, _) = listener.accept()?;
+        assert!(!is_prior_knowledge(&server, PREFACE_TIMEOUT)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prior_knowledge_gives_up_on_partial_preface() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let timeout = Duration::from_millis(100);
+
+        let mut client = TcpStream::connect(addr)?;
+        client.write_all(&PREFACE[..4])?;
+        let (server, _) = listener.accept()?;
+        assert!(!is_prior_knowledge(&server, timeout)?);
+        // the stream is blocking again for the HTTP/1.1 reader
+        assert_eq!(server.read_timeout()?, None);
+
+        // a client that sends nothing at all
+        let _client = TcpStream::connect(addr)?;
+        let (server, _) = listener.accept()?;
+        assert!(!is_prior_knowledge(&server, timeout)?);
+
+        Ok(())
+    }
+}
@@ -1,5 +1,25 @@
+#[cfg(feature = "tokio")]
+pub mod async_server;
+pub mod body;
+pub mod cache;
+pub mod compression;
+pub mod conditional;
+pub mod embed;
+mod event_loop;
 pub mod handler;
+#[cfg(feature = "http2")]
+mod http2;
+mod listing;
+pub mod mime;
+mod range;
 pub mod request;
 pub mod response;
 pub mod server;
+pub mod sse;
+pub mod static_files;
+#[cfg(test)]
+mod test_util;
 pub mod threadpool;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod websocket;
@@ -0,0 +1,305 @@
+use crate::conditional;
+use crate::mime;
+use crate::request::Request;
+use crate::response::Response;
+use std::cmp::Ordering;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Something in a listed directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Listed {
+    pub(crate) name: String,
+    pub(crate) is_dir: bool,
+    /// `None` for directories
+    pub(crate) size: Option<u64>,
+    pub(crate) modified: Option<SystemTime>,
+}
+
+/// What a listing can be sorted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Column {
+    Name,
+    Size,
+    Modified,
+}
+
+impl Column {
+    const ALL: [Column; 3] = [Column::Name, Column::Size, Column::Modified];
+
+    fn key(self) -> &'static str {
+        match self {
+            Column::Name => "name",
+            Column::Size => "size",
+            Column::Modified => "modified",
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Column::Name => "Name",
+            Column::Size => "Size",
+            Column::Modified => "Modified",
+        }
+    }
+}
+
+/// The listing of a directory's `entries`, as JSON if the request asks
+/// for it with `?format=json` or by accepting only `application/json`,
+/// as an HTML page otherwise. Sorted by `?sort=name|size|modified` and
+/// `?order=asc|desc`, with directories first. `is_root` leaves out the
+/// link to the parent directory.
+pub(crate) fn response(req: &Request, mut entries: Vec<Listed>, is_root: bool) -> Response {
+    let param = |name: &str| {
+        req.query()?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    };
+    let column = Column::ALL
+        .into_iter()
+        .find(|column| param("sort") == Some(column.key()))
+        .unwrap_or(Column::Name);
+    let descending = param("order") == Some("desc");
+    sort(&mut entries, column, descending);
+
+    let wants_json = param("format") == Some("json")
+        || req.header("Accept").is_some_and(|accept| {
+            accept.contains(mime::APPLICATION_JSON) && !accept.contains("text/html")
+        });
+    if wants_json {
+        Response::new(200)
+            .with_header("Content-Type", mime::APPLICATION_JSON)
+            .with_body(json(req.path(), &entries))
+    } else {
+        Response::Ok(html(req.path(), &entries, is_root, column, descending))
+    }
+}
+
+fn sort(entries: &mut [Listed], column: Column, descending: bool) {
+    entries.sort_by(|a, b| {
+        let order = match column {
+            Column::Name => Ordering::Equal,
+            Column::Size => a.size.cmp(&b.size),
+            Column::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+        .then_with(|| a.name.cmp(&b.name));
+        let order = if descending { order.reverse() } else { order };
+        // directories stay on top either way
+        b.is_dir.cmp(&a.is_dir).then(order)
+    });
+}
+
+fn html(path: &str, entries: &[Listed], is_root: bool, sorted: Column, descending: bool) -> String {
+    let path = html_escape(path);
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Index of {path}</title>\n\
+         <style>\n\
+         body {{ font-family: sans-serif; }}\n\
+         th, td {{ padding: 0.2em 1em; text-align: left; }}\n\
+         td.size {{ text-align: right; }}\n\
+         </style>\n</head>\n<body>\n<h1>Index of {path}</h1>\n<table>\n<thead><tr>"
+    );
+    for column in Column::ALL {
+        // a column sorts ascending first, then the other way round
+        let (order, arrow) = match (column == sorted, descending) {
+            (true, false) => ("desc", " &#9650;"),
+            (true, true) => ("asc", " &#9660;"),
+            (false, _) => ("asc", ""),
+        };
+        _ = write!(
+            page,
+            "<th><a href=\"?sort={key}&amp;order={order}\">{title}</a>{arrow}</th>",
+            key = column.key(),
+            title = column.title(),
+        );
+    }
+    page.push_str("</tr></thead>\n<tbody>\n");
+    if !is_root {
+        page.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let size = entry.size.map(format_size).unwrap_or_default();
+        let modified = entry
+            .modified
+            .map(conditional::http_date)
+            .unwrap_or_default();
+        _ = writeln!(
+            page,
+            "<tr><td><a href=\"{href}{slash}\">{name}{slash}</a></td>\
+             <td class=\"size\">{size}</td><td>{modified}</td></tr>",
+            href = percent_encode(&entry.name),
+            name = html_escape(&entry.name),
+        );
+    }
+    page.push_str("</tbody>\n</table>\n</body>\n</html>\n");
+    page
+}
+
+fn json(path: &str, entries: &[Listed]) -> String {
+    let entries: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let optional = |value: Option<u64>| value.map_or("null".to_string(), |v| v.to_string());
+            let modified = entry.modified.map(|modified| {
+                let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+                since_epoch.as_secs()
+            });
+            format!(
+                "{{\"name\":{name},\"type\":\"{kind}\",\"size\":{size},\"modified\":{modified}}}",
+                name = json_string(&entry.name),
+                kind = if entry.is_dir { "directory" } else { "file" },
+                size = optional(entry.size),
+                modified = optional(modified),
+            )
+        })
+        .collect();
+    format!(
+        "{{\"path\":{path},\"entries\":[{entries}]}}",
+        path = json_string(path),
+        entries = entries.join(","),
+    )
+}
+
+/// A size in bytes the way people read it
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if size < 1024 {
+        return format!("{size} B");
+    }
+    let mut scaled = size as f64;
+    let mut unit = "B";
+    for next in UNITS {
+        if scaled < 1024.0 {
+            break;
+        }
+        scaled /= 1024.0;
+        unit = next;
+    }
+    format!("{scaled:.1} {unit}")
+}
+
+/// `text` safe to put in HTML text and quoted attributes
+pub(crate) fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A file name as a relative link, so it can't be taken for a scheme,
+/// query or fragment
+fn percent_encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            _ = write!(encoded, "%{byte:02X}");
+        }
+    }
+    encoded
+}
+
+/// `text` as a JSON string literal
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => _ = write!(quoted, "\\u{:04x}", c as u32),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn file(name: &str, size: u64, modified: u64) -> Listed {
+        Listed {
+            name: name.to_string(),
+            is_dir: false,
+            size: Some(size),
+            modified: Some(UNIX_EPOCH + Duration::from_secs(modified)),
+        }
+    }
+
+    fn dir(name: &str) -> Listed {
+        Listed {
+            name: name.to_string(),
+            is_dir: true,
+            size: None,
+            modified: None,
+        }
+    }
+
+    fn names(entries: &[Listed]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_sort() {
+        let mut entries = vec![
+            file("b.txt", 30, 1),
+            dir("logs"),
+            file("A.txt", 10, 3),
+            file("c.txt", 20, 2),
+        ];
+        sort(&mut entries, Column::Name, false);
+        assert_eq!(names(&entries), ["logs", "A.txt", "b.txt", "c.txt"]);
+        sort(&mut entries, Column::Size, true);
+        assert_eq!(names(&entries), ["logs", "b.txt", "c.txt", "A.txt"]);
+        sort(&mut entries, Column::Modified, false);
+        assert_eq!(names(&entries), ["logs", "b.txt", "c.txt", "A.txt"]);
+    }
+
+    #[test]
+    fn test_escaping() {
+        let entries = [file("<script>\"&'.txt", 1, 0), file("#?x y.txt", 1, 0)];
+        let page = html("/a<b>/", &entries, false, Column::Name, false);
+        assert!(page.contains("<title>Index of /a&lt;b&gt;/</title>"));
+        assert!(page.contains(
+            "<a href=\"%3Cscript%3E%22%26%27.txt\">&lt;script&gt;&quot;&amp;&#39;.txt</a>"
+        ));
+        assert!(page.contains("<a href=\"%23%3Fx%20y.txt\">#?x y.txt</a>"));
+        assert!(!page.contains("<script>"));
+
+        let listing = json("/", &[file("quote\"back\\slash\n", 5, 60), dir("sub")]);
+        assert_eq!(
+            listing,
+            "{\"path\":\"/\",\"entries\":[\
+             {\"name\":\"quote\\\"back\\\\slash\\n\",\"type\":\"file\",\"size\":5,\"modified\":60},\
+             {\"name\":\"sub\",\"type\":\"directory\",\"size\":null,\"modified\":null}]}"
+        );
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+}
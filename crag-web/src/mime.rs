@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+pub const TEXT_HTML: &str = "text/html; charset=utf-8";
+pub const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+pub const TEXT_EVENT_STREAM: &str = "text/event-stream";
+pub const APPLICATION_JSON: &str = "application/json";
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// How many leading bytes `sniff` looks at
+pub const SNIFF_LEN: usize = 512;
+
+/// The built in type for a file extension, compared case-insensitively.
+/// Text types come with their charset.
+pub fn from_extension(extension: &str) -> Option<&'static str> {
+    let content_type = match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => TEXT_HTML,
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "txt" | "log" => TEXT_PLAIN,
+        "csv" => "text/csv; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "xml" => "application/xml",
+        "json" | "map" => APPLICATION_JSON,
+        "webmanifest" => "application/manifest+json",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => return None,
+    };
+    Some(content_type)
+}
+
+/// Guess the type from the first bytes of a file: the signatures of
+/// common binary formats, markup, and otherwise plain text if it is
+/// UTF-8 without control characters. Looks at up to `SNIFF_LEN` bytes.
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    let bytes = &bytes[..bytes.len().min(SNIFF_LEN)];
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"\0asm", "application/wasm"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b\x08", "application/gzip"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+    ];
+    if let Some((_, content_type)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| bytes.starts_with(signature))
+    {
+        return Some(content_type);
+    }
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+
+    let start = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    let markup = |tag: &[u8]| {
+        bytes[start..]
+            .get(..tag.len())
+            .is_some_and(|head| head.eq_ignore_ascii_case(tag))
+    };
+    if [b"<!doctype html".as_slice(), b"<html", b"<head", b"<body"]
+        .into_iter()
+        .any(markup)
+    {
+        return Some(TEXT_HTML);
+    }
+    if markup(b"<?xml") {
+        return Some("application/xml");
+    }
+
+    // the sample may end in the middle of a character
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&bytes[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    let binary = text
+        .chars()
+        .any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c'));
+    (!text.is_empty() && !binary).then_some(TEXT_PLAIN)
+}
+
+/// `content_type` with `charset=utf-8` added if it is a text type without
+/// parameters
+pub fn with_charset(content_type: &str) -> String {
+    let is_text = content_type.starts_with("text/") && content_type != TEXT_EVENT_STREAM;
+    if is_text && !content_type.contains(';') {
+        format!("{content_type}; charset=utf-8")
+    } else {
+        content_type.to_string()
+    }
+}
+
+/// The table content types are looked up in: the built in one, extended
+/// or overridden per extension, with sniffing as an optional fallback for
+/// files whose extension isn't known.
+///
+/// ```
+/// # use crag_web::mime::MimeTypes;
+/// let types = MimeTypes::new()
+///     .with_extension("gltf", "model/gltf+json")
+///     .with_extension("log", "text/plain")
+///     .sniff(true);
+/// assert_eq!(types.content_type("scene.gltf".as_ref(), b""), "model/gltf+json");
+/// assert_eq!(types.content_type("build.log".as_ref(), b""), "text/plain; charset=utf-8");
+/// assert_eq!(types.content_type("README".as_ref(), b"# crag-web"), "text/plain; charset=utf-8");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MimeTypes {
+    extensions: HashMap<String, String>,
+    sniff: bool,
+}
+
+impl MimeTypes {
+    /// The built in table, without sniffing
+    pub fn new() -> MimeTypes {
+        MimeTypes::default()
+    }
+
+    /// Serve files ending in `.{extension}` as `content_type`. Text types
+    /// get `charset=utf-8` unless they name a charset themselves.
+    pub fn with_extension(
+        mut self,
+        extension: impl AsRef<str>,
+        content_type: impl AsRef<str>,
+    ) -> MimeTypes {
+        self.extensions.insert(
+            extension
+                .as_ref()
+                .trim_start_matches('.')
+                .to_ascii_lowercase(),
+            with_charset(content_type.as_ref()),
+        );
+        self
+    }
+
+    /// Look at the contents of files whose extension isn't known
+    pub fn sniff(mut self, sniff: bool) -> MimeTypes {
+        self.sniff = sniff;
+        self
+    }
+
+    /// The type going by the extension of `path` alone
+    pub fn from_path(&self, path: &Path) -> Option<&str> {
+        let extension = path.extension()?.to_str()?;
+        self.extensions
+            .get(&extension.to_ascii_lowercase())
+            .map(String::as_str)
+            .or_else(|| from_extension(extension))
+    }
+
+    /// Whether `content_type` needs the start of the file to be found
+    pub fn needs_contents(&self, path: &Path) -> bool {
+        self.sniff && self.from_path(path).is_none()
+    }
+
+    /// The type of the file at `path` starting with `head`, falling back
+    /// to `application/octet-stream`
+    pub fn content_type(&self, path: &Path, head: &[u8]) -> String {
+        self.from_path(path)
+            .or_else(|| self.sniff.then(|| sniff(head)).flatten())
+            .unwrap_or(OCTET_STREAM)
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extensions() {
+        let types = MimeTypes::new();
+        assert_eq!(
+            types.content_type(Path::new("css/BLUE.CSS"), b""),
+            "text/css; charset=utf-8"
+        );
+        assert_eq!(types.content_type(Path::new("me.jpeg"), b""), "image/jpeg");
+        // without sniffing, the contents don't matter
+        assert_eq!(
+            types.content_type(Path::new("LICENSE"), b"MIT"),
+            OCTET_STREAM
+        );
+
+        let types = types
+            .with_extension(".jpeg", "image/x-custom")
+            .with_extension("tmpl", "text/html; charset=latin1");
+        assert_eq!(
+            types.content_type(Path::new("me.jpeg"), b""),
+            "image/x-custom"
+        );
+        assert_eq!(
+            types.content_type(Path::new("page.tmpl"), b""),
+            "text/html; charset=latin1"
+        );
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("image/png"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"\n  <!DOCTYPE html><html>"), Some(TEXT_HTML));
+        assert_eq!(sniff(b"<?xml version=\"1.0\"?>"), Some("application/xml"));
+        assert_eq!(sniff("plain text, caf\u{e9}".as_bytes()), Some(TEXT_PLAIN));
+        // cut off in the middle of a character
+        assert_eq!(sniff(&"caf\u{e9}".as_bytes()[..4]), Some(TEXT_PLAIN));
+        assert_eq!(sniff(b"\x7fELF\x02\x01\x01\0"), None);
+        assert_eq!(sniff(b""), None);
+
+        let types = MimeTypes::new().sniff(true);
+        assert!(types.needs_contents(Path::new("README")));
+        assert!(!types.needs_contents(Path::new("index.html")));
+        assert_eq!(
+            types.content_type(Path::new("README"), b"# notes"),
+            TEXT_PLAIN
+        );
+        assert_eq!(
+            types.content_type(Path::new("blob"), b"\0\x01"),
+            OCTET_STREAM
+        );
+    }
+
+    #[test]
+    fn test_with_charset() {
+        assert_eq!(with_charset("text/css"), "text/css; charset=utf-8");
+        assert_eq!(
+            with_charset("text/css; charset=latin1"),
+            "text/css; charset=latin1"
+        );
+        assert_eq!(with_charset("image/png"), "image/png");
+        assert_eq!(with_charset(TEXT_EVENT_STREAM), TEXT_EVENT_STREAM);
+    }
+}
@@ -0,0 +1,414 @@
+use crate::conditional;
+use crate::request::{Method, Request};
+use crate::response::{self, Body, Response, Seekable};
+use std::collections::VecDeque;
+use std::io::{self, Read, SeekFrom};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Requests for more ranges than this get the whole body, rather than
+/// making the server seek back and forth for a handful of bytes each
+const MAX_RANGES: usize = 32;
+
+/// The `Range` of a GET request, and the `If-Range` it depends on
+#[derive(Debug, Clone)]
+pub(crate) struct RangeRequest {
+    method: Method,
+    range: Option<String>,
+    if_range: Option<String>,
+}
+
+impl RangeRequest {
+    pub(crate) fn new(req: &Request) -> RangeRequest {
+        RangeRequest {
+            method: req.method(),
+            range: req.header("Range").map(str::to_string),
+            if_range: req.header("If-Range").map(str::to_string),
+        }
+    }
+
+    /// Answer with the requested parts of a `200 OK` to a GET request:
+    /// `206 Partial Content` with a single range, or with
+    /// `multipart/byteranges` for several, and
+    /// `416 Range Not Satisfiable` if none of them overlap the body.
+    /// Responses that could be answered in part say so with
+    /// `Accept-Ranges`.
+    pub(crate) fn apply(&self, response: Response) -> Response {
+        if self.method != Method::GET || response.status() != 200 {
+            return response;
+        }
+        let len = match response.body_len() {
+            Some(len) if len > 0 => len,
+            _ => return response,
+        };
+        let ranges = self.ranges(&response, len);
+        let (status, mut headers, body) = response.into_parts();
+        if !headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("Accept-Ranges"))
+        {
+            headers.push(("Accept-Ranges".to_string(), "bytes".to_string()));
+        }
+
+        let Some(ranges) = ranges else {
+            return Response::from_parts(status, headers, body);
+        };
+        let (reader, offset): (Box<dyn Seekable>, u64) = match body {
+            // a single range of a file is still sent straight from it
+            Body::File { file, offset, .. } if ranges.len() == 1 => {
+                let range = &ranges[0];
+                headers.push(("Content-Range".to_string(), content_range(range, len)));
+                let body = Body::File {
+                    file,
+                    offset: offset + range.start,
+                    len: range.end - range.start,
+                };
+                return Response::from_parts(206, headers, body);
+            }
+            Body::File { file, offset, .. } => (Box::new(file), offset),
+            Body::Seekable { reader, offset, .. } => (reader, offset),
+            Body::Full(body) => (Box::new(io::Cursor::new(body)), 0),
+            body => return Response::from_parts(status, headers, body),
+        };
+
+        match ranges.as_slice() {
+            [] => Response::new(416).with_header("Content-Range", format!("bytes */{len}")),
+            [range] => {
+                headers.push(("Content-Range".to_string(), content_range(range, len)));
+                let body = Body::Seekable {
+                    reader,
+                    offset: offset + range.start,
+                    len: range.end - range.start,
+                };
+                Response::from_parts(206, headers, body)
+            }
+            _ => {
+                let boundary = boundary();
+                let content_type = headers
+                    .iter()
+                    .position(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+                    .map(|i| headers.remove(i).1);
+                headers.push((
+                    "Content-Type".to_string(),
+                    format!("multipart/byteranges; boundary={boundary}"),
+                ));
+                let parts = Multipart::new(
+                    reader,
+                    offset,
+                    len,
+                    &ranges,
+                    &boundary,
+                    content_type.as_deref(),
+                );
+                let body = Body::Stream {
+                    chunks: response::read_chunks(parts),
+                    trailers: None,
+                };
+                Response::from_parts(206, headers, body)
+            }
+        }
+    }
+
+    /// Whether `apply` answers `response` with only part of its body, or
+    /// with `416` because none of the ranges asked for are in it
+    pub(crate) fn is_partial(&self, response: &Response) -> bool {
+        self.method == Method::GET
+            && response.status() == 200
+            && response
+                .body_len()
+                .is_some_and(|len| len > 0 && self.ranges(response, len).is_some())
+    }
+
+    /// The ranges of the `len` bytes of `response` that were asked for,
+    /// `None` if the whole of it is to be sent. A malformed `Range`
+    /// header is ignored.
+    fn ranges(&self, response: &Response, len: u64) -> Option<Vec<Range<u64>>> {
+        self.range
+            .as_deref()
+            .filter(|_| self.if_range_holds(response))
+            .and_then(|range| parse(range, len))
+    }
+
+    /// Whether `If-Range` still names the current representation, which
+    /// takes a strong `ETag` or exactly its `Last-Modified` date. Without
+    /// `If-Range` the range always applies.
+    fn if_range_holds(&self, response: &Response) -> bool {
+        let Some(if_range) = &self.if_range else {
+            return true;
+        };
+        let if_range = if_range.trim();
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            return response
+                .header("ETag")
+                .is_some_and(|etag| conditional::strong_match(if_range, etag));
+        }
+        match (
+            conditional::parse_http_date(if_range),
+            response
+                .header("Last-Modified")
+                .and_then(conditional::parse_http_date),
+        ) {
+            (Some(date), Some(last_modified)) => date == last_modified,
+            _ => false,
+        }
+    }
+}
+
+/// The ranges of a body of `len` bytes a `Range` header asks for, leaving
+/// out those that lie past its end. `None` if the header isn't a valid
+/// list of byte ranges, or has more than `MAX_RANGES` of them.
+fn parse(header: &str, len: u64) -> Option<Vec<Range<u64>>> {
+    let (unit, specs) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let specs: Vec<&str> = specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return None;
+    }
+
+    let number = |digits: &str| {
+        (!digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| digits.parse::<u64>().ok())
+            .flatten()
+    };
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let (first, last) = spec.split_once('-')?;
+        let range = match (first.trim(), last.trim()) {
+            // the last `n` bytes
+            ("", suffix) => {
+                let suffix = number(suffix)?;
+                len.saturating_sub(suffix)..len
+            }
+            (first, "") => number(first)?..len,
+            (first, last) => {
+                let (first, last) = (number(first)?, number(last)?);
+                if last < first {
+                    return None;
+                }
+                first..last.saturating_add(1).min(len)
+            }
+        };
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+    Some(ranges)
+}
+
+/// `Content-Range` of `range` in a body of `len` bytes
+fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{len}", range.start, range.end - 1)
+}
+
+/// A separator that won't turn up in the parts it separates
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let digest = conditional::strong_etag(format!("{nanos}-{count}").as_bytes());
+    format!("crag-{}", digest.trim_matches('"'))
+}
+
+/// A `multipart/byteranges` body, each range of the reader read as its
+/// part is reached
+struct Multipart {
+    reader: Box<dyn Seekable>,
+    segments: VecDeque<Segment>,
+}
+
+/// What is left of a multipart body
+enum Segment {
+    Bytes(io::Cursor<Vec<u8>>),
+    /// Positions in the reader
+    Range(Range<u64>),
+}
+
+impl Multipart {
+    fn new(
+        reader: Box<dyn Seekable>,
+        offset: u64,
+        len: u64,
+        ranges: &[Range<u64>],
+        boundary: &str,
+        content_type: Option<&str>,
+    ) -> Multipart {
+        let mut segments = VecDeque::new();
+        for range in ranges {
+            let mut head = format!("--{boundary}\r\n");
+            if let Some(content_type) = content_type {
+                head.push_str(&format!("Content-Type: {content_type}\r\n"));
+            }
+            head.push_str(&format!(
+                "Content-Range: {}\r\n\r\n",
+                content_range(range, len)
+            ));
+            segments.push_back(Segment::Bytes(io::Cursor::new(head.into_bytes())));
+            segments.push_back(Segment::Range(offset + range.start..offset + range.end));
+            segments.push_back(Segment::Bytes(io::Cursor::new(b"\r\n".to_vec())));
+        }
+        segments.push_back(Segment::Bytes(io::Cursor::new(
+            format!("--{boundary}--\r\n").into_bytes(),
+        )));
+        Multipart { reader, segments }
+    }
+}
+
+impl Read for Multipart {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(segment) = self.segments.front_mut() {
+            let n = match segment {
+                Segment::Bytes(bytes) => bytes.read(buf)?,
+                Segment::Range(range) if range.is_empty() => 0,
+                Segment::Range(range) => {
+                    self.reader.seek(SeekFrom::Start(range.start))?;
+                    let n = (&mut self.reader).take(range.end - range.start).read(buf)?;
+                    if n == 0 && !buf.is_empty() {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    range.start += n as u64;
+                    n
+                }
+            };
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.segments.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    fn get(headers: &[(&str, &str)]) -> RangeRequest {
+        RangeRequest::new(&test_util::get("/", headers))
+    }
+
+    fn collect(body: Body) -> Vec<u8> {
+        let mut output = Vec::new();
+        match body {
+            Body::Full(body) => output = body,
+            Body::Seekable {
+                reader,
+                offset,
+                len,
+            } => response::write_sized(&mut output, response::seekable_chunks(reader, offset, len))
+                .unwrap(),
+            Body::File { file, offset, len } => {
+                let chunks = response::seekable_chunks(Box::new(file), offset, len);
+                response::write_sized(&mut output, chunks).unwrap()
+            }
+            Body::Stream { chunks, .. } => response::write_sized(&mut output, chunks).unwrap(),
+            Body::Takeover(_) => {}
+        }
+        output
+    }
+
+    fn digits() -> Response {
+        Response::new(200)
+            .with_header("Content-Type", "text/plain")
+            .with_header("ETag", "\"v1\"")
+            .with_header("Last-Modified", "Sun, 06 Nov 1994 08:49:37 GMT")
+            .with_body("0123456789")
+    }
+
+    #[test]
+    fn test_parse() {
+        let parse = |header: &str, len| {
+            parse(header, len).map(|ranges| {
+                let bounds = ranges.into_iter().map(|range| (range.start, range.end));
+                bounds.collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(parse("bytes=0-4", 10), Some(vec![(0, 5)]));
+        assert_eq!(parse("bytes=5-", 10), Some(vec![(5, 10)]));
+        assert_eq!(parse("bytes=-3", 10), Some(vec![(7, 10)]));
+        assert_eq!(parse("bytes=-30", 10), Some(vec![(0, 10)]));
+        assert_eq!(parse("bytes=8-20", 10), Some(vec![(8, 10)]));
+        assert_eq!(parse("Bytes= 0-0 , 2-3", 10), Some(vec![(0, 1), (2, 4)]));
+        // nothing of these is in the body
+        assert_eq!(parse("bytes=10-", 10), Some(vec![]));
+        assert_eq!(parse("bytes=-0", 10), Some(vec![]));
+
+        for header in [
+            "items=0-1",
+            "bytes=",
+            "bytes=4-2",
+            "bytes=a-b",
+            "bytes=1",
+            "0-1",
+        ] {
+            assert_eq!(parse(header, 10), None, "{header}");
+        }
+        let many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse(&many, 10), None);
+    }
+
+    #[test]
+    fn test_single_range() {
+        let response = get(&[("Range", "bytes=2-4")]).apply(digits());
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.header("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(response.header("Accept-Ranges"), Some("bytes"));
+        assert_eq!(collect(response.into_parts().2), b"234");
+
+        let response = get(&[("Range", "bytes=20-")]).apply(digits());
+        assert_eq!(response.status(), 416);
+        assert_eq!(response.header("Content-Range"), Some("bytes */10"));
+
+        // only GET has ranges, and only whole bodies are split up
+        let mut req = Request::POST("/", "");
+        req.add_header("Range", "bytes=2-4");
+        assert_eq!(RangeRequest::new(&req).apply(digits()).status(), 200);
+        let response = get(&[("Range", "bytes=2-4")]).apply(Response::new(404).with_body("gone"));
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.header("Accept-Ranges"), None);
+    }
+
+    #[test]
+    fn test_multiple_ranges() {
+        let response = get(&[("Range", "bytes=0-1,-2")]).apply(digits());
+        assert_eq!(response.status(), 206);
+        let content_type = response.header("Content-Type").unwrap().to_string();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let body = String::from_utf8(collect(response.into_parts().2)).unwrap();
+        assert_eq!(
+            body,
+            format!(
+                "--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+                 --{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n\
+                 --{boundary}--\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_if_range() {
+        let status = |if_range| {
+            get(&[("Range", "bytes=0-1"), ("If-Range", if_range)])
+                .apply(digits())
+                .status()
+        };
+        assert_eq!(status("\"v1\""), 206);
+        assert_eq!(status("Sun, 06 Nov 1994 08:49:37 GMT"), 206);
+        // the representation changed, so the whole of it is sent
+        assert_eq!(status("\"v0\""), 200);
+        assert_eq!(status("W/\"v1\""), 200);
+        assert_eq!(status("Sun, 06 Nov 1994 08:49:36 GMT"), 200);
+    }
+}
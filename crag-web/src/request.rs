@@ -1,14 +1,62 @@
+#[cfg(feature = "tls")]
+use crate::tls::PeerCertificate;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use tracing::debug;
+
+/// Methods the server can route on
+#[derive(Debug, Eq, Hash, PartialEq, Clone, Copy)]
+pub enum Method {
+    GET,
+    POST,
+}
+
+/// A parsed HTTP request.
+///
+/// Handlers are registered with a request built by `Request::GET` or
+/// `Request::POST`; incoming requests are routed on their method and path.
 #[derive(Debug, Eq, Hash, PartialEq, Clone)]
-pub enum Request {
-    GET(String),
-    POST(String, String),
+pub struct Request {
+    method: Method,
+    uri: String,
+    body: String,
+    headers: Vec<(String, String)>,
+    trailers: Vec<(String, String)>,
+    #[cfg(feature = "tls")]
+    peer_certificate: Option<Arc<PeerCertificate>>,
 }
 
 impl Request {
+    fn new(method: Method, uri: String) -> Request {
+        Request {
+            method,
+            uri,
+            body: String::default(),
+            headers: Vec::new(),
+            trailers: Vec::new(),
+            #[cfg(feature = "tls")]
+            peer_certificate: None,
+        }
+    }
+
+    /// A GET request for `uri`
+    #[allow(non_snake_case)]
+    pub fn GET(uri: impl Into<String>) -> Request {
+        Request::new(Method::GET, uri.into())
+    }
+
+    /// A POST request for `uri` carrying `body`
+    #[allow(non_snake_case)]
+    pub fn POST(uri: impl Into<String>, body: impl Into<String>) -> Request {
+        let mut req = Request::new(Method::POST, uri.into());
+        req.body = body.into();
+        req
+    }
+
     // should this be from implementation instead?
     pub fn parse(request_line: impl AsRef<str>) -> anyhow::Result<Request> {
         let request_line = request_line.as_ref();
-        println!("{request_line}");
+        debug!("{request_line}");
         let mut parts = request_line.split_whitespace();
 
         let method = parts
@@ -31,17 +79,113 @@ impl Request {
         }
 
         let ret = match method {
-            "GET" => Request::GET(String::from(uri)),
-            "POST" => Request::POST(String::from(uri), String::default()),
+            "GET" => Request::GET(uri),
+            "POST" => Request::POST(uri, String::default()),
             _ => anyhow::bail!("Invalid method {method}"),
         };
         Ok(ret)
     }
     pub fn add_body(&mut self, body: String) {
-        if let Request::POST(_, ref mut b) = self {
-            *b = body;
+        if self.method == Method::POST {
+            self.body = body;
         };
     }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    /// The request target as sent, including any query string
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    /// The request target without its query string
+    pub fn path(&self) -> &str {
+        self.uri.split_once('?').map_or(&self.uri, |(path, _)| path)
+    }
+
+    /// The query string, without the leading `?`
+    pub fn query(&self) -> Option<&str> {
+        self.uri.split_once('?').map(|(_, query)| query)
+    }
+
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    /// Value of the first header named `name`, compared case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All headers in the order they were received
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// Whether the client asks to switch the connection to `protocol`
+    /// through `Connection: Upgrade`. A protocol without a version, e.g.
+    /// `h2c`, also matches offers with one, e.g. `h2c/1`.
+    pub fn wants_upgrade(&self, protocol: &str) -> bool {
+        let has_token = |name: &str, matches: &dyn Fn(&str) -> bool| {
+            self.headers
+                .iter()
+                .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+                .flat_map(|(_, value)| value.split(','))
+                .any(|token| matches(token.trim()))
+        };
+        has_token("Connection", &|token| token.eq_ignore_ascii_case("upgrade"))
+            && has_token("Upgrade", &|token| {
+                token.eq_ignore_ascii_case(protocol)
+                    || token
+                        .split_once('/')
+                        .is_some_and(|(name, _)| name.eq_ignore_ascii_case(protocol))
+            })
+    }
+
+    pub(crate) fn add_header(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.headers.push((name.into(), value.into()));
+    }
+
+    /// Value of the first trailer named `name`, compared
+    /// case-insensitively. Only chunked bodies carry trailers; they are
+    /// kept apart from the headers since they arrive after the body.
+    pub fn trailer(&self, name: &str) -> Option<&str> {
+        self.trailers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All trailers in the order they were received
+    pub fn trailers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.trailers.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub(crate) fn add_trailer(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.trailers.push((name.into(), value.into()));
+    }
+
+    /// The client certificate verified during a mutual TLS handshake
+    #[cfg(feature = "tls")]
+    pub fn peer_certificate(&self) -> Option<&PeerCertificate> {
+        self.peer_certificate.as_deref()
+    }
+
+    #[cfg(feature = "tls")]
+    pub(crate) fn set_peer_certificate(&mut self, certificate: Option<Arc<PeerCertificate>>) {
+        self.peer_certificate = certificate;
+    }
+
+    /// The request handlers are registered under: the same method and
+    /// path, without query, headers or body.
+    pub(crate) fn route_key(&self) -> Request {
+        Request::new(self.method, self.path().to_string())
+    }
 }
 
 #[cfg(test)]
@@ -50,47 +194,76 @@ mod tests {
 
     #[test]
     fn test_request_parser_happy_path() {
-        let req = Request::parse(&String::from("GET / HTTP/1.1")).unwrap();
+        let req = Request::parse(String::from("GET / HTTP/1.1")).unwrap();
         assert_eq!(req, Request::GET(String::from("/")));
 
-        let req = Request::parse(&String::from("POST / HTTP/1.1")).unwrap();
+        let req = Request::parse(String::from("POST / HTTP/1.1")).unwrap();
         assert_eq!(req, Request::POST(String::from("/"), String::default()));
     }
 
     #[test]
     fn test_no_verb_found() {
-        let req = Request::parse(&String::from(""));
+        let req = Request::parse(String::from(""));
         assert!(req.is_err(), "Returned request is: {req:?}");
         assert!(req.err().unwrap().to_string().contains("No method found"));
     }
 
     #[test]
     fn test_request_parser_bad_verbs() {
-        let req = Request::parse(&String::from("FOO / HTTP/1.1"));
+        let req = Request::parse(String::from("FOO / HTTP/1.1"));
         assert!(req.is_err(), "Returned request is: {req:?}");
     }
     #[test]
     fn test_good_paths() {
-        let req = Request::parse(&String::from("GET /foo/bar HTTP/1.1")).unwrap();
+        let req = Request::parse(String::from("GET /foo/bar HTTP/1.1")).unwrap();
         assert_eq!(req, Request::GET(String::from("/foo/bar")));
     }
+    #[test]
+    fn test_path_and_query() {
+        let req = Request::parse(String::from("GET /search?q=crag HTTP/1.1")).unwrap();
+        assert_eq!(req.path(), "/search");
+        assert_eq!(req.query(), Some("q=crag"));
+        assert_eq!(req.route_key(), Request::GET("/search"));
+    }
+
+    #[test]
+    fn test_headers_are_case_insensitive() {
+        let mut req = Request::GET("/");
+        req.add_header("Content-Type", "text/plain");
+        assert_eq!(req.header("content-type"), Some("text/plain"));
+        assert_eq!(req.header("Accept"), None);
+    }
+
+    #[test]
+    fn test_wants_upgrade() {
+        let mut req = Request::GET("/chat");
+        req.add_header("Upgrade", "chat/2, websocket");
+        assert!(!req.wants_upgrade("websocket"));
+
+        req.add_header("Connection", "keep-alive, Upgrade");
+        assert!(req.wants_upgrade("websocket"));
+        assert!(req.wants_upgrade("Chat"));
+        assert!(req.wants_upgrade("chat/2"));
+        assert!(!req.wants_upgrade("h2c"));
+    }
+
     #[test]
     fn test_bad_path() {
-        let req = Request::parse(&String::from("GET"));
+        let req = Request::parse(String::from("GET"));
         assert!(req.is_err(), "Returned request is: {req:?}");
         assert!(req.err().unwrap().to_string().contains("No URI found"));
     }
 
     #[test]
     fn test_missing_protocol() {
-        let req = Request::parse(&String::from("GET /"));
+        let req = Request::parse(String::from("GET /"));
         assert!(req.is_err(), "Returned request is: {req:?}");
         assert!(req.err().unwrap().to_string().contains("No protocol found"));
     }
 
     #[test]
     fn test_bad_protocol_name() {
-        let req = Request::parse(&String::from("GET / HTTP/1.0"));
+        let req = Request::parse(String::from("GET / HTTP/1.0"));
         assert!(req.is_err(), "Returned request is: {req:?}");
         assert!(req
             .err()
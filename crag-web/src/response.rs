@@ -1,31 +1,765 @@
-pub enum Response {
-    Ok(String),
-    NotFound(String),
+use crate::conditional;
+use crate::listing;
+use crate::mime::{self, MimeTypes};
+use crate::server::Connection;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+use tracing::warn;
+
+/// Takes over the connection once the response head was written
+pub(crate) type Upgrade = Box<dyn FnOnce(Upgraded) + Send>;
+
+/// Pieces of a streamed body, produced as they are written
+pub(crate) type Chunks = Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>;
+
+/// Produces trailer fields once the whole streamed body was written
+pub(crate) type Trailers = Box<dyn FnOnce() -> Vec<(String, String)> + Send>;
+
+/// A body that can be read from any position
+pub(crate) trait Seekable: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> Seekable for T {}
+
+/// Size of the chunks a streamed body is read in
+const STREAM_CHUNK_SIZE: usize = 16 * 1024;
+
+/// A response returned by a handler: status code, headers and body.
+/// `Content-Length` is filled in when the response is written.
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Body,
+}
+
+/// What follows the response head
+pub(crate) enum Body {
+    Full(Vec<u8>),
+    /// Written chunk by chunk with `Transfer-Encoding: chunked`, followed
+    /// by optional trailers
+    Stream {
+        chunks: Chunks,
+        trailers: Option<Trailers>,
+    },
+    /// `len` bytes of `reader` starting at `offset`, read as they are
+    /// written. Parts of it can be sent in answer to a `Range` request.
+    Seekable {
+        reader: Box<dyn Seekable>,
+        offset: u64,
+        len: u64,
+    },
+    /// `len` bytes of `file` starting at `offset`. Sent by the kernel
+    /// straight from the file where the connection allows, read and
+    /// written like `Seekable` where it doesn't.
+    File {
+        file: File,
+        offset: u64,
+        len: u64,
+    },
+    /// Whatever a callback writes to the connection after the head, e.g.
+    /// another protocol or an event stream. The connection is closed once
+    /// the callback is done with it.
+    Takeover(Upgrade),
+}
+
+impl Response {
+    /// An empty response with the given status code
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Full(Vec::new()),
+        }
+    }
+
+    /// `200 OK` with an HTML body
+    #[allow(non_snake_case)]
+    pub fn Ok(body: impl Into<String>) -> Response {
+        Response::new(200)
+            .with_header("Content-Type", mime::TEXT_HTML)
+            .with_body(body.into())
+    }
+
+    /// `404 Not Found` with the built in 404 page, showing `message`
+    #[allow(non_snake_case)]
+    pub fn NotFound(message: impl Into<String>) -> Response {
+        const BODY: &str = include_str!("../static/html/404.html");
+        // the text the page ships with, replaced by the message
+        const MESSAGE: &str = "Could not find resource";
+        let body = BODY.replace(MESSAGE, &listing::html_escape(&message.into()));
+        Response::new(404)
+            .with_header("Content-Type", mime::TEXT_HTML)
+            .with_body(body)
+    }
+
+    /// Add a header field. Fields whose name or value would break the
+    /// framing, and the framing headers the server sets itself, are left
+    /// out when the response is written.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Response {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = Body::Full(body.into());
+        self
+    }
+
+    /// Stream the body from `reader` instead of holding it in memory
+    pub fn with_body_reader(self, reader: impl Read + Send + 'static) -> Response {
+        self.with_chunks(read_chunks(reader))
+    }
+
+    /// Stream the body from `reader`, from its current position to its
+    /// end. Unlike `with_body_reader`, the length is known up front, and
+    /// a GET request can ask for parts of the body with `Range`.
+    ///
+    /// ```no_run
+    /// # use crag_web::response::Response;
+    /// let clip = std::fs::File::open("clips/intro.mp4")?;
+    /// let response = Response::new(200)
+    ///     .with_header("Content-Type", "video/mp4")
+    ///     .with_body_seekable(clip)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn with_body_seekable(
+        mut self,
+        reader: impl Read + Seek + Send + 'static,
+    ) -> io::Result<Response> {
+        let mut reader = reader;
+        let offset = reader.stream_position()?;
+        let len = reader.seek(SeekFrom::End(0))?.saturating_sub(offset);
+        self.body = Body::Seekable {
+            reader: Box::new(reader),
+            offset,
+            len,
+        };
+        Ok(self)
+    }
+
+    /// `200 OK` with the file at `path` as the body, along with its
+    /// `Content-Type`, `ETag` and `Last-Modified`. The file is not read
+    /// into memory: on Linux, plain HTTP/1.1 connections have the kernel
+    /// copy it to the socket with `sendfile`, other connections stream it.
+    ///
+    /// ```no_run
+    /// # use crag_web::{request::Request, response::Response};
+    /// fn report(_req: Request) -> anyhow::Result<Response> {
+    ///     Ok(Response::file("reports/latest.pdf")?)
+    /// }
+    /// ```
+    pub fn file(path: impl AsRef<Path>) -> io::Result<Response> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let mime_types = MimeTypes::new();
+        let mut head = Vec::new();
+        if mime_types.needs_contents(path) {
+            (&mut file)
+                .take(mime::SNIFF_LEN as u64)
+                .read_to_end(&mut head)?;
+            file.rewind()?;
+        }
+        let mut response =
+            Response::new(200).with_header("Content-Type", mime_types.content_type(path, &head));
+        let metadata = file.metadata()?;
+        if let Ok(modified) = metadata.modified() {
+            response = response
+                .with_header("ETag", conditional::weak_etag(metadata.len(), modified))
+                .with_header("Last-Modified", conditional::http_date(modified));
+        }
+        response.with_body_file(file)
+    }
+
+    /// Send `file` from its current position to its end as the body, the
+    /// way `Response::file` does. Like `with_body_seekable`, a GET request
+    /// can ask for parts of it with `Range`.
+    pub fn with_body_file(mut self, file: File) -> io::Result<Response> {
+        let mut file = file;
+        let offset = file.stream_position()?;
+        let len = file.metadata()?.len().saturating_sub(offset);
+        self.body = Body::File { file, offset, len };
+        Ok(self)
+    }
+
+    /// Stream the body as the pieces `chunks` produces, e.g. the rows of
+    /// a generated CSV
+    ///
+    /// ```
+    /// # use crag_web::response::Response;
+    /// let rows = (1..=1_000_000).map(|n| format!("{n},{}\n", n * n));
+    /// let response = Response::new(200)
+    ///     .with_header("Content-Type", "text/csv")
+    ///     .with_body_chunks(rows);
+    /// ```
+    pub fn with_body_chunks<I>(self, chunks: I) -> Response
+    where
+        I: IntoIterator,
+        I::IntoIter: Send + 'static,
+        I::Item: Into<Vec<u8>>,
+    {
+        self.with_chunks(Box::new(chunks.into_iter().map(|chunk| Ok(chunk.into()))))
+    }
+
+    /// Send trailer fields after the body, e.g. a checksum only known
+    /// once everything was written. `trailers` runs after the last chunk;
+    /// a body that isn't streamed yet is sent as a single chunk. Fields
+    /// whose name or value would break the framing are left out, and a
+    /// response that takes over the connection has no trailers.
+    pub fn with_trailers(
+        mut self,
+        trailers: impl FnOnce() -> Vec<(String, String)> + Send + 'static,
+    ) -> Response {
+        let chunks: Chunks = match std::mem::replace(&mut self.body, Body::Full(Vec::new())) {
+            Body::Stream { chunks, .. } => chunks,
+            Body::Full(body) => Box::new((!body.is_empty()).then_some(Ok(body)).into_iter()),
+            Body::Seekable {
+                reader,
+                offset,
+                len,
+            } => seekable_chunks(reader, offset, len),
+            Body::File { file, offset, len } => seekable_chunks(Box::new(file), offset, len),
+            body @ Body::Takeover(_) => {
+                self.body = body;
+                return self;
+            }
+        };
+        self.body = Body::Stream {
+            chunks,
+            trailers: Some(Box::new(trailers)),
+        };
+        self
+    }
+
+    fn with_chunks(mut self, chunks: Chunks) -> Response {
+        self.body = Body::Stream {
+            chunks,
+            trailers: None,
+        };
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Value of the first header named `name`, compared case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// The body, empty for streamed bodies and responses that take over
+    /// the connection
+    pub fn body(&self) -> &[u8] {
+        match &self.body {
+            Body::Full(body) => body,
+            Body::Stream { .. } | Body::Seekable { .. } | Body::File { .. } | Body::Takeover(_) => {
+                &[]
+            }
+        }
+    }
+
+    /// `101 Switching Protocols` to `protocol`. Once the response was
+    /// written, `callback` owns the connection on a thread of its own;
+    /// the connection is closed when it drops the `Upgraded` stream.
+    /// Only the threaded backend can upgrade connections.
+    ///
+    /// ```no_run
+    /// # use crag_web::{request::Request, response::Response};
+    /// # use std::io::{Read, Write};
+    /// fn echo(req: Request) -> anyhow::Result<Response> {
+    ///     if !req.wants_upgrade("echo") {
+    ///         return Ok(Response::new(426).with_header("Upgrade", "echo"));
+    ///     }
+    ///     Ok(Response::upgrade("echo", |mut stream| {
+    ///         let mut buffer = [0; 1024];
+    ///         while let Ok(n @ 1..) = stream.read(&mut buffer) {
+    ///             _ = stream.write_all(&buffer[..n]);
+    ///         }
+    ///     }))
+    /// }
+    /// ```
+    pub fn upgrade(
+        protocol: impl Into<String>,
+        callback: impl FnOnce(Upgraded) + Send + 'static,
+    ) -> Response {
+        Response::new(101)
+            .with_header("Upgrade", protocol)
+            .with_header("Connection", "Upgrade")
+            .with_takeover(callback)
+    }
+
+    /// Length of the body if it is known before it is written
+    pub(crate) fn body_len(&self) -> Option<u64> {
+        match &self.body {
+            Body::Full(body) => Some(body.len() as u64),
+            Body::Seekable { len, .. } | Body::File { len, .. } => Some(*len),
+            Body::Stream { .. } | Body::Takeover(_) => None,
+        }
+    }
+
+    /// Let `callback` write the rest of the response once the head is out
+    pub(crate) fn with_takeover(
+        mut self,
+        callback: impl FnOnce(Upgraded) + Send + 'static,
+    ) -> Response {
+        self.body = Body::Takeover(Box::new(callback));
+        self
+    }
+
+    /// Whether the connection has to be handed over once the head is written
+    pub(crate) fn takes_over(&self) -> bool {
+        matches!(self.body, Body::Takeover(_))
+    }
+
+    pub(crate) fn into_parts(self) -> (u16, Vec<(String, String)>, Body) {
+        (self.status, self.headers, self.body)
+    }
+
+    pub(crate) fn from_parts(status: u16, headers: Vec<(String, String)>, body: Body) -> Response {
+        Response {
+            status,
+            headers,
+            body,
+        }
+    }
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("body", &format_args!("{} bytes", self.body().len()))
+            .field("takes_over", &self.takes_over())
+            .finish()
+    }
+}
+
+/// A connection taken over by `Response::upgrade`. Reads first return
+/// what the client sent right behind its request, then read from the
+/// connection itself.
+pub struct Upgraded {
+    stream: Box<dyn Connection + Send>,
+    buffered: Vec<u8>,
+    /// How much of `buffered` was read already
+    position: usize,
+}
+
+impl Upgraded {
+    pub(crate) fn new(stream: Box<dyn Connection + Send>, buffered: Vec<u8>) -> Upgraded {
+        Upgraded {
+            stream,
+            buffered,
+            position: 0,
+        }
+    }
+
+    /// Bytes that arrived with the request and haven't been read yet
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffered[self.position..]
+    }
+
+    /// Make reads give up with `WouldBlock` or `TimedOut` after `timeout`,
+    /// or block for good with `None`
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position < self.buffered.len() {
+            let n = (&self.buffered[self.position..]).read(buf)?;
+            self.position += n;
+            return Ok(n);
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Drop for Upgraded {
+    fn drop(&mut self) {
+        self.stream.close();
+    }
+}
+
+/// Reason phrase for the status line
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        416 => "Range Not Satisfiable",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Headers `http1_head` writes itself, so a handler's can't contradict it
+const FRAMING_HEADERS: [&str; 3] = ["Content-Length", "Transfer-Encoding", "Connection"];
+
+/// HTTP/1.1 status line and headers, with the framing `body` needs. Header
+/// fields that would break the framing are left out.
+pub(crate) fn http1_head(status: u16, headers: &[(String, String)], body: &Body) -> Vec<u8> {
+    let mut head = format!("HTTP/1.1 {status} {reason}\r\n", reason = reason(status));
+    // a 101 hands the connection over with the `Connection` the handler set
+    let keeps_connection = status == 101 && matches!(body, Body::Takeover(_));
+    for (name, value) in headers {
+        let framing = FRAMING_HEADERS
+            .iter()
+            .any(|framing| name.eq_ignore_ascii_case(framing));
+        if framing && !(keeps_connection && name.eq_ignore_ascii_case("Connection")) {
+            warn!("Dropping {name} header, the server frames the body itself");
+            continue;
+        }
+        if !is_valid_field(name, value) {
+            warn!("Dropping invalid header field {name:?}");
+            continue;
+        }
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    match body {
+        // a 304 has no body but would have to give the length of the one
+        // it stands in for
+        Body::Full(_) if !has_content_length(status) => head.push_str("Connection: close\r\n\r\n"),
+        Body::Full(body) => head.push_str(&format!(
+            "Content-Length: {len}\r\nConnection: close\r\n\r\n",
+            len = body.len()
+        )),
+        Body::Seekable { len, .. } | Body::File { len, .. } => head.push_str(&format!(
+            "Content-Length: {len}\r\nConnection: close\r\n\r\n"
+        )),
+        Body::Stream { .. } => {
+            head.push_str("Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n")
+        }
+        // the connection carries another protocol from here on
+        Body::Takeover(_) if status == 101 => head.push_str("\r\n"),
+        // the body ends when the connection is closed
+        Body::Takeover(_) => head.push_str("Connection: close\r\n\r\n"),
+    }
+    head.into_bytes()
+}
+
+/// Whether a response with `status` states the length of its body
+pub(crate) fn has_content_length(status: u16) -> bool {
+    !matches!(status, 100..=199 | 204 | 304)
+}
+
+/// The pieces `reader` produces, read `STREAM_CHUNK_SIZE` at a time
+pub(crate) fn read_chunks(reader: impl Read + Send + 'static) -> Chunks {
+    let mut reader = reader;
+    Box::new(std::iter::from_fn(move || {
+        let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+        loop {
+            return match reader.read(&mut chunk) {
+                Ok(0) => None,
+                Ok(n) => {
+                    chunk.truncate(n);
+                    Some(Ok(chunk))
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Some(Err(e)),
+            };
+        }
+    }))
+}
+
+/// The `len` bytes of `reader` starting at `offset`. A reader that ends
+/// before them, e.g. a file truncated in the meantime, is an
+/// `UnexpectedEof` error.
+pub(crate) fn seekable_chunks(reader: Box<dyn Seekable>, offset: u64, len: u64) -> Chunks {
+    read_chunks(Window {
+        reader,
+        offset,
+        remaining: len,
+        positioned: false,
+    })
+}
+
+/// Part of a seekable reader, only positioned once it is first read
+struct Window {
+    reader: Box<dyn Seekable>,
+    offset: u64,
+    remaining: u64,
+    positioned: bool,
+}
+
+impl Read for Window {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.positioned {
+            self.reader.seek(SeekFrom::Start(self.offset))?;
+            self.positioned = true;
+        }
+        let n = (&mut self.reader).take(self.remaining).read(buf)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Write a body whose length was given in the head. Fails like
+/// `write_chunked`, leaving the body short of its length.
+pub(crate) fn write_sized(out: &mut impl Write, chunks: Chunks) -> Result<(), BodyError> {
+    for chunk in chunks {
+        out.write_all(&chunk.map_err(BodyError::Source)?)?;
+    }
+    Ok(())
 }
-const HTML_TYPE: &str = "Content-Type: text/html";
+
+/// Write a streamed body with chunked encoding. Fails with
+/// `BodyError::Source` when a chunk can't be produced, in which case the
+/// body is left unterminated so the client sees it is incomplete.
+pub(crate) fn write_chunked(
+    out: &mut impl Write,
+    chunks: Chunks,
+    trailers: Option<Trailers>,
+) -> Result<(), BodyError> {
+    for chunk in chunks {
+        let chunk = chunk.map_err(BodyError::Source)?;
+        // an empty chunk would end the body early
+        if chunk.is_empty() {
+            continue;
+        }
+        out.write_all(format!("{:x}\r\n", chunk.len()).as_bytes())?;
+        out.write_all(&chunk)?;
+        out.write_all(b"\r\n")?;
+    }
+
+    out.write_all(b"0\r\n")?;
+    for (name, value) in valid_trailers(trailers.map(|trailers| trailers()).unwrap_or_default()) {
+        out.write_all(format!("{name}: {value}\r\n").as_bytes())?;
+    }
+    out.write_all(b"\r\n")?;
+    Ok(())
+}
+
+/// Whether a header or trailer field can be written as it is. A name has
+/// to be a token and neither may contain a line break, or the field would
+/// end early and whatever follows it would be read as another field.
+fn is_valid_field(name: &str, value: &str) -> bool {
+    let is_token = !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    is_token && !value.contains(['\r', '\n', '\0'])
+}
+
+/// The trailer fields that can be sent as they are
+pub(crate) fn valid_trailers(trailers: Vec<(String, String)>) -> Vec<(String, String)> {
+    trailers
+        .into_iter()
+        .filter(|(name, value)| {
+            let valid = is_valid_field(name, value);
+            if !valid {
+                warn!("Dropping invalid trailer field {name:?}");
+            }
+            valid
+        })
+        .collect()
+}
+
+/// Why a body couldn't be written
+#[derive(Debug)]
+pub(crate) enum BodyError {
+    /// Producing the body failed
+    Source(io::Error),
+    /// Writing to the connection failed
+    Write(io::Error),
+}
+
+impl From<io::Error> for BodyError {
+    fn from(e: io::Error) -> Self {
+        BodyError::Write(e)
+    }
+}
+
+/// The whole response for servers that can't hand over connections;
+/// a streamed body is collected first and a takeover body is left out
 impl From<Response> for Vec<u8> {
     fn from(value: Response) -> Vec<u8> {
-        match value {
-            Response::Ok(body) => {
-                const STATUS_LINE: &str = "HTTP/1.0 200 OK";
-                to_output(STATUS_LINE, HTML_TYPE, body.as_str())
+        let (status, headers, body) = value.into_parts();
+        let mut output = http1_head(status, &headers, &body);
+        let sized = |output: &mut Vec<u8>, reader: Box<dyn Seekable>, offset, len| {
+            if let Err(e) = write_sized(output, seekable_chunks(reader, offset, len)) {
+                tracing::error!("Error producing response body: {e:?}");
             }
-            Response::NotFound(_) => {
-                const STATUS_LINE: &str = "HTTP/1.0 404 Not Found";
-                const BODY: &str = include_str!("../static/html/404.html");
-                to_output(STATUS_LINE, HTML_TYPE, BODY)
+        };
+        match body {
+            Body::Full(body) => output.extend(body),
+            Body::Stream { chunks, trailers } => {
+                if let Err(e) = write_chunked(&mut output, chunks, trailers) {
+                    tracing::error!("Error producing response body: {e:?}");
+                }
             }
+            Body::Seekable {
+                reader,
+                offset,
+                len,
+            } => sized(&mut output, reader, offset, len),
+            Body::File { file, offset, len } => sized(&mut output, Box::new(file), offset, len),
+            Body::Takeover(_) => {}
         }
-        .into_bytes()
+        output
     }
 }
 
-fn to_output(status: &str, content_type: &str, body: &str) -> String {
-    format!(
-        "{status}\r\nContent-Type: {type}\r\nContent-Length: {len}\r\n\r\n{body}",
-        status = status,
-        type = content_type,
-        len = body.len(),
-        body = body
-    )
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers_cannot_break_framing() {
+        let output = Vec::<u8>::from(
+            Response::new(200)
+                .with_header("X-Name", "a\r\nSet-Cookie: injected")
+                .with_header("Bad Name", "value")
+                .with_header("content-length", "100")
+                .with_header("Transfer-Encoding", "chunked")
+                .with_header("Connection", "keep-alive")
+                .with_header("X-Kept", "yes")
+                .with_body("hi"),
+        );
+        assert_eq!(
+            output,
+            b"HTTP/1.1 200 OK\r\nX-Kept: yes\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi"
+        );
+    }
+
+    #[test]
+    fn test_ok_output() {
+        let output = Vec::<u8>::from(Response::Ok("hi"));
+        assert_eq!(
+            output,
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi"
+        );
+    }
+
+    #[test]
+    fn test_custom_response() {
+        let response = Response::new(201)
+            .with_header("Location", "/items/1")
+            .with_body("created");
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.header("location"), Some("/items/1"));
+
+        let output = String::from_utf8(Vec::<u8>::from(response)).unwrap();
+        assert!(output.starts_with("HTTP/1.1 201 Created\r\nLocation: /items/1\r\n"));
+        assert!(output.ends_with("Content-Length: 7\r\nConnection: close\r\n\r\ncreated"));
+    }
+
+    #[test]
+    fn test_not_found_shows_message() {
+        let response = Response::NotFound("No <such> page");
+        assert_eq!(response.status(), 404);
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(body.contains("<p1>No &lt;such&gt; page</p1>"), "{body}");
+    }
+
+    #[test]
+    fn test_chunked_output() {
+        let response = Response::new(200)
+            .with_body_chunks(["id,name\n", "", "1,crag\n"])
+            .with_trailers(|| vec![("X-Rows".to_string(), "1".to_string())]);
+        assert_eq!(response.body(), b"");
+
+        let output = String::from_utf8(Vec::<u8>::from(response)).unwrap();
+        assert_eq!(
+            output,
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+             8\r\nid,name\n\r\n7\r\n1,crag\n\r\n0\r\nX-Rows: 1\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_trailers_cannot_inject_fields() {
+        let response = Response::new(200).with_body("ok").with_trailers(|| {
+            vec![
+                ("X-Sum".to_string(), "1\r\nSet-Cookie: evil".to_string()),
+                ("X-Bad\r\nName".to_string(), "1".to_string()),
+                ("X-Fine".to_string(), "2".to_string()),
+            ]
+        });
+        let output = String::from_utf8(Vec::<u8>::from(response)).unwrap();
+        assert!(output.ends_with("0\r\nX-Fine: 2\r\n\r\n"), "{output}");
+        assert!(!output.contains("evil"), "{output}");
+    }
+
+    #[test]
+    fn test_takeover_ignores_trailers() {
+        let response = Response::upgrade("echo", |_| {}).with_trailers(Vec::new);
+        assert!(response.takes_over());
+    }
+
+    #[test]
+    fn test_body_reader_is_streamed() {
+        let data = vec![7; STREAM_CHUNK_SIZE + 1];
+        let response = Response::new(200).with_body_reader(io::Cursor::new(data));
+        let Body::Stream { chunks, .. } = response.body else {
+            panic!("expected a streamed body");
+        };
+        let sizes: Vec<usize> = chunks.map(|chunk| chunk.unwrap().len()).collect();
+        assert_eq!(sizes, [STREAM_CHUNK_SIZE, 1]);
+    }
+
+    #[test]
+    fn test_seekable_output() -> io::Result<()> {
+        let mut reader = io::Cursor::new(b"skipped|hello".to_vec());
+        reader.seek(SeekFrom::Start(8))?;
+        let response = Response::new(200).with_body_seekable(reader)?;
+        let output = String::from_utf8(Vec::<u8>::from(response)).unwrap();
+        assert_eq!(
+            output,
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello"
+        );
+
+        // the reader is shorter than it was said to be
+        let chunks = seekable_chunks(Box::new(io::Cursor::new(b"hello".to_vec())), 2, 5);
+        let e = write_sized(&mut Vec::new(), chunks).unwrap_err();
+        assert!(matches!(e, BodyError::Source(e) if e.kind() == io::ErrorKind::UnexpectedEof));
+        Ok(())
+    }
 }
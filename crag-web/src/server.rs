@@ -1,45 +1,335 @@
+use crate::body::{BodyReader, ChunkedDecoder, Framing};
+use crate::cache::{Lookup, ResponseCache};
+use crate::compression::{self, Compression};
+use crate::conditional::Preconditions;
+use crate::event_loop;
 use crate::handler;
+#[cfg(feature = "http2")]
+use crate::http2;
+use crate::range::RangeRequest;
 use crate::request;
 use crate::request::Request;
-use crate::response::Response;
+use crate::response::{self, Body, BodyError, Response, Upgrade, Upgraded};
+use crate::static_files::ServeDir;
 use crate::threadpool;
-use anyhow::Result;
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::websocket;
+use anyhow::{Context, Result};
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::net::TcpListener;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::net::ToSocketAddrs;
+use std::net::{TcpListener, TcpStream};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use tracing::error;
 
-type HandlerMap = HashMap<request::Request, handler::Handler>;
-struct Handlers {
-    valid_handlers: HandlerMap,
-    error_handler: handler::Handler,
+/// Written when a request can't be parsed or its handler fails
+pub(crate) const INTERNAL_SERVER_ERROR: &[u8] = b"HTTP/1.1 500 Internal Server Error\r\n\r\n";
+
+/// A request refused because of how the client sent it, answered with its
+/// own status rather than a 500
+#[derive(Debug)]
+pub(crate) struct ClientError {
+    status: u16,
+    message: String,
+}
+
+impl ClientError {
+    /// 400, for a request that can't be read the way it is framed
+    fn bad_request(message: impl Into<String>) -> anyhow::Error {
+        anyhow::Error::new(ClientError {
+            status: 400,
+            message: message.into(),
+        })
+    }
+
+    /// 400, for a head or body that isn't valid UTF-8
+    fn invalid_utf8(part: &str) -> anyhow::Error {
+        ClientError::bad_request(format!("Request {part} is not valid UTF-8"))
+    }
+
+    /// 431, for a head that doesn't end within `MAX_HEAD_SIZE` bytes
+    pub(crate) fn head_too_large() -> anyhow::Error {
+        anyhow::Error::new(ClientError {
+            status: 431,
+            message: format!("Request head larger than {MAX_HEAD_SIZE} bytes"),
+        })
+    }
+
+    /// 413, for a body larger than the server accepts
+    pub(crate) fn too_large(max_body_size: usize) -> anyhow::Error {
+        anyhow::Error::new(ClientError {
+            status: 413,
+            message: format!("Request body larger than {max_body_size} bytes"),
+        })
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// The status a request that failed with `e` is answered with
+pub(crate) fn error_status(e: &anyhow::Error) -> u16 {
+    e.downcast_ref::<ClientError>().map_or(500, |e| e.status)
+}
+
+/// Written when a request fails before its handler produced a response
+pub(crate) fn error_response(e: &anyhow::Error) -> Vec<u8> {
+    match error_status(e) {
+        500 => INTERNAL_SERVER_ERROR.to_vec(),
+        status => Vec::from(Response::new(status)),
+    }
+}
+
+/// Largest request body read unless changed with `max_body_size`
+pub const DEFAULT_MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Sent to a client holding back its body until the server is ready for it
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+pub(crate) type HandlerMap<H = handler::Handler> = HashMap<request::Request, H>;
+
+/// Answers the requests under a path prefix, or `None` to leave one to
+/// the error handler
+pub(crate) type Mount = Box<dyn Fn(&Request) -> Result<Option<Response>> + Send + Sync + 'static>;
+
+/// Routing table shared by the blocking and async servers, generic over
+/// the kind of handler stored, along with the limit request bodies are
+/// read with. Routes whose handlers read the body themselves are kept
+/// apart, since their body must not be read before routing.
+pub(crate) struct Handlers<H = handler::Handler> {
+    valid_handlers: HandlerMap<H>,
+    error_handler: H,
+    pub(crate) streaming_handlers: HandlerMap<handler::StreamingHandler>,
+    /// Tried longest prefix first for requests no handler matches
+    mounts: Vec<(Request, Mount)>,
+    pub(crate) max_body_size: usize,
+    pub(crate) compression: Option<Compression>,
+    pub(crate) cache: Option<ResponseCache>,
+}
+impl<H> Handlers<H> {
+    /// an error handler must always be defined or this will err.
+    pub(crate) fn new(valid_handlers: HandlerMap<H>, error_handler: Option<H>) -> Result<Self> {
+        // Check to see that there is a handler for 404 errors
+        let error_handler = match error_handler {
+            Some(eh) => eh,
+            None => anyhow::bail!("No handler for 404 errors"),
+        };
+        Ok(Handlers {
+            valid_handlers,
+            error_handler,
+            streaming_handlers: HandlerMap::new(),
+            mounts: Vec::new(),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            compression: None,
+            cache: None,
+        })
+    }
+
+    /// What the cache has for the request, if caching was enabled
+    pub(crate) fn lookup(&self, req: &Request) -> Lookup {
+        match &self.cache {
+            Some(cache) => cache.lookup(req),
+            None => Lookup::Uncached,
+        }
+    }
+
+    /// Answer a request with `response` as it applies to it: checked
+    /// against its preconditions, cut to its range, or else compressed if
+    /// compression was enabled. Whether the response varies with
+    /// `Accept-Encoding` and the form of its `ETag` are settled before the
+    /// preconditions are checked, so a `304` says the same as the response
+    /// it stands in for.
+    pub(crate) fn respond(
+        &self,
+        preconditions: &Preconditions,
+        range: &RangeRequest,
+        accept_encoding: Option<&str>,
+        response: Response,
+    ) -> Response {
+        let response = preconditions.with_etag(response);
+        let (response, encoding) = match &self.compression {
+            // a range is cut from the body as the handler made it, which
+            // keeps its strong ETag for `If-Range` to match
+            Some(compression) if !range.is_partial(&response) => {
+                compression.prepare(accept_encoding, response)
+            }
+            _ => (response, None),
+        };
+        let response = range.apply(preconditions.apply(response));
+        compression::encode(encoding, response)
+    }
+
+    /// The handler registered for the request
+    #[cfg(feature = "tokio")]
+    pub(crate) fn route(&self, req: &Request) -> Option<&H> {
+        self.valid_handlers.get(&req.route_key())
+    }
+
+    /// The handler for requests nothing else answers
+    #[cfg(feature = "tokio")]
+    pub(crate) fn error_handler(&self) -> &H {
+        &self.error_handler
+    }
+
+    /// The mount with the longest prefix the request falls under
+    pub(crate) fn mount_for(&self, req: &Request) -> Option<&Mount> {
+        self.mounts
+            .iter()
+            .find(|(prefix, _)| {
+                prefix.method() == req.method() && under_prefix(prefix.path(), req.path())
+            })
+            .map(|(_, mount)| mount)
+    }
+
+    pub(crate) fn set_mounts(&mut self, mut mounts: Vec<(Request, Mount)>) {
+        mounts.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.path().len()));
+        self.mounts = mounts;
+    }
 }
 impl Handlers {
-    fn handle_error(&self, req: Request) -> Result<Response> {
+    /// Run the handler registered for the request, or the mount the
+    /// request falls under, or the error handler
+    pub(crate) fn dispatch(&self, req: Request) -> Result<Response> {
+        if self.streams_body(&req) {
+            anyhow::bail!("Streamed request bodies are only supported by the threaded server");
+        }
+        let preconditions = Preconditions::new(&req);
+        let range = RangeRequest::new(&req);
+        let accept_encoding = req.header("Accept-Encoding").map(str::to_string);
+        let response = match self.lookup(&req) {
+            Lookup::Hit(response) => response,
+            lookup => lookup.store(self.run_handler(req)?),
+        };
+        Ok(self.respond(&preconditions, &range, accept_encoding.as_deref(), response))
+    }
+
+    fn run_handler(&self, req: Request) -> Result<Response> {
+        let key = req.route_key();
+        if let Some(handler) = self.valid_handlers.get(&key) {
+            return handler(req);
+        }
+        if let Some(mount) = self.mount_for(&req) {
+            if let Some(response) = mount(&req)? {
+                return Ok(response);
+            }
+        }
         (self.error_handler)(req)
     }
+
+    /// Whether the request's handler reads the body itself
+    pub(crate) fn streams_body(&self, req: &Request) -> bool {
+        self.streaming_handlers.contains_key(&req.route_key())
+    }
+
+    /// Run the handler registered to read the request's body itself
+    pub(crate) fn dispatch_streaming(&self, req: Request, body: BodyReader) -> Result<Response> {
+        let handler = self
+            .streaming_handlers
+            .get(&req.route_key())
+            .ok_or_else(|| anyhow::anyhow!("No streaming handler for {}", req.path()))?;
+        handler(req, body)
+    }
+}
+
+/// Serve `dir` under `prefix`, replacing whatever was mounted there before
+pub(crate) fn add_mount(mounts: &mut Vec<(Request, Mount)>, prefix: &str, dir: ServeDir) {
+    let prefix = normalize_prefix(prefix);
+    mounts.retain(|(mounted, _)| mounted.path() != prefix);
+    let mount: Mount = Box::new({
+        let prefix = prefix.clone();
+        move |req| dir.serve(req, &req.path()[prefix.len()..])
+    });
+    mounts.push((Request::GET(prefix), mount));
+}
+
+/// `prefix` normalized without a trailing slash, so `/` mounts at the root
+fn normalize_prefix(prefix: &str) -> String {
+    format!("/{}", prefix.trim_matches('/'))
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// Whether `path` is `prefix` or below it, going by whole segments
+fn under_prefix(prefix: &str, path: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// How the server drives connection I/O
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Each connection is handled start to finish by a pool thread.
+    /// With the `http2` feature, the I/O of HTTP/2 connections is driven
+    /// by one shared runtime and only their streams run on the pool.
+    #[default]
+    Threaded,
+    /// A single event loop does all connection I/O without blocking and
+    /// only hands complete requests to the pool, so slow or idle clients
+    /// don't tie up pool threads
+    EventLoop,
 }
 
 pub struct Server {
     tcp_listener: TcpListener,
-    pool: threadpool::ThreadPool,
+    pool: Arc<threadpool::ThreadPool>,
     handlers: Arc<Handlers>,
+    backend: Backend,
+    #[cfg(feature = "tls")]
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+    #[cfg(feature = "tls")]
+    tls_handshake_timeout: Duration,
 }
 
 pub struct ServerBuilder {
     handlers: HandlerMap,
+    streaming_handlers: HandlerMap<handler::StreamingHandler>,
+    mounts: Vec<(Request, Mount)>,
     error_handler: Option<handler::Handler>,
+    pool_builder: threadpool::ThreadPoolBuilder,
+    backend: Backend,
+    max_body_size: usize,
+    compression: Option<Compression>,
+    cache: Option<ResponseCache>,
+    #[cfg(feature = "tls")]
+    tls: Option<tls::TlsSettings>,
+    #[cfg(feature = "tls")]
+    tls_handshake_timeout: Duration,
 }
 impl ServerBuilder {
     /// Finalize the server builder and create a server instance.
     /// an error handler must always be defined or this will err.
+    ///
+    /// `pool_size` is the number of worker threads, or the minimum number
+    /// when the pool was configured to grow with `thread_pool`.
     pub fn finalize(self, addr: impl ToSocketAddrs, pool_size: usize) -> Result<Server> {
-        // Check to see that there is a handler for 404 errors
-        let error_handler = match self.error_handler {
-            Some(eh) => eh,
-            None => anyhow::bail!("No handler for 404 errors"),
+        if !self.streaming_handlers.is_empty() && self.backend != Backend::Threaded {
+            anyhow::bail!("Streamed request bodies are only supported by the threaded backend");
+        }
+        let mut handlers = Handlers::new(self.handlers, self.error_handler)?;
+        handlers.streaming_handlers = self.streaming_handlers;
+        handlers.set_mounts(self.mounts);
+        handlers.max_body_size = self.max_body_size;
+        handlers.compression = self.compression;
+        handlers.cache = self.cache;
+        let handlers = Arc::new(handlers);
+
+        #[cfg(feature = "tls")]
+        let tls_config = match self.tls {
+            Some(_) if self.backend != Backend::Threaded => {
+                anyhow::bail!("TLS is only supported by the threaded backend")
+            }
+            Some(settings) => Some(settings.server_config()?),
+            None => None,
         };
 
         let socket_addr = addr
@@ -48,34 +338,188 @@ impl ServerBuilder {
             .ok_or_else(|| anyhow::anyhow!("Could not resolve address"))?;
 
         let tcp_listener = TcpListener::bind(socket_addr)?;
-        let pool = threadpool::ThreadPool::build(pool_size)?;
-        let handlers = Arc::new(Handlers {
-            valid_handlers: self.handlers,
-            error_handler,
-        });
+        let pool = Arc::new(self.pool_builder.finalize(pool_size)?);
 
         let server = Server {
             tcp_listener,
             pool,
             handlers,
+            backend: self.backend,
+            #[cfg(feature = "tls")]
+            tls_config,
+            #[cfg(feature = "tls")]
+            tls_handshake_timeout: self.tls_handshake_timeout,
         };
 
         Ok(server)
     }
+    /// Configure the worker threads, e.g. to let the pool grow and shrink
+    /// with load:
+    ///
+    /// ```no_run
+    /// # use crag_web::{handler, server::Server, threadpool::ThreadPool};
+    /// let server = Server::build()
+    ///     .thread_pool(ThreadPool::builder().name_prefix("crag").max_size(64))
+    ///     .register_error_handler(handler::default_error_404_handler)?
+    ///     .finalize(("127.0.0.1", 8080), 4)?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn thread_pool(mut self, pool_builder: threadpool::ThreadPoolBuilder) -> Self {
+        self.pool_builder = pool_builder;
+        self
+    }
+
+    /// Select how connections are driven, see `Backend`
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Serve HTTPS using a PEM encoded certificate chain and private key.
+    /// This certificate is used for clients that don't ask for a name
+    /// registered with `tls_sni`.
+    #[cfg(feature = "tls")]
+    pub fn tls(
+        mut self,
+        cert_chain_pem: impl AsRef<[u8]>,
+        key_pem: impl AsRef<[u8]>,
+    ) -> Result<Self> {
+        self.tls
+            .get_or_insert_with(Default::default)
+            .set_default_cert(cert_chain_pem.as_ref(), key_pem.as_ref())?;
+        Ok(self)
+    }
+
+    /// Serve HTTPS with a certificate picked by the server name the client
+    /// asks for through SNI.
+    #[cfg(feature = "tls")]
+    pub fn tls_sni(
+        mut self,
+        server_name: impl AsRef<str>,
+        cert_chain_pem: impl AsRef<[u8]>,
+        key_pem: impl AsRef<[u8]>,
+    ) -> Result<Self> {
+        self.tls.get_or_insert_with(Default::default).add_sni_cert(
+            server_name.as_ref(),
+            cert_chain_pem.as_ref(),
+            key_pem.as_ref(),
+        )?;
+        Ok(self)
+    }
+
+    /// Ask clients for a certificate signed by one of the CAs in the PEM
+    /// bundle. Handlers find the verified certificate through
+    /// `Request::peer_certificate`.
+    #[cfg(feature = "tls")]
+    pub fn tls_client_auth(
+        mut self,
+        ca_bundle_pem: impl AsRef<[u8]>,
+        mode: tls::ClientAuth,
+    ) -> Result<Self> {
+        self.tls
+            .get_or_insert_with(Default::default)
+            .set_client_auth(ca_bundle_pem.as_ref(), mode)?;
+        Ok(self)
+    }
+
+    /// How long a client gets to finish the TLS handshake before the
+    /// connection is dropped. Defaults to `tls::DEFAULT_HANDSHAKE_TIMEOUT`.
+    #[cfg(feature = "tls")]
+    pub fn tls_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.tls_handshake_timeout = timeout;
+        self
+    }
+
+    /// Refuse requests whose body, once a chunked upload is decoded, is
+    /// larger than `max_body_size` bytes. Defaults to
+    /// `DEFAULT_MAX_BODY_SIZE`; bodies of streaming handlers aren't
+    /// limited.
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Compress responses for clients that accept it, as configured by
+    /// `compression`
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Answer GET requests with the responses `cache` kept, for handlers
+    /// that allow it with `Cache-Control`
+    pub fn cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
     pub fn register_handler(
         mut self,
         r: request::Request,
         handler: impl Fn(Request) -> anyhow::Result<Response> + 'static + Send + Sync,
     ) -> Self {
-        self.handlers.insert(r, Box::new(handler));
+        self.streaming_handlers.remove(&r.route_key());
+        self.handlers.insert(r.route_key(), Box::new(handler));
+        self
+    }
+
+    /// Register a handler that reads the request body itself, as it
+    /// arrives, instead of getting it as part of the `Request`. Meant for
+    /// uploads too large to hold in memory:
+    ///
+    /// ```no_run
+    /// # use crag_web::{handler, request::Request, response::Response, server::Server};
+    /// let server = Server::build()
+    ///     .register_streaming_handler(Request::POST("/artifacts", ""), |_req, mut body| {
+    ///         let mut file = std::fs::File::create("/tmp/artifact")?;
+    ///         let written = std::io::copy(&mut body, &mut file)?;
+    ///         Ok(Response::Ok(format!("{written} bytes")))
+    ///     })
+    ///     .register_error_handler(handler::default_error_404_handler)?
+    ///     .finalize(("127.0.0.1", 8080), 4)?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    ///
+    /// Only the threaded backend can stream request bodies.
+    pub fn register_streaming_handler(
+        mut self,
+        r: request::Request,
+        handler: impl Fn(Request, BodyReader) -> anyhow::Result<Response> + 'static + Send + Sync,
+    ) -> Self {
+        self.handlers.remove(&r.route_key());
+        self.streaming_handlers
+            .insert(r.route_key(), Box::new(handler));
         self
     }
 
+    /// Serve the files of `dir` for GET requests under `prefix`, e.g.
+    /// `/static/css/blue.css` from `dir`'s `css/blue.css` when mounted at
+    /// `/static`. Handlers registered for a path take precedence, and a
+    /// longer prefix over a shorter one.
+    pub fn mount(mut self, prefix: impl AsRef<str>, dir: ServeDir) -> Self {
+        add_mount(&mut self.mounts, prefix.as_ref(), dir);
+        self
+    }
+
+    /// Serve WebSocket sessions on `path`. Once the handshake is done,
+    /// `handler` runs on a thread of its own for as long as the session
+    /// lasts. Only the threaded backend can upgrade connections.
+    pub fn register_websocket(
+        self,
+        path: impl Into<String>,
+        handler: impl Fn(Request, websocket::WebSocket) -> anyhow::Result<()> + 'static + Send + Sync,
+    ) -> Self {
+        let handler = Arc::new(handler);
+        self.register_handler(Request::GET(path), move |req| {
+            Ok(websocket::upgrade(req, Arc::clone(&handler)))
+        })
+    }
+
     pub fn register_error_handler(
         mut self,
         handler: impl Fn(Request) -> anyhow::Result<Response> + 'static + Send + Sync,
     ) -> Result<Self> {
-        if let Some(_) = self.error_handler {
+        if self.error_handler.is_some() {
             anyhow::bail!("Error handler already registered");
         }
         self.error_handler = Some(Box::new(handler));
@@ -87,57 +531,289 @@ impl Server {
     pub fn build() -> ServerBuilder {
         ServerBuilder {
             handlers: HashMap::new(),
+            streaming_handlers: HashMap::new(),
+            mounts: Vec::new(),
             error_handler: None,
+            pool_builder: threadpool::ThreadPool::builder(),
+            backend: Backend::default(),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            compression: None,
+            cache: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            tls_handshake_timeout: tls::DEFAULT_HANDSHAKE_TIMEOUT,
         }
     }
+
+    /// The pool running the handlers, e.g. to resize it at runtime
+    pub fn thread_pool(&self) -> &threadpool::ThreadPool {
+        &self.pool
+    }
     pub fn run(&self) -> Result<()> {
+        match self.backend {
+            Backend::Threaded => self.run_threaded(),
+            Backend::EventLoop => {
+                event_loop::run(self.tcp_listener.try_clone()?, &self.pool, &self.handlers)
+            }
+        }
+    }
+
+    fn run_threaded(&self) -> Result<()> {
         for stream in self.tcp_listener.incoming() {
-            let mut stream = stream?;
+            let stream = stream?;
             let handlers = self.handlers.clone();
+            #[cfg(feature = "http2")]
+            let pool = self.pool.clone();
+
+            #[cfg(feature = "tls")]
+            if let Some(config) = &self.tls_config {
+                let config = config.clone();
+                let timeout = self.tls_handshake_timeout;
+                self.pool.execute(move || {
+                    #[cfg(feature = "http2")]
+                    let accepted = tls::handshake(&config, stream, timeout);
+                    #[cfg(not(feature = "http2"))]
+                    let accepted = tls::accept(&config, stream, timeout);
+                    match accepted {
+                        #[cfg(feature = "http2")]
+                        Ok(tls::Negotiated::Http2(io)) => {
+                            if let Err(e) = http2::serve_tls(io, handlers, pool) {
+                                error!("Error starting HTTP/2 connection: {e:?}");
+                            }
+                        }
+                        #[cfg(feature = "http2")]
+                        Ok(tls::Negotiated::Http1(stream)) => serve_and_close(&handlers, stream),
+                        #[cfg(not(feature = "http2"))]
+                        Ok(stream) => serve_and_close(&handlers, stream),
+                        Err(e) => error!("Error accepting TLS connection: {e:?}"),
+                    }
+                });
+                continue;
+            }
 
             self.pool.execute(move || {
-                if let Err(e) = handle_connection(&handlers, &mut stream) {
-                    // Error boundary for the thread handling the connection
-                    error!("Error handling connection: {e:?}");
-                    _ = stream.write_all("HTTP/1.1 500 Internal Server Error\r\n\r\n".as_bytes());
+                #[cfg(feature = "http2")]
+                if let Ok(true) = http2::is_prior_knowledge(&stream, http2::PREFACE_TIMEOUT) {
+                    if let Err(e) = http2::serve_cleartext(stream, handlers, pool) {
+                        error!("Error starting HTTP/2 connection: {e:?}");
+                    }
+                    return;
                 }
+
+                serve_and_close(&handlers, stream)
             });
         }
         Ok(())
     }
 }
 
-fn handle_connection<S>(handlers: &Handlers, stream: &mut S) -> Result<()>
+/// A stream requests can be served over
+pub(crate) trait Connection: Read + Write {
+    /// The client certificate verified by a mutual TLS handshake
+    #[cfg(feature = "tls")]
+    fn peer_certificate(&self) -> Option<Arc<tls::PeerCertificate>> {
+        None
+    }
+
+    /// Make reads give up with `WouldBlock` or `TimedOut` after `timeout`,
+    /// or block for good with `None`
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Write `len` bytes of `file` starting at `offset`, the body of a
+    /// response whose head is out. Read and written chunk by chunk unless
+    /// the connection can do better.
+    fn send_file(&mut self, file: File, offset: u64, len: u64) -> Result<(), BodyError> {
+        let chunks = response::seekable_chunks(Box::new(file), offset, len);
+        response::write_sized(&mut &mut *self, chunks)
+    }
+
+    /// Flush what is left to write before the connection is dropped
+    fn close(&mut self) {
+        _ = self.flush();
+    }
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_file(&mut self, file: File, offset: u64, len: u64) -> Result<(), BodyError> {
+        sendfile(self, file, offset, len)
+    }
+}
+
+/// Have the kernel copy `len` bytes of `file` from `offset` to `stream`
+/// without passing them through user space. Falls back to reading and
+/// writing them for files it can't send, e.g. on some FUSE file systems.
+#[cfg(target_os = "linux")]
+fn sendfile(stream: &mut TcpStream, file: File, offset: u64, len: u64) -> Result<(), BodyError> {
+    use std::os::fd::AsRawFd;
+
+    // the most a single call moves
+    const MAX_COUNT: u64 = 0x7fff_f000;
+    let mut position = libc::off_t::try_from(offset)
+        .map_err(|_| BodyError::Source(io::ErrorKind::InvalidInput.into()))?;
+    let mut remaining = len;
+    while remaining > 0 {
+        let count = remaining.min(MAX_COUNT) as usize;
+        // SAFETY: both descriptors stay open while they are borrowed, and
+        // the kernel only writes through the pointer to `position`.
+        let sent =
+            unsafe { libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut position, count) };
+        if sent < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                _ if e.kind() == io::ErrorKind::Interrupted => continue,
+                Some(libc::EINVAL | libc::ENOSYS) if remaining == len => {
+                    let chunks = response::seekable_chunks(Box::new(file), offset, len);
+                    return response::write_sized(stream, chunks);
+                }
+                _ => return Err(BodyError::Write(e)),
+            }
+        }
+        if sent == 0 {
+            // the file was truncated since its length went out in the head
+            return Err(BodyError::Source(io::ErrorKind::UnexpectedEof.into()));
+        }
+        remaining -= sent as u64;
+    }
+    Ok(())
+}
+
+/// Serve the request on a connection, then close it, unless the response
+/// switched it to another protocol. Upgraded connections are long lived,
+/// so they get a thread of their own rather than holding on to the pool.
+fn serve_and_close<S>(handlers: &Handlers, mut stream: S)
+where
+    S: Connection + Send + 'static,
+{
+    let Some((upgrade, buffered)) = serve_connection(handlers, &mut stream) else {
+        stream.close();
+        return;
+    };
+    let spawned = thread::Builder::new()
+        .name("crag-upgraded".to_string())
+        .spawn(move || upgrade(Upgraded::new(Box::new(stream), buffered)));
+    if let Err(e) = spawned {
+        error!("Error starting upgraded connection: {e:?}");
+    }
+}
+
+/// Error boundary for the thread handling the connection
+fn serve_connection<S>(handlers: &Handlers, stream: &mut S) -> Option<(Upgrade, Vec<u8>)>
+where
+    S: Connection + Send,
+{
+    match handle_connection(handlers, stream) {
+        Ok(upgrade) => upgrade,
+        Err(e) => {
+            error!("Error handling connection: {e:?}");
+            _ = stream.write_all(&error_response(&e));
+            None
+        }
+    }
+}
+
+/// Returns how to take over the connection if the response asked for it,
+/// along with the bytes that were read past the request
+fn handle_connection<S>(handlers: &Handlers, stream: &mut S) -> Result<Option<(Upgrade, Vec<u8>)>>
 where
-    S: Read + Write,
+    S: Connection + Send,
 {
-    let req = read_and_parse_request(stream)
-        .map_err(|e| anyhow::anyhow!("Error parsing request: {e:?}"))?;
+    let mut buffer = BufReader::new(&mut *stream);
+    #[allow(unused_mut)]
+    let (mut req, framing) = read_head(&mut buffer).context("Error parsing request")?;
+
+    // the handshake has completed once the request could be read
+    #[cfg(feature = "tls")]
+    req.set_peer_certificate(buffer.get_ref().peer_certificate());
+
+    let streams_body = handlers.streams_body(&req);
+    if let (false, Framing::Length(content_length)) = (streams_body, framing) {
+        // refused before the client is told to send it
+        check_body_size(content_length, handlers.max_body_size)?;
+    }
+    let expects_continue = req
+        .header("Expect")
+        .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"));
+    if expects_continue && framing != Framing::Length(0) {
+        buffer.get_mut().write_all(CONTINUE)?;
+    }
 
     // build response
-    let response = match handlers.valid_handlers.get(&req) {
-        Some(handler) => handler(req),
-        None => handlers.handle_error(req),
+    let response = if streams_body {
+        handlers.dispatch_streaming(req, BodyReader::new(&mut buffer, framing))?
+    } else {
+        read_body(&mut buffer, &mut req, framing, handlers.max_body_size)
+            .context("Error parsing request")?;
+        handlers.dispatch(req)?
     };
-
-    let response = response?;
+    let buffered = buffer.buffer().to_vec();
 
     // write response into TcpStream
-    stream.write_all(&Vec::<u8>::from(response))?;
+    let (status, headers, body) = response.into_parts();
+    stream.write_all(&response::http1_head(status, &headers, &body))?;
+    let mut upgrade = None;
+    let written = match body {
+        Body::Full(body) => {
+            stream.write_all(&body)?;
+            Ok(())
+        }
+        Body::Stream { chunks, trailers } => response::write_chunked(stream, chunks, trailers),
+        Body::Seekable {
+            reader,
+            offset,
+            len,
+        } => response::write_sized(stream, response::seekable_chunks(reader, offset, len)),
+        Body::File { file, offset, len } => stream.send_file(file, offset, len),
+        Body::Takeover(takeover) => {
+            upgrade = Some((takeover, buffered));
+            Ok(())
+        }
+    };
+    match written {
+        Ok(()) => {}
+        // the head is out, so the client can only learn about it from the
+        // body ending early
+        Err(BodyError::Source(e)) => error!("Error producing response body: {e:?}"),
+        Err(BodyError::Write(e)) => return Err(e.into()),
+    }
+    stream.flush()?;
 
-    Ok(())
+    Ok(upgrade)
 }
 
-fn read_and_parse_request(stream: &mut impl Read) -> Result<request::Request> {
-    // create buffer
-    let mut buffer = BufReader::new(stream);
+/// For servers that can only answer requests: refuse responses that want
+/// to take over the connection
+pub(crate) fn without_upgrade(response: Response) -> Result<Response> {
+    if response.takes_over() {
+        anyhow::bail!("Connection upgrades are only supported by the threaded HTTP/1.1 server");
+    }
+    Ok(response)
+}
 
+/// Read the request line and headers, leaving the body to be read. A head
+/// that doesn't end within `MAX_HEAD_SIZE` bytes is refused.
+fn read_head(buffer: &mut impl BufRead) -> Result<(request::Request, Framing)> {
+    let mut buffer = buffer.take(MAX_HEAD_SIZE as u64);
     // Read the HTTP request headers until end of header
     let lines = {
         let mut lines: Vec<String> = vec![];
         loop {
             let mut next_line = String::new();
-            buffer.read_line(&mut next_line)?;
+            match buffer.read_line(&mut next_line) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    return Err(ClientError::invalid_utf8("head"));
+                }
+                Err(e) => return Err(e.into()),
+            }
+            if buffer.limit() == 0 && !next_line.ends_with('\n') {
+                return Err(ClientError::head_too_large());
+            }
             if next_line.is_empty() || next_line == "\r" || next_line == "\r\n" {
                 break lines;
             }
@@ -145,16 +821,168 @@ fn read_and_parse_request(stream: &mut impl Read) -> Result<request::Request> {
         }
     };
 
-    let (req, _content_length) = parse_request(&lines)?;
+    parse_request(&lines)
+}
+
+/// Read the whole body into `req`, based on how it is framed
+fn read_body(
+    reader: &mut (impl BufRead + Send),
+    req: &mut Request,
+    framing: Framing,
+    max_body_size: usize,
+) -> Result<()> {
+    match framing {
+        Framing::Length(0) => {}
+        Framing::Length(content_length) => {
+            check_body_size(content_length, max_body_size)?;
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body)?;
+            req.add_body(String::from_utf8(body).map_err(|_| ClientError::invalid_utf8("body"))?);
+        }
+        Framing::Chunked => {
+            let mut body = BodyReader::new(reader, framing);
+            let mut bytes = Vec::new();
+            // one byte past the limit tells a body that is too large apart
+            (&mut body)
+                .take(max_body_size as u64 + 1)
+                .read_to_end(&mut bytes)
+                .map_err(framing_error)?;
+            check_body_size(bytes.len(), max_body_size)?;
+            for (name, value) in body.into_trailers() {
+                req.add_trailer(name, value);
+            }
+            req.add_body(String::from_utf8(bytes).map_err(|_| ClientError::invalid_utf8("body"))?);
+        }
+    }
+    Ok(())
+}
 
-    // Parse the request body based on Content-Length
-    // let mut body_buffer = vec![];
-    // buffer.read_to_end(&mut body_buffer)?;
+/// Largest request head buffered while waiting for the blank line that ends it
+const MAX_HEAD_SIZE: usize = 64 * 1024;
 
-    Ok(req)
+/// The bytes of a request read so far, for callers that can't block on the
+/// stream. The head is parsed once, as soon as the blank line ending it has
+/// arrived, and only newly read bytes are searched for that line. A chunked
+/// body is decoded as it arrives, and its framing dropped from the buffer.
+pub(crate) struct RequestBuffer {
+    buffer: Vec<u8>,
+    max_body_size: usize,
+    /// How far `buffer` has been searched for a blank line
+    searched: usize,
+    /// The parsed head, how its body is framed and where the body starts
+    head: Option<(request::Request, Framing, usize)>,
+    chunks: Option<ChunkedDecoder>,
+    /// How many bytes of chunked body were decoded and dropped from `buffer`
+    decoded: usize,
 }
 
-fn parse_request<IT, S>(lines: IT) -> Result<(request::Request, usize)>
+impl RequestBuffer {
+    pub(crate) fn new(max_body_size: usize) -> RequestBuffer {
+        RequestBuffer {
+            buffer: Vec::new(),
+            max_body_size,
+            searched: 0,
+            head: None,
+            chunks: None,
+            decoded: 0,
+        }
+    }
+
+    pub(crate) fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Returns `None` until the headers and the whole body have arrived,
+    /// otherwise the request and the number of bytes it used. A head that
+    /// doesn't end within `MAX_HEAD_SIZE` bytes is refused.
+    pub(crate) fn parse(&mut self) -> Result<Option<(request::Request, usize)>> {
+        if self.head.is_none() {
+            let header_end = match self.find_blank_line() {
+                Some(header_end) if header_end <= MAX_HEAD_SIZE => header_end,
+                None if self.buffer.len() <= MAX_HEAD_SIZE => return Ok(None),
+                _ => return Err(ClientError::head_too_large()),
+            };
+            let head = std::str::from_utf8(&self.buffer[..header_end])
+                .map_err(|_| ClientError::invalid_utf8("head"))?;
+            let (req, framing) = parse_request(head.split("\r\n"))?;
+            if let Framing::Length(content_length) = framing {
+                check_body_size(content_length, self.max_body_size)?;
+            }
+            self.head = Some((req, framing, header_end + 4));
+        }
+        let Some((req, framing, body_start)) = &mut self.head else {
+            unreachable!("the head was parsed above");
+        };
+        let body_start = *body_start;
+
+        let body_end = match *framing {
+            Framing::Length(content_length) => {
+                let body_end = body_start
+                    .checked_add(content_length)
+                    .ok_or_else(|| ClientError::too_large(self.max_body_size))?;
+                if self.buffer.len() < body_end {
+                    return Ok(None);
+                }
+                if content_length > 0 {
+                    let body = self.buffer[body_start..body_end].to_vec();
+                    req.add_body(
+                        String::from_utf8(body).map_err(|_| ClientError::invalid_utf8("body"))?,
+                    );
+                }
+                body_end
+            }
+            Framing::Chunked => {
+                let chunks = self.chunks.get_or_insert_with(ChunkedDecoder::new);
+                let used = chunks
+                    .decode(&self.buffer[body_start..])
+                    .map_err(framing_error)?;
+                self.buffer.drain(body_start..body_start + used);
+                self.decoded += used;
+                check_body_size(chunks.body_len(), self.max_body_size)?;
+                if !chunks.is_done() {
+                    return Ok(None);
+                }
+                let (body, trailers) = self.chunks.take().expect("decoded above").into_parts();
+                for (name, value) in trailers {
+                    req.add_trailer(name, value);
+                }
+                req.add_body(
+                    String::from_utf8(body).map_err(|_| ClientError::invalid_utf8("body"))?,
+                );
+                body_start + self.decoded
+            }
+        };
+        let (req, _, _) = self.head.take().expect("the head was parsed above");
+        Ok(Some((req, body_end)))
+    }
+
+    /// Start of the first `\r\n\r\n`, searching only what wasn't searched
+    /// before
+    fn find_blank_line(&mut self) -> Option<usize> {
+        // a match may straddle the bytes searched last time
+        let from = self.searched.saturating_sub(3);
+        let found = self.buffer[from..]
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|i| from + i);
+        self.searched = match found {
+            Some(start) => start + 4,
+            None => self.buffer.len(),
+        };
+        found
+    }
+}
+
+/// An error reading a chunked body, answered with 400 when the client
+/// framed its chunks or trailers wrong
+fn framing_error(e: io::Error) -> anyhow::Error {
+    match e.kind() {
+        io::ErrorKind::InvalidData => ClientError::bad_request(e.to_string()),
+        _ => e.into(),
+    }
+}
+
+fn parse_request<IT, S>(lines: IT) -> Result<(request::Request, Framing)>
 where
     IT: IntoIterator<Item = S>,
     S: AsRef<str>,
@@ -165,26 +993,80 @@ where
     let first_line = lines
         .next()
         .ok_or_else(|| anyhow::anyhow!("No request line found"))?;
-    let req = request::Request::parse(first_line)?;
-
-    let content_length = match req {
-        Request::GET(_) => 0,
-        Request::POST(_, _) => {
-            lines
-                // .lines()
-                .find(|line| line.as_ref().starts_with("Content-Length:"))
-                .and_then(|line| {
-                    line.as_ref()
-                        .trim()
-                        .split(':')
-                        .nth(1)
-                        .and_then(|value| value.trim().parse::<usize>().ok())
-                })
-                .unwrap_or(0)
+    let mut req = request::Request::parse(first_line)?;
+
+    for line in lines {
+        let line = line.as_ref().trim_end();
+        if line.is_empty() {
+            continue;
         }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Invalid header line: {line}"))?;
+        req.add_header(name.trim(), value.trim());
+    }
+
+    let framing = framing(&req)?;
+    Ok((req, framing))
+}
+
+/// Find how the body is framed, refusing anything two parties could read
+/// differently (RFC 9112, section 6.3): a request with both
+/// `Transfer-Encoding` and `Content-Length` is how requests get smuggled
+/// past a proxy, so it is rejected rather than one of them ignored.
+fn framing(req: &Request) -> Result<Framing> {
+    let values = |name: &str| -> Vec<String> {
+        req.headers()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .flat_map(|(_, value)| value.split(','))
+            .map(|value| value.trim().to_ascii_lowercase())
+            .collect()
     };
+    let transfer_encoding = values("Transfer-Encoding");
+    let content_length = values("Content-Length");
 
-    Ok((req, content_length))
+    if !transfer_encoding.is_empty() {
+        if !content_length.is_empty() {
+            return Err(ClientError::bad_request(
+                "Request has both Transfer-Encoding and Content-Length",
+            ));
+        }
+        // chunked has to be the one and only coding for the body to end
+        if transfer_encoding != ["chunked"] {
+            return Err(ClientError::bad_request(format!(
+                "Unsupported Transfer-Encoding: {}",
+                transfer_encoding.join(", ")
+            )));
+        }
+        return Ok(Framing::Chunked);
+    }
+
+    let mut lengths = content_length.iter().map(|value| {
+        value
+            .bytes()
+            .all(|b| b.is_ascii_digit())
+            .then(|| value.parse::<usize>().ok())
+            .flatten()
+            .ok_or_else(|| ClientError::bad_request(format!("Invalid Content-Length: {value}")))
+    });
+    let Some(length) = lengths.next().transpose()? else {
+        return Ok(Framing::Length(0));
+    };
+    for other in lengths {
+        if other? != length {
+            return Err(ClientError::bad_request(
+                "Request has conflicting Content-Length headers",
+            ));
+        }
+    }
+    Ok(Framing::Length(length))
+}
+
+fn check_body_size(size: usize, max_body_size: usize) -> Result<()> {
+    if size > max_body_size {
+        return Err(ClientError::too_large(max_body_size));
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -193,6 +1075,7 @@ mod tests {
 
     use super::*;
     use crate::response::Response;
+    use crate::test_util::TempDir;
 
     #[test]
     fn test_builder_pattern() -> Result<()> {
@@ -218,6 +1101,29 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_streaming_handlers_need_the_threaded_backend() -> Result<()> {
+        let server = Server::build()
+            .backend(Backend::EventLoop)
+            .register_streaming_handler(request::Request::POST("/upload", ""), |_req, _body| {
+                Ok(Response::Ok("uploaded"))
+            })
+            .register_error_handler(handler::default_error_404_handler)?
+            .finalize(("127.0.0.1", 0), 1);
+        assert!(server.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_prefixes() {
+        assert_eq!(normalize_prefix("/"), "");
+        assert_eq!(normalize_prefix("static/"), "/static");
+        assert!(under_prefix("/static", "/static"));
+        assert!(under_prefix("/static", "/static/css/blue.css"));
+        assert!(!under_prefix("/static", "/statics/blue.css"));
+        assert!(under_prefix("", "/anything"));
+    }
+
     #[test]
     fn test_parse_request() -> Result<()> {
         let lines = &["GET / HTTP/1.1"];
@@ -226,6 +1132,185 @@ mod tests {
         Ok(())
     }
 
+    /// Parse a request out of everything read so far in one go
+    fn parse_buffered_request(
+        buffer: &[u8],
+        max_body_size: usize,
+    ) -> Result<Option<(request::Request, usize)>> {
+        let mut request = RequestBuffer::new(max_body_size);
+        request.extend(buffer);
+        request.parse()
+    }
+
+    #[test]
+    fn test_parse_buffered_request() -> Result<()> {
+        let raw = b"POST /form HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+
+        // nothing is returned until the whole body is there
+        assert!(parse_buffered_request(&raw[..20], 1024)?.is_none());
+        assert!(parse_buffered_request(&raw[..raw.len() - 1], 1024)?.is_none());
+
+        let (req, used) = parse_buffered_request(raw, 1024)?.unwrap();
+        assert_eq!(req.route_key(), Request::POST("/form", ""));
+        assert_eq!(req.body(), "hello");
+        assert_eq!(req.header("content-length"), Some("5"));
+        assert_eq!(used, raw.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_request_body() -> Result<()> {
+        let raw = b"POST /form HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello";
+        let mut reader = &raw[..];
+        let (mut req, framing) = read_head(&mut reader)?;
+        read_body(&mut reader, &mut req, framing, DEFAULT_MAX_BODY_SIZE)?;
+        assert_eq!(req.route_key(), Request::POST("/form", ""));
+        assert_eq!(req.body(), "hello");
+
+        // the body isn't allocated when it is larger than allowed
+        let raw = b"POST /form HTTP/1.1\r\nContent-Length: 999999999999\r\n\r\n";
+        let mut reader = &raw[..];
+        let (mut req, framing) = read_head(&mut reader)?;
+        assert!(read_body(&mut reader, &mut req, framing, DEFAULT_MAX_BODY_SIZE).is_err());
+
+        // nor does the end of the body overflow without a limit
+        let raw = format!(
+            "POST /form HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            usize::MAX
+        );
+        assert!(parse_buffered_request(raw.as_bytes(), usize::MAX).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_buffer_fed_a_byte_at_a_time() -> Result<()> {
+        let requests: [&[u8]; 2] = [
+            b"POST /form HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+            // the blank line inside the chunk doesn't end the body
+            b"POST /form HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5\r\nh\r\n\r\n\r\n0\r\n\r\n",
+        ];
+        for raw in requests {
+            let mut buffer = RequestBuffer::new(1024);
+            for (i, byte) in raw.iter().enumerate() {
+                buffer.extend(&[*byte]);
+                let parsed = buffer.parse()?;
+                assert_eq!(parsed.is_some(), i == raw.len() - 1);
+                if let Some((req, used)) = parsed {
+                    assert_eq!(req.route_key(), Request::POST("/form", ""));
+                    assert_eq!(used, raw.len());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_head_too_large() {
+        let mut raw = b"GET / HTTP/1.1\r\n".to_vec();
+        while raw.len() <= MAX_HEAD_SIZE {
+            raw.extend_from_slice(b"X-Filler: aaaaaaaaaaaaaaaa\r\n");
+        }
+        raw.extend_from_slice(b"\r\n");
+
+        let e = read_head(&mut &raw[..]).unwrap_err();
+        assert_eq!(error_status(&e), 431, "{e}");
+        let e = parse_buffered_request(&raw, 1024).unwrap_err();
+        assert_eq!(error_status(&e), 431, "{e}");
+    }
+
+    #[test]
+    fn test_parse_chunked_request() -> Result<()> {
+        let raw = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5;name=value\r\nhello\r\n7\r\n, world\r\n0\r\nX-Checksum: 42\r\n\r\nnext";
+
+        // nothing is returned until the trailers have ended
+        let end = raw.len() - "next".len();
+        for partial in [40, 50, end - 3, end - 1] {
+            assert!(parse_buffered_request(&raw[..partial], 1024)?.is_none());
+        }
+
+        let (req, used) = parse_buffered_request(raw, 1024)?.unwrap();
+        assert_eq!(req.body(), "hello, world");
+        assert_eq!(req.trailer("x-checksum"), Some("42"));
+        assert_eq!(used, end);
+
+        // the blocking reader leaves what follows the body alone
+        let mut reader = &raw[..];
+        let (mut req, framing) = read_head(&mut reader)?;
+        read_body(&mut reader, &mut req, framing, 1024)?;
+        assert_eq!(req.body(), "hello, world");
+        assert_eq!(reader, b"next");
+
+        assert!(parse_buffered_request(raw, 11).is_err());
+        let mut reader = &raw[..];
+        let (mut req, framing) = read_head(&mut reader)?;
+        assert!(read_body(&mut reader, &mut req, framing, 11).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_request_rejects_ambiguous_framing() {
+        let parse = |headers: &[&str]| {
+            let mut lines = vec!["POST / HTTP/1.1"];
+            lines.extend(headers);
+            parse_request(lines).map(|(_, framing)| framing)
+        };
+
+        assert_eq!(parse(&["Content-Length: 5"]).unwrap(), Framing::Length(5));
+        assert_eq!(
+            parse(&["Content-Length: 5", "Content-Length: 5"]).unwrap(),
+            Framing::Length(5)
+        );
+        assert_eq!(
+            parse(&["Transfer-Encoding: Chunked"]).unwrap(),
+            Framing::Chunked
+        );
+
+        for headers in [
+            &["Transfer-Encoding: chunked", "Content-Length: 5"][..],
+            &["Content-Length: 5", "Content-Length: 6"],
+            &["Content-Length: +5"],
+            &["Content-Length: five"],
+            &["Transfer-Encoding: gzip"],
+            &["Transfer-Encoding: gzip, chunked"],
+            &["Transfer-Encoding: chunked", "Transfer-Encoding: chunked"],
+        ] {
+            assert!(parse(headers).is_err(), "{headers:?}");
+        }
+    }
+
+    #[test]
+    fn test_invalid_chunks() {
+        for body in [
+            "x\r\nhello\r\n0\r\n\r\n",
+            "5\r\nhello, world\r\n0\r\n\r\n",
+            "0\r\nnot a trailer\r\n\r\n",
+        ] {
+            let raw = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{body}");
+            let e = parse_buffered_request(raw.as_bytes(), 1024).unwrap_err();
+            assert_eq!(error_status(&e), 400, "{body:?}");
+        }
+    }
+
+    #[test]
+    fn test_invalid_utf8_is_a_client_error() {
+        for raw in [
+            &b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\n\xff\xfe"[..],
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n\xff\xfe\r\n0\r\n\r\n",
+            b"GET /\xff HTTP/1.1\r\n\r\n",
+        ] {
+            let e = parse_buffered_request(raw, 1024).unwrap_err();
+            assert_eq!(error_status(&e), 400, "{e}");
+
+            let mut reader = raw;
+            let e = read_head(&mut reader)
+                .and_then(|(mut req, framing)| read_body(&mut reader, &mut req, framing, 1024))
+                .unwrap_err();
+            assert_eq!(error_status(&e), 400, "{e}");
+        }
+    }
+
     #[test]
     fn test_parse_request_with_no_lines() -> Result<()> {
         // this is silly, we wouldn't use hash set but wanted to demonstrate
@@ -240,4 +1325,103 @@ mod tests {
             .contains("No request line found"));
         Ok(())
     }
+
+    /// A connection that reads a canned request and keeps what is written
+    struct Canned {
+        request: io::Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl Read for Canned {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.request.read(buf)
+        }
+    }
+
+    impl Write for Canned {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Canned {
+        fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_file_body() -> Result<()> {
+        let dir = TempDir::new("file")?;
+        let path = dir.join("file.bin");
+        let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &contents)?;
+
+        let mut handlers = HandlerMap::new();
+        let file = path.clone();
+        handlers.insert(
+            Request::GET("/file"),
+            Box::new(move |_req| Ok(Response::file(&file)?)) as handler::Handler,
+        );
+        let handlers = Handlers::new(handlers, Some(Box::new(handler::default_error_404_handler)))?;
+
+        // copied through user space on connections the kernel can't send to
+        let mut canned = Canned {
+            request: io::Cursor::new(b"GET /file HTTP/1.1\r\n\r\n".to_vec()),
+            written: Vec::new(),
+        };
+        handle_connection(&handlers, &mut canned)?;
+        let head_len = canned.written.len() - contents.len();
+        let head = String::from_utf8_lossy(&canned.written[..head_len]);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(head.contains("Content-Length: 200000\r\n"), "{head}");
+        assert!(canned.written[head_len..] == contents[..]);
+
+        // and sent by the kernel over TCP, from wherever the range starts
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let mut client = TcpStream::connect(listener.local_addr()?)?;
+        client.write_all(b"GET /file HTTP/1.1\r\nRange: bytes=1000-\r\n\r\n")?;
+        let (mut stream, _) = listener.accept()?;
+        let mut received = Vec::new();
+        thread::scope(|scope| {
+            let handlers = &handlers;
+            // the client reads until the thread is done and drops the stream
+            let server = scope.spawn(move || handle_connection(handlers, &mut stream).map(|_| ()));
+            client.read_to_end(&mut received)?;
+            server.join().unwrap()
+        })?;
+        let head_len = received.len() - (contents.len() - 1000);
+        let head = String::from_utf8_lossy(&received[..head_len]);
+        assert!(
+            head.starts_with("HTTP/1.1 206 Partial Content\r\n"),
+            "{head}"
+        );
+        assert!(head.contains("Content-Length: 199000\r\n"), "{head}");
+        assert!(received[head_len..] == contents[1000..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_continue_for_a_body_too_large() -> Result<()> {
+        let handlers = Handlers::new(
+            HandlerMap::new(),
+            Some(Box::new(handler::default_error_404_handler) as handler::Handler),
+        )?;
+        let mut canned = Canned {
+            request: io::Cursor::new(
+                b"POST /upload HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 999999999999\r\n\r\n"
+                    .to_vec(),
+            ),
+            written: Vec::new(),
+        };
+        assert!(serve_connection(&handlers, &mut canned).is_none());
+        let written = String::from_utf8_lossy(&canned.written);
+        assert!(written.starts_with("HTTP/1.1 413"), "{written}");
+        Ok(())
+    }
 }
@@ -0,0 +1,243 @@
+use crate::mime;
+use crate::request::Request;
+use crate::response::{Response, Upgraded};
+use anyhow::Result;
+use std::io::Write;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+
+/// How often a comment is sent on an idle stream unless changed with
+/// `EventStream::heartbeat`
+pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// One event of a `text/event-stream`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Event {
+    event: Option<String>,
+    data: String,
+    id: Option<String>,
+}
+
+impl Event {
+    /// An unnamed event; clients receive it as a `message` event
+    pub fn new(data: impl Into<String>) -> Event {
+        Event {
+            data: data.into(),
+            ..Event::default()
+        }
+    }
+
+    /// Name the event so clients can listen for it specifically
+    pub fn with_event(mut self, event: impl Into<String>) -> Event {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// The id a reconnecting client sends back as `Last-Event-ID`
+    pub fn with_id(mut self, id: impl Into<String>) -> Event {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// The event as it goes on the wire. Multi-line data is split over
+    /// several `data:` fields at every line ending the client recognizes,
+    /// i.e. `\r\n`, `\r` and `\n`; line breaks in names and ids are
+    /// dropped since they would end the field early.
+    fn encode(&self) -> String {
+        let single_line = |value: &str| value.replace(['\r', '\n'], "");
+        let mut encoded = String::new();
+        if let Some(event) = &self.event {
+            encoded.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(id) = &self.id {
+            encoded.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            encoded.push_str(&format!("data: {line}\n"));
+        }
+        encoded.push('\n');
+        encoded
+    }
+}
+
+/// Pushes events to one client. Clones push to the same stream, so it can
+/// be handed to any number of background producers.
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    events: mpsc::Sender<Event>,
+    last_event_id: Option<Arc<str>>,
+}
+
+impl EventSender {
+    /// Queue an event for the client. Fails once the client disconnected.
+    pub fn send(&self, event: Event) -> Result<()> {
+        self.events
+            .send(event)
+            .map_err(|_| anyhow::anyhow!("Event stream closed"))
+    }
+
+    /// The id of the last event a reconnecting client received, to resume
+    /// the stream after it
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+}
+
+/// A `text/event-stream` response that stays open after the handler
+/// returns. Events pushed through its `EventSender` are written as they
+/// come, on a thread of its own, until every sender is dropped or the
+/// client goes away.
+///
+/// ```no_run
+/// # use crag_web::{request::Request, response::Response, sse::{Event, EventStream}};
+/// fn ticks(req: Request) -> anyhow::Result<Response> {
+///     let (events, response) = EventStream::new(&req).open();
+///     std::thread::spawn(move || {
+///         let start = events.last_event_id().and_then(|id| id.parse().ok()).unwrap_or(0);
+///         for tick in start + 1.. {
+///             let event = Event::new(format!("tick {tick}")).with_id(tick.to_string());
+///             if events.send(event).is_err() {
+///                 break;
+///             }
+///             std::thread::sleep(std::time::Duration::from_secs(1));
+///         }
+///     });
+///     Ok(response)
+/// }
+/// ```
+///
+/// Only the threaded backend can serve event streams.
+#[derive(Debug, Clone)]
+pub struct EventStream {
+    last_event_id: Option<String>,
+    heartbeat: Option<Duration>,
+    retry: Option<Duration>,
+}
+
+impl EventStream {
+    /// An event stream answering `req`, resuming after its `Last-Event-ID`
+    pub fn new(req: &Request) -> EventStream {
+        EventStream {
+            last_event_id: req.header("Last-Event-ID").map(str::to_string),
+            heartbeat: Some(DEFAULT_HEARTBEAT),
+            retry: None,
+        }
+    }
+
+    /// The id of the last event the client received before reconnecting
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
+    /// Send a comment after `interval` without events, so proxies keep the
+    /// connection open and dead clients are noticed. `None` turns it off.
+    pub fn heartbeat(mut self, interval: Option<Duration>) -> EventStream {
+        self.heartbeat = interval;
+        self
+    }
+
+    /// Tell the client how long to wait before reconnecting
+    pub fn retry(mut self, delay: Duration) -> EventStream {
+        self.retry = Some(delay);
+        self
+    }
+
+    /// The sender to push events with, and the response to return from
+    /// the handler. Events sent before the response went out are queued.
+    pub fn open(self) -> (EventSender, Response) {
+        let (events, queue) = mpsc::channel();
+        let sender = EventSender {
+            events,
+            last_event_id: self.last_event_id.map(Arc::from),
+        };
+
+        let heartbeat = self.heartbeat;
+        let retry = self.retry;
+        let response = Response::new(200)
+            .with_header("Content-Type", mime::TEXT_EVENT_STREAM)
+            .with_header("Cache-Control", "no-cache")
+            .with_takeover(move |mut stream| {
+                if let Err(e) = write_events(&mut stream, &queue, heartbeat, retry) {
+                    debug!("Event stream ended: {e:?}");
+                }
+            });
+        (sender, response)
+    }
+}
+
+/// Write queued events until every sender is gone or a write fails
+fn write_events(
+    stream: &mut Upgraded,
+    queue: &mpsc::Receiver<Event>,
+    heartbeat: Option<Duration>,
+    retry: Option<Duration>,
+) -> Result<()> {
+    if let Some(retry) = retry {
+        stream.write_all(format!("retry: {}\n\n", retry.as_millis()).as_bytes())?;
+        stream.flush()?;
+    }
+
+    loop {
+        let next = match heartbeat {
+            Some(interval) => queue.recv_timeout(interval),
+            None => queue.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let chunk = match next {
+            Ok(event) => event.encode(),
+            Err(RecvTimeoutError::Timeout) => ":\n\n".to_string(),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        stream.write_all(chunk.as_bytes())?;
+        stream.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_encoding() {
+        assert_eq!(Event::new("hello").encode(), "data: hello\n\n");
+
+        let event = Event::new("line one\nline two")
+            .with_event("update")
+            .with_id("7");
+        assert_eq!(
+            event.encode(),
+            "event: update\nid: 7\ndata: line one\ndata: line two\n\n"
+        );
+
+        // a newline can't end the id field early
+        let event = Event::new("x").with_id("1\ndata: injected");
+        assert_eq!(event.encode(), "id: 1data: injected\ndata: x\n\n");
+
+        // a bare carriage return ends a line too, so it can't smuggle a field
+        assert_eq!(
+            Event::new("x\rid: evil").encode(),
+            "data: x\ndata: id: evil\n\n"
+        );
+        assert_eq!(
+            Event::new("a\r\nb\n\rc").encode(),
+            "data: a\ndata: b\ndata: \ndata: c\n\n"
+        );
+    }
+
+    #[test]
+    fn test_last_event_id() {
+        let mut req = Request::GET("/events");
+        req.add_header("Last-Event-ID", "41");
+        let stream = EventStream::new(&req);
+        assert_eq!(stream.last_event_id(), Some("41"));
+
+        let (events, response) = stream.open();
+        assert_eq!(events.last_event_id(), Some("41"));
+        assert_eq!(
+            response.header("content-type"),
+            Some(mime::TEXT_EVENT_STREAM)
+        );
+        assert!(response.takes_over());
+    }
+}
@@ -0,0 +1,502 @@
+use crate::compression::{self, Encoding};
+use crate::conditional;
+use crate::embed::{EmbeddedDir, EmbeddedFile};
+use crate::listing::{self, Listed};
+use crate::mime::{self, MimeTypes};
+use crate::request::Request;
+use crate::response::Response;
+use anyhow::Result;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+
+/// Files up to this size are read into memory, larger ones are sent
+/// straight from the file
+const BUFFERED_FILE_SIZE: u64 = 64 * 1024;
+
+/// Encodings a file may be stored in next to the original, looked for
+/// under the original's name with their extension added
+const PRECOMPRESSED: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+/// Serves the files under a directory, mounted under a path prefix with
+/// `ServerBuilder::mount`:
+///
+/// ```no_run
+/// # use crag_web::{handler, server::Server, static_files::ServeDir};
+/// let server = Server::build()
+///     // GET /css/blue.css is answered with ./static/css/blue.css
+///     .mount("/css", ServeDir::new("./static/css"))
+///     .mount("/", ServeDir::new("./static/html").index_file("index.html"))
+///     .register_error_handler(handler::default_error_404_handler)?
+///     .finalize(("127.0.0.1", 8080), 4)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+///
+/// Files come with a weak `ETag` and `Last-Modified`, so clients that
+/// have them already get a `304 Not Modified`, and can be fetched in
+/// parts with `Range` to resume a download or seek in a video.
+///
+/// A file compressed ahead of time, e.g. `app.js.br` or `app.js.gz` next
+/// to `app.js`, is sent in place of the original to clients that accept
+/// its encoding.
+///
+/// Paths that don't name a file under the root, including ones that try
+/// to climb out of it with `..` or through a symlink, are left to the
+/// error handler.
+#[derive(Debug, Clone)]
+pub struct ServeDir {
+    source: Source,
+    index_file: Option<String>,
+    list_directories: bool,
+    fallback_file: Option<String>,
+    mime_types: MimeTypes,
+}
+
+/// Where the files are
+#[derive(Debug, Clone)]
+enum Source {
+    Root(PathBuf),
+    Embedded(&'static EmbeddedDir),
+}
+
+/// What a request path names
+enum Entry {
+    File(PathBuf),
+    Embedded(&'static EmbeddedFile),
+    /// A directory, by its path relative to the root
+    Directory(PathBuf),
+}
+
+impl ServeDir {
+    /// Serve the files under `root`
+    pub fn new(root: impl Into<PathBuf>) -> ServeDir {
+        ServeDir::with_source(Source::Root(root.into()))
+    }
+
+    /// Serve files compiled into the binary with `embed::Embed`. Their
+    /// `Content-Type`s were looked up when they were embedded, so
+    /// `mime_types` doesn't apply to them. They come with a strong `ETag`
+    /// of their contents, and their precompressed versions if they were
+    /// embedded along with them.
+    pub fn embedded(dir: &'static EmbeddedDir) -> ServeDir {
+        ServeDir::with_source(Source::Embedded(dir))
+    }
+
+    fn with_source(source: Source) -> ServeDir {
+        ServeDir {
+            source,
+            index_file: None,
+            list_directories: false,
+            fallback_file: None,
+            mime_types: MimeTypes::new(),
+        }
+    }
+
+    /// Answer requests for a directory with the file of that name in it,
+    /// e.g. `index.html`
+    pub fn index_file(mut self, name: impl Into<String>) -> ServeDir {
+        self.index_file = Some(name.into());
+        self
+    }
+
+    /// Answer requests for a directory without an index file with a page
+    /// listing what is in it: name, size and modification time, sortable
+    /// by each. Clients get the listing as JSON with `?format=json` or by
+    /// only accepting `application/json`. Hidden files, those starting
+    /// with a dot, are left out.
+    pub fn list_directories(mut self, list_directories: bool) -> ServeDir {
+        self.list_directories = list_directories;
+        self
+    }
+
+    /// Answer paths that name nothing with the file at `path`, relative
+    /// to the root, for apps routed on the client: `/users/42` gets
+    /// `index.html` with `200 OK`, and the app shows the user. Paths whose
+    /// last segment has an extension, like `/assets/app.js`, are taken to
+    /// be missing assets and still left to the error handler.
+    ///
+    /// ```no_run
+    /// # use crag_web::{handler, server::Server, static_files::ServeDir};
+    /// let server = Server::build()
+    ///     .mount(
+    ///         "/app",
+    ///         ServeDir::new("./dist")
+    ///             .index_file("index.html")
+    ///             .fallback_file("index.html"),
+    ///     )
+    ///     .register_error_handler(handler::default_error_404_handler)?
+    ///     .finalize(("127.0.0.1", 8080), 4)?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn fallback_file(mut self, path: impl Into<String>) -> ServeDir {
+        self.fallback_file = Some(path.into());
+        self
+    }
+
+    /// Look up `Content-Type`s in `mime_types` instead of the built in
+    /// table
+    pub fn mime_types(mut self, mime_types: MimeTypes) -> ServeDir {
+        self.mime_types = mime_types;
+        self
+    }
+
+    /// The response for `path`, the part of the request path below where
+    /// the directory is mounted, or `None` if there is nothing to serve
+    pub fn serve(&self, req: &Request, path: &str) -> Result<Option<Response>> {
+        let Some(relative) = relative_path(path) else {
+            return Ok(None);
+        };
+        if let Some(response) = self.serve_relative(req, path, &relative)? {
+            return Ok(Some(response));
+        }
+
+        let Some(fallback_file) = &self.fallback_file else {
+            return Ok(None);
+        };
+        if relative.extension().is_some() {
+            return Ok(None);
+        }
+        let Some(fallback) = relative_path(fallback_file) else {
+            anyhow::bail!("Invalid fallback file {fallback_file}");
+        };
+        match self.lookup(&fallback)? {
+            None | Some(Entry::Directory(_)) => Ok(None),
+            Some(file) => self.respond(req, file),
+        }
+    }
+
+    /// The response for the `relative` path `path` names
+    fn serve_relative(
+        &self,
+        req: &Request,
+        path: &str,
+        relative: &Path,
+    ) -> Result<Option<Response>> {
+        let directory = match self.lookup(relative)? {
+            None => return Ok(None),
+            Some(Entry::Directory(directory)) => directory,
+            Some(file) => return self.respond(req, file),
+        };
+
+        if self.index_file.is_none() && !self.list_directories {
+            return Ok(None);
+        }
+        // relative links in the index resolve against the directory only
+        // when its path ends with a slash
+        if !path.ends_with('/') {
+            let mut location = format!("{}/", req.path());
+            if let Some(query) = req.query() {
+                location = format!("{location}?{query}");
+            }
+            return Ok(Some(Response::new(301).with_header("Location", location)));
+        }
+        if let Some(index_file) = &self.index_file {
+            match self.lookup(&directory.join(index_file))? {
+                None | Some(Entry::Directory(_)) => {}
+                Some(index) => return self.respond(req, index),
+            }
+        }
+        if !self.list_directories {
+            return Ok(None);
+        }
+        let is_root = directory.as_os_str().is_empty();
+        Ok(Some(listing::response(
+            req,
+            self.list(&directory)?,
+            is_root,
+        )))
+    }
+
+    /// What is in the `relative` directory, but hidden files
+    fn list(&self, relative: &Path) -> Result<Vec<Listed>> {
+        let root = match &self.source {
+            Source::Root(root) => root.canonicalize()?,
+            Source::Embedded(dir) => {
+                let prefix = match embedded_path(relative) {
+                    Some(path) if path.is_empty() => path,
+                    Some(path) => format!("{path}/"),
+                    None => return Ok(Vec::new()),
+                };
+                let mut listed: Vec<Listed> = Vec::new();
+                for file in dir.files {
+                    let Some(rest) = file.path.strip_prefix(&prefix) else {
+                        continue;
+                    };
+                    let entry = match rest.split_once('/') {
+                        Some((subdirectory, _)) => Listed {
+                            name: subdirectory.to_string(),
+                            is_dir: true,
+                            size: None,
+                            modified: None,
+                        },
+                        None => Listed {
+                            name: rest.to_string(),
+                            is_dir: false,
+                            size: Some(file.contents.len() as u64),
+                            modified: None,
+                        },
+                    };
+                    // the files of a subdirectory come one after the other
+                    if !entry.name.starts_with('.') && listed.last() != Some(&entry) {
+                        listed.push(entry);
+                    }
+                }
+                return Ok(listed);
+            }
+        };
+
+        let mut listed = Vec::new();
+        for entry in fs::read_dir(root.join(relative))? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                // can't be asked for in a request path
+                continue;
+            };
+            if name.starts_with('.') {
+                continue;
+            }
+            // leave out symlinks leading out of the root, as they aren't
+            // served either
+            let Ok(path) = entry.path().canonicalize() else {
+                continue;
+            };
+            if !path.starts_with(&root) {
+                continue;
+            }
+            let metadata = fs::metadata(&path)?;
+            listed.push(Listed {
+                name,
+                is_dir: metadata.is_dir(),
+                size: (!metadata.is_dir()).then_some(metadata.len()),
+                modified: metadata.modified().ok(),
+            });
+        }
+        Ok(listed)
+    }
+
+    /// What the `relative` path names, if it exists and doesn't lead out
+    /// of the root
+    fn lookup(&self, relative: &Path) -> Result<Option<Entry>> {
+        let root = match &self.source {
+            Source::Root(root) => root.canonicalize()?,
+            Source::Embedded(dir) => {
+                let Some(path) = embedded_path(relative) else {
+                    return Ok(None);
+                };
+                if let Some(file) = dir.get(&path) {
+                    return Ok(Some(Entry::Embedded(file)));
+                }
+                return Ok(dir
+                    .is_dir(&path)
+                    .then(|| Entry::Directory(relative.to_path_buf())));
+            }
+        };
+        let file = match root.join(relative).canonicalize() {
+            Ok(file) => file,
+            // e.g. a path that continues past a file, or a name the file
+            // system can't hold, names nothing either
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound
+                        | io::ErrorKind::NotADirectory
+                        | io::ErrorKind::InvalidFilename
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // a symlink may still point outside of the root
+        if !file.starts_with(&root) {
+            return Ok(None);
+        }
+        if file.is_dir() {
+            return Ok(Some(Entry::Directory(relative.to_path_buf())));
+        }
+        Ok(Some(Entry::File(file)))
+    }
+
+    fn respond(&self, req: &Request, entry: Entry) -> Result<Option<Response>> {
+        let response = match entry {
+            Entry::File(path) => self.file_response(req, &path)?,
+            Entry::Embedded(file) => embedded_response(req, file)?,
+            Entry::Directory(_) => return Ok(None),
+        };
+        Ok(Some(response))
+    }
+
+    /// The precompressed versions of the file at `path` next to it
+    fn precompressed(&self, path: &Path) -> Result<Vec<(Encoding, PathBuf)>> {
+        let Source::Root(root) = &self.source else {
+            return Ok(Vec::new());
+        };
+        let root = root.canonicalize()?;
+        let precompressed = PRECOMPRESSED.into_iter().filter_map(|encoding| {
+            let mut name = path.as_os_str().to_owned();
+            name.push(".");
+            name.push(encoding.extension()?);
+            let compressed = PathBuf::from(name).canonicalize().ok()?;
+            (compressed.starts_with(&root) && compressed.is_file())
+                .then_some((encoding, compressed))
+        });
+        Ok(precompressed.collect())
+    }
+
+    /// `200 OK` with the file as body, or its precompressed version the
+    /// client prefers. Its `ETag` comes from the size and modification
+    /// time, so revalidating it never hashes the file, but one of up to
+    /// `BUFFERED_FILE_SIZE` bytes is still read before the preconditions
+    /// are checked; larger ones are only read once they are sent.
+    fn file_response(&self, req: &Request, path: &Path) -> Result<Response> {
+        let mut file = File::open(path)?;
+        let mut head = Vec::new();
+        if self.mime_types.needs_contents(path) {
+            (&mut file)
+                .take(mime::SNIFF_LEN as u64)
+                .read_to_end(&mut head)?;
+            file.rewind()?;
+        }
+        let mut response = Response::new(200)
+            .with_header("Content-Type", self.mime_types.content_type(path, &head));
+
+        let precompressed = self.precompressed(path)?;
+        if !precompressed.is_empty() {
+            response = response.with_header("Vary", "Accept-Encoding");
+        }
+        if let Some((encoding, compressed)) = preferred(req, precompressed) {
+            file = File::open(compressed)?;
+            response = response.with_header("Content-Encoding", encoding.name());
+        }
+
+        let metadata = file.metadata()?;
+        if let Ok(modified) = metadata.modified() {
+            response = response
+                .with_header("ETag", conditional::weak_etag(metadata.len(), modified))
+                .with_header("Last-Modified", conditional::http_date(modified));
+        }
+
+        if metadata.len() <= BUFFERED_FILE_SIZE {
+            let mut body = Vec::new();
+            file.read_to_end(&mut body)?;
+            Ok(response.with_body(body))
+        } else {
+            Ok(response.with_body_file(file)?)
+        }
+    }
+}
+
+/// `200 OK` with an embedded file, or its precompressed version the
+/// client prefers
+fn embedded_response(req: &Request, file: &'static EmbeddedFile) -> Result<Response> {
+    let mut response = Response::new(200).with_header("Content-Type", file.content_type);
+    let precompressed = file.precompressed();
+    if !precompressed.is_empty() {
+        response = response.with_header("Vary", "Accept-Encoding");
+    }
+    let contents = match preferred(req, precompressed) {
+        Some((encoding, compressed)) => {
+            response = response
+                .with_header("Content-Encoding", encoding.name())
+                .with_header("ETag", file.encoded_etag(encoding));
+            compressed
+        }
+        None => {
+            response = response.with_header("ETag", file.etag);
+            file.contents
+        }
+    };
+    // served straight from the binary, without copying it first
+    Ok(response.with_body_seekable(io::Cursor::new(contents))?)
+}
+
+/// The version out of `precompressed` the client prefers, if it accepts
+/// any of them
+fn preferred<T>(req: &Request, precompressed: Vec<(Encoding, T)>) -> Option<(Encoding, T)> {
+    let available: Vec<Encoding> = precompressed
+        .iter()
+        .map(|(encoding, _)| *encoding)
+        .collect();
+    let preferred = compression::negotiate(req.header("Accept-Encoding")?, &available)?;
+    precompressed
+        .into_iter()
+        .find(|(encoding, _)| *encoding == preferred)
+}
+
+/// The path of an embedded file for a relative path
+fn embedded_path(relative: &Path) -> Option<String> {
+    let segments: Option<Vec<&str>> = relative
+        .components()
+        .map(|component| component.as_os_str().to_str())
+        .collect();
+    Some(segments?.join("/"))
+}
+
+/// Turn a percent-encoded request path into a relative file path. Returns
+/// `None` for anything that could name a file outside of the directory it
+/// is joined to.
+fn relative_path(path: &str) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for segment in path.split('/') {
+        let segment = percent_decode(segment)?;
+        match segment.as_str() {
+            "" | "." => {}
+            ".." => return None,
+            // an encoded slash, or a separator or drive on Windows
+            segment if segment.contains(['/', '\\', ':', '\0']) => return None,
+            segment => relative.push(segment),
+        }
+    }
+    Some(relative)
+}
+
+/// Decode `%XX` escapes, `None` if they are malformed or not UTF-8
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(relative_path(""), Some(PathBuf::new()));
+        assert_eq!(
+            relative_path("/css/./blue.css"),
+            Some(PathBuf::from("css").join("blue.css"))
+        );
+        assert_eq!(
+            relative_path("/my%20notes.txt"),
+            Some(PathBuf::from("my notes.txt"))
+        );
+
+        for path in [
+            "/../secret",
+            "/css/../../secret",
+            "/%2e%2e/secret",
+            "/css%2f..%2f..%2fsecret",
+            "/..%5csecret",
+            "/C:/secret",
+            "/broken%2",
+            "/broken%zz",
+        ] {
+            assert_eq!(relative_path(path), None, "{path}");
+        }
+    }
+}
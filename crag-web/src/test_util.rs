@@ -0,0 +1,43 @@
+//! Fixtures shared by the unit tests
+
+use crate::request::Request;
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A GET request for `uri` with `headers`
+pub(crate) fn get(uri: &str, headers: &[(&str, &str)]) -> Request {
+    let mut req = Request::GET(uri);
+    for (name, value) in headers {
+        req.add_header(*name, *value);
+    }
+    req
+}
+
+/// A fresh directory under the system's temporary directory, removed
+/// again when dropped, whether the test passed or not
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> io::Result<TempDir> {
+        let path = std::env::temp_dir().join(format!("crag-web-{name}-{}", std::process::id()));
+        _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path)?;
+        Ok(TempDir(path))
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,12 +1,32 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::{BTreeMap, VecDeque};
 use std::error;
 use std::fmt;
-use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::task::{Context, Poll, Waker};
 use std::thread;
+use std::time::Duration;
+use tracing::{debug, error, warn};
 
 #[derive(Debug)]
 pub enum PoolCreationError {
     ZeroSize,
+    /// The OS refused to spawn a worker thread
+    SpawnFailed(io::Error),
+    /// The minimum pool size is larger than the maximum
+    MinAboveMax {
+        min: usize,
+        max: usize,
+    },
+    /// A core passed to `cpu_affinity` is past what the OS can pin to
+    CoreOutOfRange(usize),
 }
 
 impl fmt::Display for PoolCreationError {
@@ -17,85 +37,1231 @@ impl fmt::Display for PoolCreationError {
 
 impl error::Error for PoolCreationError {}
 
-#[derive(Debug)]
 pub struct ThreadPool {
-    _workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
+    shared: Arc<Shared>,
 }
 
-impl ThreadPool {
-    /// Create a new ThreadPool
-    ///
-    /// The size is the number of threads in the pool.
-    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
-        if size < 1 {
-            return Err(PoolCreationError::ZeroSize);
+/// State shared between the pool and its workers so the pool can grow,
+/// shrink and replace workers that die
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled when a job is queued, the bounds change or the pool shuts down
+    available: Condvar,
+    config: ThreadPoolBuilder,
+    restarts: AtomicUsize,
+}
+
+struct State {
+    jobs: VecDeque<Job>,
+    /// Jobs addressed to one worker in particular, see `ThreadPool::broadcast`
+    broadcasts: BTreeMap<usize, VecDeque<Job>>,
+    workers: BTreeMap<usize, Worker>,
+    /// Workers currently waiting for a job
+    idle: usize,
+    min: usize,
+    max: usize,
+    shutdown: bool,
+}
+
+impl fmt::Debug for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("State")
+            .field("jobs", &self.jobs.len())
+            .field("broadcasts", &self.broadcasts.len())
+            .field("workers", &self.workers)
+            .field("idle", &self.idle)
+            .field("min", &self.min)
+            .field("max", &self.max)
+            .field("shutdown", &self.shutdown)
+            .finish()
+    }
+}
+
+impl State {
+    /// Next job for the given worker, preferring jobs addressed to it
+    fn next_job(&mut self, id: usize) -> Option<Job> {
+        match self.broadcasts.get_mut(&id).and_then(VecDeque::pop_front) {
+            Some(job) => Some(job),
+            None => self.jobs.pop_front(),
         }
+    }
 
-        // use mpsc channel to send requests to workers
-        let (sender, receiver) = mpsc::channel();
+    /// Whether there is a job the given worker could take
+    fn has_work(&self, id: usize) -> bool {
+        !self.jobs.is_empty()
+            || self
+                .broadcasts
+                .get(&id)
+                .is_some_and(|jobs| !jobs.is_empty())
+    }
 
-        // workers will need a Mutex to share the receiver
-        // across threads so we wrap in thread safe
-        // smart pointer Arc
-        let receiver = Arc::new(Mutex::new(receiver));
+    /// Stop tracking a worker that is exiting for good. Workers only retire
+    /// without work addressed to them; if there is some anyway, dropping it
+    /// counts it off its scope.
+    fn retire(&mut self, id: usize) {
+        self.workers.remove(&id);
+        self.broadcasts.remove(&id);
+    }
 
-        let mut workers = Vec::with_capacity(size);
+    /// Lowest id not taken by a live worker
+    fn free_id(&self) -> usize {
+        (0..).find(|id| !self.workers.contains_key(id)).unwrap()
+    }
 
-        for id in 0..size {
-            // give each worker a clone of the Arc that holds the receiver
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
-        }
+    /// Spawn a worker under the given id and track it.
+    /// Must be called with the state lock held, which keeps the new worker
+    /// from looking at the state before it is registered.
+    fn spawn_worker(&mut self, id: usize, shared: &Arc<Shared>) -> Result<(), PoolCreationError> {
+        let worker = Worker::spawn(id, Arc::clone(shared))?;
+        self.workers.insert(id, worker);
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ThreadPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThreadPool")
+            .field("shared", &self.shared)
+            .finish()
+    }
+}
+
+impl ThreadPool {
+    /// Create a new ThreadPool
+    ///
+    /// The size is the number of threads in the pool.
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::builder().finalize(size)
+    }
 
-        Ok(ThreadPool {
-            _workers: workers,
-            sender,
-        })
+    /// Create a ThreadPoolBuilder to configure the worker threads
+    /// before the pool is created.
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::default()
     }
+
     /// Execute a request in the stream by being passed in the
     /// handle_connection function as a closure
+    ///
+    /// If every worker is busy and the pool is below its maximum size
+    /// a new worker is started for the job.
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
         let job: Job = Box::new(f);
-        self.sender.send(job).expect("sender error");
+        self.shared.push(job);
+    }
+
+    /// Run `f` on the pool and get a handle to its result.
+    ///
+    /// The handle can be joined from a blocking context or awaited. A panic
+    /// in `f` is caught and handed back through the handle instead of
+    /// taking down the worker.
+    pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let packet = Arc::new(Signal::new(Packet {
+            result: None,
+            waker: None,
+        }));
+        let sender = Arc::clone(&packet);
+        self.execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            let mut packet = sender.lock();
+            packet.result = Some(result);
+            if let Some(waker) = packet.waker.take() {
+                waker.wake();
+            }
+            drop(packet);
+            sender.notify();
+        });
+        JoinHandle {
+            packet,
+            pool: Arc::downgrade(&self.shared),
+        }
+    }
+
+    /// Run jobs that may borrow from the caller's stack.
+    ///
+    /// Every job spawned on the scope has finished when this returns. If a
+    /// job panicked the panic is resumed here once all jobs are done.
+    ///
+    /// When called from a worker of this pool, the worker keeps running
+    /// queued jobs while it waits, so fanning out from inside a handler
+    /// does not starve the pool.
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            shared: &self.shared,
+            latch: Arc::new(Signal::new(Latch {
+                pending: 0,
+                panic: None,
+            })),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+        // jobs may still borrow from the environment, so wait for them
+        // even when `f` itself panicked
+        let mut latch = scope
+            .latch
+            .wait_until(&self.shared, |latch| latch.pending == 0);
+        let job_panic = latch.panic.take();
+        drop(latch);
+
+        match (result, job_panic) {
+            (Err(panic), _) | (Ok(_), Some(panic)) => panic::resume_unwind(panic),
+            (Ok(result), None) => result,
+        }
+    }
+
+    /// Run `f` once on every worker, passing the worker id, and collect the
+    /// results ordered by worker id.
+    ///
+    /// A worker that dies from a panicking job before it gets to `f` is
+    /// replaced under the same id and runs it instead. If it can't be
+    /// replaced its result is missing.
+    pub fn broadcast<F, T>(&self, f: F) -> Vec<T>
+    where
+        F: Fn(usize) -> T + Sync,
+        T: Send,
+    {
+        let results = Mutex::new(BTreeMap::new());
+        let job = |id| {
+            let result = f(id);
+            results.lock().unwrap().insert(id, result);
+        };
+        self.scope(|s| s.spawn_broadcast(&job));
+
+        results.into_inner().unwrap().into_values().collect()
+    }
+
+    /// Number of workers that have been respawned after a job panicked
+    pub fn restarts(&self) -> usize {
+        self.shared.restarts.load(Ordering::Relaxed)
+    }
+
+    /// Number of live workers
+    pub fn size(&self) -> usize {
+        self.shared.lock().workers.len()
+    }
+
+    /// Number of jobs waiting for a worker
+    pub fn queued(&self) -> usize {
+        self.shared.lock().jobs.len()
+    }
+
+    /// Change the bounds of the pool at runtime.
+    ///
+    /// Workers are started right away to reach `min`. Workers above `max`
+    /// retire once they finish their current job, and workers above `min`
+    /// retire after sitting idle for the configured idle timeout.
+    pub fn resize(&self, min: usize, max: usize) -> Result<(), PoolCreationError> {
+        check_bounds(min, max)?;
+
+        let mut state = self.shared.lock();
+        let old = (state.min, state.max);
+        state.min = min;
+        state.max = max;
+        let mut result = Ok(());
+        while state.workers.len() < min {
+            let id = state.free_id();
+            if let Err(e) = state.spawn_worker(id, &self.shared) {
+                // keep the old bounds, the workers started so far retire
+                // again if they are above them
+                (state.min, state.max) = old;
+                result = Err(e);
+                break;
+            }
+        }
+        drop(state);
+
+        // wake idle workers so the ones above max can retire
+        self.shared.available.notify_all();
+        result
+    }
+
+    /// Let the workers drain the queue, then wait for them to exit.
+    ///
+    /// Dropping the pool stops it the same way but does not wait, so
+    /// whoever drops it isn't held up by long-running jobs such as
+    /// connections that stay open.
+    pub fn shutdown(self) {
+        for (id, worker) in self.stop() {
+            // a job shutting down the pool cannot wait for its own worker
+            if worker.thread.thread().id() == thread::current().id() {
+                continue;
+            }
+            if worker.thread.join().is_err() {
+                error!("Worker {id} panicked while shutting down");
+            }
+        }
+    }
+
+    /// Tell the workers to exit once the queue is empty and hand back
+    /// their handles
+    fn stop(&self) -> BTreeMap<usize, Worker> {
+        let workers = {
+            let mut state = self.shared.lock();
+            state.shutdown = true;
+            std::mem::take(&mut state.workers)
+        };
+        self.shared.available.notify_all();
+        workers
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Let the workers drain the queue and exit, without waiting for them
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // jobs never run while the lock is held, so poisoning can only
+        // come from a bug in the pool itself
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Id of the calling thread if it is one of this pool's workers
+    fn current_worker(self: &Arc<Self>) -> Option<usize> {
+        match CURRENT_WORKER.get() {
+            Some((pool, id)) if pool == Arc::as_ptr(self) as usize => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Run one queued job on the calling worker thread.
+    /// Returns false if the caller is not a worker or there was nothing to run.
+    ///
+    /// The job may be anybody's, so its panic must not unwind into the
+    /// waiting caller: that could leave a scope while its jobs still borrow
+    /// from it, or hand a stranger's panic to `JoinHandle::join`. The panic
+    /// is kept instead and resumed once the worker is back in its loop,
+    /// where it is supervised like any other panicking job.
+    fn help(self: &Arc<Self>) -> bool {
+        let Some(id) = self.current_worker() else {
+            return false;
+        };
+        let job = self.lock().next_job(id);
+        match job {
+            Some(job) => {
+                if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                    // the first panic is the one that kills the worker
+                    let first = HELPED_PANIC.take().unwrap_or(panic);
+                    HELPED_PANIC.set(Some(first));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Queue a job for any worker
+    fn push(self: &Arc<Self>, job: Job) {
+        let mut state = self.lock();
+        state.jobs.push_back(job);
+
+        // the queue is backing up when there are more jobs than idle workers
+        if !state.shutdown && state.jobs.len() > state.idle && state.workers.len() < state.max {
+            let id = state.free_id();
+            if let Err(e) = state.spawn_worker(id, self) {
+                // the job stays queued for the existing workers
+                warn!("Could not grow thread pool: {e}");
+            }
+        }
+        drop(state);
+        self.available.notify_one();
+    }
+}
+
+thread_local! {
+    /// (pool, worker id) for pool worker threads
+    static CURRENT_WORKER: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+
+    /// Panic from a job a worker ran while waiting, see `Shared::help`
+    static HELPED_PANIC: Cell<Option<Box<dyn Any + Send>>> = const { Cell::new(None) };
+}
+
+/// Let a panic from a job run while waiting take down the worker, now
+/// that nothing on its stack is borrowed by scoped jobs anymore
+fn resume_helped_panic() {
+    if let Some(panic) = HELPED_PANIC.take() {
+        panic::resume_unwind(panic);
+    }
+}
+
+fn check_bounds(min: usize, max: usize) -> Result<(), PoolCreationError> {
+    if min < 1 {
+        return Err(PoolCreationError::ZeroSize);
+    }
+    if min > max {
+        return Err(PoolCreationError::MinAboveMax { min, max });
+    }
+    Ok(())
+}
+
+/// Hook called from inside a worker thread with the worker id
+type WorkerHook = Arc<dyn Fn(usize) + Send + Sync + 'static>;
+
+/// How long a worker above the minimum pool size waits for a job
+/// before it retires
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Configuration shared by every worker of a pool
+#[derive(Clone, Default)]
+pub struct ThreadPoolBuilder {
+    name_prefix: Option<String>,
+    stack_size: Option<usize>,
+    cpu_affinity: Option<Vec<usize>>,
+    on_start: Option<WorkerHook>,
+    on_stop: Option<WorkerHook>,
+    max_size: Option<usize>,
+    idle_timeout: Option<Duration>,
+}
+
+impl fmt::Debug for ThreadPoolBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThreadPoolBuilder")
+            .field("name_prefix", &self.name_prefix)
+            .field("stack_size", &self.stack_size)
+            .field("cpu_affinity", &self.cpu_affinity)
+            .field("on_start", &self.on_start.is_some())
+            .field("on_stop", &self.on_stop.is_some())
+            .field("max_size", &self.max_size)
+            .field("idle_timeout", &self.idle_timeout)
+            .finish()
+    }
+}
+
+impl ThreadPoolBuilder {
+    /// Name worker threads `{prefix}-{id}` so they can be told apart
+    /// in debuggers and profilers.
+    pub fn name_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.name_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Stack size in bytes for each worker thread.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// Pin workers to the given CPU cores, assigned round-robin by worker id.
+    /// Only supported on Linux; ignored with a warning elsewhere.
+    /// `finalize` fails if a core is not below `CPU_SETSIZE` (1024).
+    pub fn cpu_affinity(mut self, cores: impl IntoIterator<Item = usize>) -> Self {
+        let cores: Vec<usize> = cores.into_iter().collect();
+        self.cpu_affinity = (!cores.is_empty()).then_some(cores);
+        self
+    }
+
+    /// Called from each worker thread, with its id, before it takes any job.
+    /// A panic in the hook is logged and the worker carries on.
+    pub fn on_thread_start(mut self, hook: impl Fn(usize) + Send + Sync + 'static) -> Self {
+        self.on_start = Some(Arc::new(hook));
+        self
+    }
+
+    /// Called from each worker thread, with its id, right before it exits,
+    /// including a pool worker that is dying from a panicking job.
+    pub fn on_thread_stop(mut self, hook: impl Fn(usize) + Send + Sync + 'static) -> Self {
+        self.on_stop = Some(Arc::new(hook));
+        self
+    }
+
+    /// Let the pool grow up to `max` workers when jobs queue up.
+    /// Without this the pool stays at the size passed to `finalize`.
+    pub fn max_size(mut self, max: usize) -> Self {
+        self.max_size = Some(max);
+        self
+    }
+
+    /// How long a worker above the minimum size may sit idle before it
+    /// retires. Defaults to 60 seconds.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Finalize the builder and create a ThreadPool with `size` workers.
+    ///
+    /// `size` is also the minimum the pool shrinks back to when it was
+    /// allowed to grow with `max_size`.
+    pub fn finalize(self, size: usize) -> Result<ThreadPool, PoolCreationError> {
+        let max = self.max_size.unwrap_or(size);
+        check_bounds(size, max)?;
+        if let Some(&core) = self
+            .cpu_affinity
+            .iter()
+            .flatten()
+            .find(|&&c| c >= MAX_CORES)
+        {
+            return Err(PoolCreationError::CoreOutOfRange(core));
+        }
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                jobs: VecDeque::new(),
+                broadcasts: BTreeMap::new(),
+                workers: BTreeMap::new(),
+                idle: 0,
+                min: size,
+                max,
+                shutdown: false,
+            }),
+            available: Condvar::new(),
+            config: self,
+            restarts: AtomicUsize::new(0),
+        });
+
+        {
+            let mut state = shared.lock();
+            for id in 0..size {
+                state.spawn_worker(id, &shared)?;
+            }
+        }
+
+        Ok(ThreadPool { shared })
+    }
+
+    fn thread_builder(&self, id: usize) -> thread::Builder {
+        let mut builder = thread::Builder::new();
+        if let Some(prefix) = &self.name_prefix {
+            builder = builder.name(format!("{prefix}-{id}"));
+        }
+        if let Some(size) = self.stack_size {
+            builder = builder.stack_size(size);
+        }
+        builder
+    }
+
+    fn core_for(&self, id: usize) -> Option<usize> {
+        self.cpu_affinity
+            .as_ref()
+            .map(|cores| cores[id % cores.len()])
     }
 }
 
 #[derive(Debug)]
 pub struct Worker {
-    _id: usize,
-    _thread: thread::JoinHandle<()>,
+    thread: thread::JoinHandle<()>,
 }
 
 impl Worker {
     /// Create a new Worker with a receiver clone
     /// and spawns a thread that loops over jobs sent over the
-    /// receiver and executes the job
-    pub fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        // if OS cannot create a new thread, thread::spawn will panic
-        // TODO: Change to thread::Builder which returns Result
-        let thread = thread::spawn(move || loop {
-            // blocks all other threads trying to aquire lock
-            // until it goes out of scope
-            match receiver.lock().unwrap().recv() {
-                Ok(job) => {
-                    job();
+    /// receiver and executes the job, until the sender is dropped.
+    ///
+    /// The thread is set up from `config` like a pool worker, but it is
+    /// not supervised: a panicking job ends it.
+    pub fn new(
+        id: usize,
+        receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
+        config: &ThreadPoolBuilder,
+    ) -> Result<Worker, PoolCreationError> {
+        let core = config.core_for(id);
+        let on_start = config.on_start.clone();
+        let on_stop = config.on_stop.clone();
+
+        let thread = config
+            .thread_builder(id)
+            .spawn(move || {
+                if let Some(core) = core {
+                    pin_to_core(core);
+                }
+                run_hook(&on_start, id);
+                loop {
+                    // the lock is released at the end of this statement so
+                    // other workers can receive while the job runs
+                    let message = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                    match message {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
                 }
-                Err(_) => {
-                    println!("Worker {id} exiting.");
-                    break;
+                run_hook(&on_stop, id);
+            })
+            .map_err(PoolCreationError::SpawnFailed)?;
+
+        Ok(Worker { thread })
+    }
+
+    /// Start a pool worker with a handle to the shared queue
+    /// that loops over jobs pushed to the queue and executes the job
+    fn spawn(id: usize, shared: Arc<Shared>) -> Result<Worker, PoolCreationError> {
+        let config = &shared.config;
+        let core = config.core_for(id);
+        let builder = config.thread_builder(id);
+
+        let thread = builder
+            .spawn(move || {
+                CURRENT_WORKER.set(Some((Arc::as_ptr(&shared) as usize, id)));
+                if let Some(core) = core {
+                    pin_to_core(core);
+                }
+                run_hook(&shared.config.on_start, id);
+
+                // runs the stop hook and respawns this worker if a job
+                // panics and unwinds the thread
+                let sentinel = Sentinel {
+                    id,
+                    shared: &shared,
+                };
+                run_jobs(id, &shared);
+                drop(sentinel);
+
+                run_hook(&shared.config.on_stop, id);
+            })
+            .map_err(PoolCreationError::SpawnFailed)?;
+
+        Ok(Worker { thread })
+    }
+}
+
+/// Call a worker hook, if set. A panicking hook is logged rather than
+/// taking down the worker, which would otherwise die before its jobs
+/// could be supervised.
+fn run_hook(hook: &Option<WorkerHook>, id: usize) {
+    if let Some(hook) = hook {
+        if panic::catch_unwind(AssertUnwindSafe(|| hook(id))).is_err() {
+            error!("Worker {id} hook panicked");
+        }
+    }
+}
+
+/// Worker loop: run queued jobs until the pool shuts down or this
+/// worker is no longer needed
+fn run_jobs(id: usize, shared: &Arc<Shared>) {
+    let idle_timeout = shared.config.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT);
+    resume_helped_panic();
+    let mut state = shared.lock();
+    loop {
+        if let Some(job) = state.next_job(id) {
+            // release the lock so other workers can take jobs while this runs
+            drop(state);
+            job();
+            resume_helped_panic();
+            state = shared.lock();
+            continue;
+        }
+        if state.shutdown {
+            debug!("Worker {id} exiting.");
+            return;
+        }
+        if state.workers.len() > state.max && !state.has_work(id) {
+            debug!("Worker {id} retiring, pool is above its maximum size");
+            state.retire(id);
+            return;
+        }
+
+        state.idle += 1;
+        let (guard, wait) = shared
+            .available
+            .wait_timeout(state, idle_timeout)
+            .unwrap_or_else(|e| e.into_inner());
+        state = guard;
+        state.idle -= 1;
+
+        if wait.timed_out() && !state.has_work(id) && state.workers.len() > state.min {
+            debug!("Worker {id} retiring after being idle for {idle_timeout:?}");
+            state.retire(id);
+            return;
+        }
+    }
+}
+
+/// Lives on a worker's stack; when dropped during a panic it runs the stop
+/// hook and replaces the dying worker with a fresh one under the same id.
+struct Sentinel<'a> {
+    id: usize,
+    shared: &'a Arc<Shared>,
+}
+
+impl Drop for Sentinel<'_> {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+        let id = self.id;
+        run_hook(&self.shared.config.on_stop, id);
+
+        let mut state = self.shared.lock();
+        if state.shutdown {
+            return;
+        }
+        let restarts = self.shared.restarts.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("Worker {id} died from a panicking job, respawning (restart #{restarts})");
+
+        // the old handle belongs to this thread, which is exiting
+        state.workers.remove(&id);
+        if let Err(e) = state.spawn_worker(id, self.shared) {
+            error!("Could not respawn worker {id}: {e}");
+            // nobody is left to run the jobs addressed to it
+            state.broadcasts.remove(&id);
+        }
+    }
+}
+
+/// A value guarded by a mutex plus a condvar signalled when it changes
+struct Signal<S> {
+    state: Mutex<S>,
+    changed: Condvar,
+}
+
+impl<S> Signal<S> {
+    fn new(state: S) -> Self {
+        Signal {
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, S> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn notify(&self) {
+        self.changed.notify_all();
+    }
+
+    /// Block until `ready` holds. Workers of `pool` run queued jobs while
+    /// they wait instead of sleeping, so the jobs they wait on can't be
+    /// stuck behind them in the queue.
+    fn wait_until(&self, pool: &Arc<Shared>, ready: impl Fn(&S) -> bool) -> MutexGuard<'_, S> {
+        let is_worker = pool.current_worker().is_some();
+        loop {
+            let guard = self.lock();
+            if ready(&guard) {
+                return guard;
+            }
+            if !is_worker {
+                let guard = self
+                    .changed
+                    .wait_while(guard, |state| !ready(state))
+                    .unwrap_or_else(|e| e.into_inner());
+                return guard;
+            }
+            drop(guard);
+            if !pool.help() {
+                // nothing queued: whatever we wait on is running elsewhere
+                let guard = self.lock();
+                if !ready(&guard) {
+                    let _ = self.changed.wait_timeout(guard, HELP_INTERVAL);
                 }
             }
-        });
+        }
+    }
+}
 
-        Worker {
-            _id: id,
-            _thread: thread,
+/// How often a waiting worker looks for queued jobs to help with
+const HELP_INTERVAL: Duration = Duration::from_millis(10);
+
+struct Packet<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// Handle to the result of a job started with `ThreadPool::spawn`.
+///
+/// Either `join` it to block until the job finishes or `.await` it.
+/// Both give `Err` with the panic payload if the job panicked.
+pub struct JoinHandle<T> {
+    packet: Arc<Signal<Packet<T>>>,
+    pool: Weak<Shared>,
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JoinHandle")
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+impl<T> JoinHandle<T> {
+    /// Block until the job has finished and return its result
+    pub fn join(self) -> thread::Result<T> {
+        let mut packet = match self.pool.upgrade() {
+            Some(pool) => self
+                .packet
+                .wait_until(&pool, |packet| packet.result.is_some()),
+            // without a pool there is nobody left to help, the job was
+            // drained from the queue when the pool shut down
+            None => self.packet.lock(),
+        };
+        packet
+            .result
+            .take()
+            .expect("job was dropped without running")
+    }
+
+    /// Whether the job has finished, without blocking
+    pub fn is_finished(&self) -> bool {
+        self.packet.lock().result.is_some()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = thread::Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut packet = self.packet.lock();
+        match packet.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                packet.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+struct Latch {
+    pending: usize,
+    /// First panic from a scoped job
+    panic: Option<Box<dyn Any + Send + 'static>>,
+}
+
+/// Spawns jobs that may borrow from `'env`, see `ThreadPool::scope`
+pub struct Scope<'scope, 'env: 'scope> {
+    shared: &'scope Arc<Shared>,
+    latch: Arc<Signal<Latch>>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Run `f` on the pool. It is guaranteed to finish before the
+    /// enclosing `ThreadPool::scope` call returns.
+    pub fn spawn<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        let job = self.job(f);
+        self.shared.push(job);
+    }
+
+    /// Queue `f` once for every worker, see `ThreadPool::broadcast`
+    fn spawn_broadcast<F>(&'scope self, f: &'scope F)
+    where
+        F: Fn(usize) + Sync,
+    {
+        // list the workers and queue their jobs under one lock, so none of
+        // them can retire in between and leave its job behind
+        let mut state = self.shared.lock();
+        let ids: Vec<usize> = state.workers.keys().copied().collect();
+        for id in ids {
+            let job = self.job(move || f(id));
+            state.broadcasts.entry(id).or_default().push_back(job);
+        }
+        drop(state);
+        self.shared.available.notify_all();
+    }
+
+    /// Box `f` as a job counted on this scope's latch
+    fn job<F>(&'scope self, f: F) -> Job
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        self.latch.lock().pending += 1;
+        let job = ScopedJob {
+            f: Some(f),
+            latch: Arc::clone(&self.latch),
+        };
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || job.run());
+        // SAFETY: `ThreadPool::scope` neither returns nor unwinds before
+        // `pending` is back to zero: panics from `f` and from the scoped
+        // jobs are caught, and jobs run while waiting can't unwind out of
+        // the wait (see `Shared::help`). `ScopedJob` counts itself off only
+        // after dropping its closure, whether it ran or not, so the job
+        // never outlives the borrows it holds.
+        unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) }
+    }
+}
+
+/// A job spawned on a scope. It is counted off the scope's latch when it
+/// is dropped, after it ran or when it is dropped from the queue unrun.
+struct ScopedJob<F> {
+    f: Option<F>,
+    latch: Arc<Signal<Latch>>,
+}
+
+impl<F: FnOnce()> ScopedJob<F> {
+    fn run(mut self) {
+        let f = self.f.take().expect("scoped job runs once");
+        if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(f)) {
+            self.latch.lock().panic.get_or_insert(panic);
         }
     }
 }
 
+impl<F> Drop for ScopedJob<F> {
+    fn drop(&mut self) {
+        // whatever the job borrows has to be gone before the scope may end
+        drop(self.f.take());
+        self.latch.lock().pending -= 1;
+        self.latch.notify();
+    }
+}
+
+/// Cores that fit in the affinity mask; `CPU_SET` panics past it
+#[cfg(target_os = "linux")]
+const MAX_CORES: usize = libc::CPU_SETSIZE as usize;
+#[cfg(not(target_os = "linux"))]
+const MAX_CORES: usize = usize::MAX;
+
+#[cfg(target_os = "linux")]
+fn pin_to_core(core: usize) {
+    // SAFETY: cpu_set_t is plain data, zeroed is the empty set, and
+    // sched_setaffinity only reads the set we pass for the calling thread.
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result != 0 {
+        warn!(
+            "Could not pin worker to core {core}: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_core(core: usize) {
+    warn!("CPU affinity is not supported on this platform, not pinning to core {core}");
+}
+
 /// Type alias for the closure arument to ThreadPool.execute()
 type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zero_size_fails() {
+        let pool = ThreadPool::builder().finalize(0);
+        assert!(matches!(pool, Err(PoolCreationError::ZeroSize)));
+    }
+
+    #[test]
+    fn test_threads_are_named() {
+        let pool = ThreadPool::builder()
+            .name_prefix("crag-test")
+            .stack_size(256 * 1024)
+            .finalize(1)
+            .unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || {
+            tx.send(thread::current().name().map(String::from)).unwrap();
+        });
+        let name = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(name.as_deref(), Some("crag-test-0"));
+    }
+
+    #[test]
+    fn test_start_and_stop_hooks() {
+        let started = Arc::new(AtomicUsize::new(0));
+        let stopped = Arc::new(AtomicUsize::new(0));
+        let (s, t) = (started.clone(), stopped.clone());
+
+        let pool = ThreadPool::builder()
+            .on_thread_start(move |_| {
+                s.fetch_add(1, Ordering::SeqCst);
+            })
+            .on_thread_stop(move |_| {
+                t.fetch_add(1, Ordering::SeqCst);
+            })
+            .finalize(3)
+            .unwrap();
+
+        // shutting down waits for every worker to exit
+        pool.shutdown();
+        assert_eq!(started.load(Ordering::SeqCst), 3);
+        assert_eq!(stopped.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_core_out_of_range_fails() {
+        let pool = ThreadPool::builder().cpu_affinity([0, 4096]).finalize(1);
+        assert!(matches!(pool, Err(PoolCreationError::CoreOutOfRange(4096))));
+    }
+
+    #[test]
+    fn test_panicking_start_hook_keeps_worker() {
+        let pool = ThreadPool::builder()
+            .on_thread_start(|_| panic!("hook failed"))
+            .finalize(1)
+            .unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(()).unwrap());
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(pool.restarts(), 0);
+    }
+
+    #[test]
+    fn test_panicking_job_respawns_worker() {
+        let pool = ThreadPool::builder()
+            .name_prefix("crag-respawn")
+            .finalize(1)
+            .unwrap();
+
+        pool.execute(|| panic!("job failed"));
+
+        // the only worker died, so this only runs if it was replaced
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || {
+            tx.send(thread::current().name().map(String::from)).unwrap();
+        });
+        let name = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(name.as_deref(), Some("crag-respawn-0"));
+        assert_eq!(pool.restarts(), 1);
+    }
+
+    #[test]
+    fn test_stop_hook_runs_for_dying_worker() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let pool = ThreadPool::builder()
+            .on_thread_stop(move |id| tx.lock().unwrap().send(id).unwrap())
+            .finalize(1)
+            .unwrap();
+
+        pool.execute(|| panic!("job failed"));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_standalone_worker() {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let worker = Worker::new(
+            7,
+            Arc::new(Mutex::new(receiver)),
+            &ThreadPool::builder().name_prefix("crag-standalone"),
+        )
+        .unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        sender
+            .send(Box::new(move || {
+                tx.send(thread::current().name().map(String::from)).unwrap();
+            }))
+            .unwrap();
+        let name = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(name.as_deref(), Some("crag-standalone-7"));
+
+        // the worker exits once nothing can send it jobs anymore
+        drop(sender);
+        worker.thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_pool_grows_when_queue_backs_up() {
+        let pool = ThreadPool::builder()
+            .max_size(3)
+            .idle_timeout(Duration::from_millis(50))
+            .finalize(1)
+            .unwrap();
+
+        // block every worker until all three jobs have started
+        let barrier = Arc::new(std::sync::Barrier::new(3));
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..3 {
+            let barrier = barrier.clone();
+            let tx = tx.clone();
+            pool.execute(move || {
+                barrier.wait();
+                tx.send(()).unwrap();
+            });
+        }
+        for _ in 0..3 {
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(pool.size(), 3);
+
+        // the extra workers retire once they have been idle long enough
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while pool.size() > 1 && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.size(), 1);
+    }
+
+    #[test]
+    fn test_resize_at_runtime() {
+        let pool = ThreadPool::build(2).unwrap();
+        pool.resize(4, 6).unwrap();
+        assert_eq!(pool.size(), 4);
+
+        pool.resize(1, 1).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while pool.size() > 1 && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.size(), 1);
+
+        assert!(matches!(
+            pool.resize(3, 2),
+            Err(PoolCreationError::MinAboveMax { min: 3, max: 2 })
+        ));
+        assert_eq!(pool.size(), 1);
+    }
+
+    #[test]
+    fn test_drop_does_not_wait_for_jobs() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (release, blocked) = mpsc::channel::<()>();
+        let (started, wait_started) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            // stands in for a connection that stays open
+            let _ = blocked.recv();
+        });
+        wait_started.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let start = std::time::Instant::now();
+        drop(pool);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        drop(release);
+    }
+
+    #[test]
+    fn test_spawn_returns_result() {
+        let pool = ThreadPool::build(2).unwrap();
+        let handle = pool.spawn(|| 6 * 7);
+        assert_eq!(handle.join().unwrap(), 42);
+
+        let handle = pool.spawn(|| -> usize { panic!("job failed") });
+        assert!(handle.join().is_err());
+        // the panic was caught, so no worker had to be replaced
+        assert_eq!(pool.restarts(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_spawn_can_be_awaited() {
+        let pool = ThreadPool::build(1).unwrap();
+        let value = pool.spawn(|| "done".to_string()).await.unwrap();
+        assert_eq!(value, "done");
+    }
+
+    #[test]
+    fn test_scope_borrows_from_caller() {
+        let pool = ThreadPool::build(2).unwrap();
+        let mut chunks = vec![vec![1, 2], vec![3, 4], vec![5, 6]];
+        pool.scope(|s| {
+            for chunk in chunks.iter_mut() {
+                s.spawn(move || chunk.iter_mut().for_each(|x| *x *= 10));
+            }
+        });
+        assert_eq!(chunks, vec![vec![10, 20], vec![30, 40], vec![50, 60]]);
+    }
+
+    #[test]
+    fn test_scope_from_inside_a_job() {
+        // a single worker fanning out onto its own pool must not deadlock
+        let pool = Arc::new(ThreadPool::build(1).unwrap());
+        let inner = pool.clone();
+        let handle = pool.spawn(move || {
+            let mut total = 0;
+            let counter = Mutex::new(&mut total);
+            inner.scope(|s| {
+                for i in 1..=4 {
+                    let counter = &counter;
+                    s.spawn(move || **counter.lock().unwrap() += i);
+                }
+            });
+            total
+        });
+        assert_eq!(handle.join().unwrap(), 10);
+    }
+
+    #[test]
+    fn test_scope_resumes_panics() {
+        let pool = ThreadPool::build(2).unwrap();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| s.spawn(|| panic!("scoped job failed")));
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_broadcast_runs_on_every_worker() {
+        let pool = ThreadPool::builder()
+            .name_prefix("crag-broadcast")
+            .finalize(3)
+            .unwrap();
+        let names = pool.broadcast(|id| (id, thread::current().name().map(String::from)));
+        assert_eq!(
+            names,
+            (0..3)
+                .map(|id| (id, Some(format!("crag-broadcast-{id}"))))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_waiting_worker_keeps_foreign_panics() {
+        let pool = Arc::new(ThreadPool::build(1).unwrap());
+        let inner = pool.clone();
+        let handle = pool.spawn(move || {
+            // queued ahead of the jobs waited on, so the worker runs these
+            // while it waits
+            inner.execute(|| panic!("unrelated job failed"));
+            let joined = inner.spawn(|| 1).join();
+
+            inner.execute(|| panic!("unrelated job failed"));
+            let mut total = 0;
+            inner.scope(|s| s.spawn(|| total += 2));
+            (joined.is_ok(), total)
+        });
+        assert_eq!(handle.join().unwrap(), (true, 2));
+
+        // the panic takes down the worker once its own job is done
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while pool.restarts() == 0 && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.restarts(), 1);
+        assert_eq!(pool.spawn(|| 3).join().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_broadcast_while_shrinking() {
+        let pool = ThreadPool::build(4).unwrap();
+        for _ in 0..20 {
+            let ids = thread::scope(|s| {
+                s.spawn(|| pool.resize(1, 1).unwrap());
+                pool.broadcast(|id| {
+                    thread::sleep(Duration::from_millis(1));
+                    id
+                })
+            });
+            // every worker listed ran its job before it could retire
+            assert!(!ids.is_empty());
+            pool.resize(4, 4).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_cpu_affinity_round_robin() {
+        let builder = ThreadPool::builder().cpu_affinity([0, 1]);
+        assert_eq!(builder.core_for(0), Some(0));
+        assert_eq!(builder.core_for(1), Some(1));
+        assert_eq!(builder.core_for(2), Some(0));
+        assert_eq!(ThreadPool::builder().core_for(0), None);
+    }
+}
@@ -0,0 +1,309 @@
+use crate::server::Connection;
+use anyhow::{Context, Result};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{IpAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+use x509_parser::extensions::GeneralName;
+
+/// Whether clients have to present a certificate during the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAuth {
+    /// Clients without a certificate are let through, clients with one
+    /// must present a certificate signed by the configured CAs
+    Optional,
+    /// The handshake fails unless the client presents a certificate
+    /// signed by the configured CAs
+    Required,
+}
+
+/// The verified certificate a client presented during a mutual TLS handshake
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PeerCertificate {
+    subject: String,
+    subject_alt_names: Vec<SubjectAltName>,
+    der: Vec<u8>,
+}
+
+/// An entry of a certificate's Subject Alternative Name extension
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SubjectAltName {
+    Dns(String),
+    Email(String),
+    Uri(String),
+    Ip(IpAddr),
+}
+
+impl PeerCertificate {
+    fn from_der(der: &[u8]) -> Result<PeerCertificate> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der)
+            .map_err(|e| anyhow::anyhow!("Invalid peer certificate: {e}"))?;
+
+        let subject_alt_names = cert
+            .subject_alternative_name()?
+            .map(|ext| {
+                ext.value
+                    .general_names
+                    .iter()
+                    .filter_map(|name| match name {
+                        GeneralName::DNSName(name) => Some(SubjectAltName::Dns(name.to_string())),
+                        GeneralName::RFC822Name(email) => {
+                            Some(SubjectAltName::Email(email.to_string()))
+                        }
+                        GeneralName::URI(uri) => Some(SubjectAltName::Uri(uri.to_string())),
+                        GeneralName::IPAddress(bytes) => {
+                            ip_from_bytes(bytes).map(SubjectAltName::Ip)
+                        }
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(PeerCertificate {
+            subject: cert.subject().to_string(),
+            subject_alt_names,
+            der: der.to_vec(),
+        })
+    }
+
+    /// Subject distinguished name, e.g. `CN=billing, O=Example`
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn subject_alt_names(&self) -> &[SubjectAltName] {
+        &self.subject_alt_names
+    }
+
+    /// The certificate as sent by the client, DER encoded
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+}
+
+fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => <[u8; 4]>::try_from(bytes).ok().map(IpAddr::from),
+        16 => <[u8; 16]>::try_from(bytes).ok().map(IpAddr::from),
+        _ => None,
+    }
+}
+
+/// How long a client gets to finish the TLS handshake by default, see
+/// `ServerBuilder::tls_handshake_timeout`
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An accepted connection after it was wrapped in TLS
+pub(crate) type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// Certificates registered on the `ServerBuilder`, turned into a rustls
+/// config when the server is finalized
+#[derive(Debug)]
+pub(crate) struct TlsSettings {
+    provider: Arc<CryptoProvider>,
+    default_cert: Option<Arc<CertifiedKey>>,
+    sni_certs: HashMap<String, Arc<CertifiedKey>>,
+    client_auth: Option<(RootCertStore, ClientAuth)>,
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        TlsSettings {
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+            default_cert: None,
+            sni_certs: HashMap::new(),
+            client_auth: None,
+        }
+    }
+}
+
+impl TlsSettings {
+    /// Certificate for clients that send no SNI name or one without its own certificate
+    pub(crate) fn set_default_cert(&mut self, cert_chain_pem: &[u8], key_pem: &[u8]) -> Result<()> {
+        self.default_cert = Some(self.certified_key(cert_chain_pem, key_pem)?);
+        Ok(())
+    }
+
+    /// Certificate for clients asking for `server_name` through SNI
+    pub(crate) fn add_sni_cert(
+        &mut self,
+        server_name: &str,
+        cert_chain_pem: &[u8],
+        key_pem: &[u8],
+    ) -> Result<()> {
+        let key = self.certified_key(cert_chain_pem, key_pem)?;
+        self.sni_certs.insert(server_name.to_ascii_lowercase(), key);
+        Ok(())
+    }
+
+    /// Ask clients for a certificate signed by one of the CAs in the PEM bundle
+    pub(crate) fn set_client_auth(&mut self, ca_bundle_pem: &[u8], mode: ClientAuth) -> Result<()> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(ca_bundle_pem) {
+            roots.add(cert.context("Invalid PEM CA bundle")?)?;
+        }
+        if roots.is_empty() {
+            anyhow::bail!("No certificate found in PEM CA bundle");
+        }
+        self.client_auth = Some((roots, mode));
+        Ok(())
+    }
+
+    pub(crate) fn server_config(self) -> Result<Arc<ServerConfig>> {
+        let resolver = SniResolver {
+            default_cert: self.default_cert,
+            sni_certs: self.sni_certs,
+        };
+        let builder = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match self.client_auth {
+            Some((roots, mode)) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), self.provider);
+                let verifier = match mode {
+                    ClientAuth::Optional => verifier.allow_unauthenticated(),
+                    ClientAuth::Required => verifier,
+                };
+                builder.with_client_cert_verifier(verifier.build()?)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        #[allow(unused_mut)]
+        let mut config = builder.with_cert_resolver(Arc::new(resolver));
+        #[cfg(feature = "http2")]
+        {
+            config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        }
+        Ok(Arc::new(config))
+    }
+
+    fn certified_key(&self, cert_chain_pem: &[u8], key_pem: &[u8]) -> Result<Arc<CertifiedKey>> {
+        let cert_chain = CertificateDer::pem_slice_iter(cert_chain_pem)
+            .collect::<Result<Vec<_>, _>>()
+            .context("Invalid PEM certificate chain")?;
+        if cert_chain.is_empty() {
+            anyhow::bail!("No certificate found in PEM certificate chain");
+        }
+        let key = PrivateKeyDer::from_pem_slice(key_pem).context("Invalid PEM private key")?;
+        let certified_key = CertifiedKey::from_der(cert_chain, key, &self.provider)?;
+        Ok(Arc::new(certified_key))
+    }
+}
+
+/// Picks the certificate by the SNI name the client asked for
+#[derive(Debug)]
+struct SniResolver {
+    default_cert: Option<Arc<CertifiedKey>>,
+    sni_certs: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.sni_certs.get(&name.to_ascii_lowercase()))
+            .or(self.default_cert.as_ref())
+            .cloned()
+    }
+}
+
+impl Connection for TlsStream {
+    fn peer_certificate(&self) -> Option<Arc<PeerCertificate>> {
+        peer_certificate(&self.conn)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    /// Flush what is left and tell the client we are done
+    fn close(&mut self) {
+        self.conn.send_close_notify();
+        _ = self.flush();
+    }
+}
+
+/// The client certificate verified during the handshake of `conn`
+pub(crate) fn peer_certificate(conn: &ServerConnection) -> Option<Arc<PeerCertificate>> {
+    // the first certificate is the client's own, the rest is its chain
+    let der = conn.peer_certificates()?.first()?;
+    match PeerCertificate::from_der(der) {
+        Ok(certificate) => Some(Arc::new(certificate)),
+        Err(e) => {
+            warn!("Could not read client certificate: {e:?}");
+            None
+        }
+    }
+}
+
+/// Wrap an accepted connection and run the handshake, giving up if the
+/// client hasn't finished it within `timeout`
+#[cfg(not(feature = "http2"))]
+pub(crate) fn accept(
+    config: &Arc<ServerConfig>,
+    stream: TcpStream,
+    timeout: Duration,
+) -> Result<TlsStream> {
+    let conn = ServerConnection::new(Arc::clone(config))?;
+    let mut stream = StreamOwned::new(conn, stream);
+
+    // a client that stalls the handshake must not hold on to a pool thread
+    let deadline = std::time::Instant::now() + timeout;
+    while stream.conn.is_handshaking() {
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        if remaining.is_zero() {
+            anyhow::bail!("TLS handshake timed out");
+        }
+        stream.sock.set_read_timeout(Some(remaining))?;
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+    stream.sock.set_read_timeout(None)?;
+    Ok(stream)
+}
+
+/// A connection after the handshake, by the protocol picked through ALPN
+#[cfg(feature = "http2")]
+pub(crate) enum Negotiated {
+    Http1(TlsStream),
+    Http2(crate::http2::TlsIo),
+}
+
+/// Run the handshake up front so the client's ALPN choice is known before
+/// the first request is read, giving up if the client hasn't finished it
+/// within `timeout`
+#[cfg(feature = "http2")]
+pub(crate) fn handshake(
+    config: &Arc<ServerConfig>,
+    stream: TcpStream,
+    timeout: Duration,
+) -> Result<Negotiated> {
+    let runtime = crate::http2::runtime()?;
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::clone(config));
+    stream.set_nonblocking(true)?;
+
+    let runtime_context = runtime.enter();
+    let stream = tokio::net::TcpStream::from_std(stream)?;
+    let stream = runtime
+        .block_on(tokio::time::timeout(timeout, acceptor.accept(stream)))
+        .map_err(|_| anyhow::anyhow!("TLS handshake timed out"))??;
+
+    if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+        drop(runtime_context);
+        return Ok(Negotiated::Http2(crate::http2::TlsIo { stream }));
+    }
+
+    // HTTP/1.1 is served with blocking I/O, hand the session back to rustls
+    let (stream, conn) = stream.into_inner();
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    Ok(Negotiated::Http1(StreamOwned::new(conn, stream)))
+}
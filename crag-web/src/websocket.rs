@@ -0,0 +1,610 @@
+use crate::request::Request;
+use crate::response::{Response, Upgraded};
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+/// Appended to the client's key before hashing, see RFC 6455 section 1.3
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message accepted from a client unless changed with
+/// `WebSocket::set_max_message_size`
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// How long `WebSocket::close` waits for the client to answer
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A complete message. Fragmented messages are put back together before
+/// they are returned by `WebSocket::recv`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Answered with a pong automatically
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The client started or answered the closing handshake
+    Close(Option<CloseFrame>),
+}
+
+/// Status code and reason carried by a close frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseFrame {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    pub const INVALID_PAYLOAD: u16 = 1007;
+    pub const POLICY_VIOLATION: u16 = 1008;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    pub const INTERNAL_ERROR: u16 = 1011;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Opcode {
+    Continuation = 0x0,
+    Text = 0x1,
+    Binary = 0x2,
+    Close = 0x8,
+    Ping = 0x9,
+    Pong = 0xa,
+}
+
+impl Opcode {
+    fn from_bits(bits: u8) -> Option<Opcode> {
+        match bits {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xa => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn is_control(self) -> bool {
+        self as u8 & 0x8 != 0
+    }
+}
+
+#[derive(Debug)]
+struct Frame {
+    fin: bool,
+    opcode: Opcode,
+    payload: Vec<u8>,
+}
+
+/// A client broke the protocol; the session is closed with `code`
+#[derive(Debug)]
+struct ProtocolError {
+    code: u16,
+    reason: &'static str,
+}
+
+impl ProtocolError {
+    fn new(code: u16, reason: &'static str) -> ProtocolError {
+        ProtocolError { code, reason }
+    }
+}
+
+/// A WebSocket session on a connection that finished the handshake.
+///
+/// Sessions run on a thread of their own. To push updates while also
+/// listening to the client, poll with `recv_timeout` and send in between.
+pub struct WebSocket {
+    stream: Upgraded,
+    /// Bytes read from the stream that don't make up a whole frame yet
+    buffer: Vec<u8>,
+    /// Opcode and payload of a fragmented message being received
+    fragments: Option<(Opcode, Vec<u8>)>,
+    max_message_size: usize,
+    close_sent: bool,
+    close_received: bool,
+}
+
+/// Answer a handshake request with `101 Switching Protocols` and run
+/// `handler` on the session once the response was written. Requests that
+/// aren't a valid handshake get an error response instead.
+pub(crate) fn upgrade<F>(req: Request, handler: Arc<F>) -> Response
+where
+    F: Fn(Request, WebSocket) -> Result<()> + Send + Sync + 'static,
+{
+    let key = match handshake_key(&req) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+
+    Response::upgrade("websocket", move |stream| {
+        if let Err(e) = handler(req, WebSocket::new(stream)) {
+            error!("Error in WebSocket session: {e:?}");
+        }
+    })
+    .with_header("Sec-WebSocket-Accept", accept_key(&key))
+}
+
+/// The client's `Sec-WebSocket-Key`, or the response refusing the handshake
+fn handshake_key(req: &Request) -> std::result::Result<String, Response> {
+    if !req.wants_upgrade("websocket") {
+        return Err(Response::new(426)
+            .with_header("Upgrade", "websocket")
+            .with_body("Expected a WebSocket handshake"));
+    }
+    if req.header("Sec-WebSocket-Version") != Some("13") {
+        return Err(Response::new(426)
+            .with_header("Sec-WebSocket-Version", "13")
+            .with_body("Unsupported WebSocket version"));
+    }
+    match req.header("Sec-WebSocket-Key") {
+        Some(key) if BASE64.decode(key).is_ok_and(|nonce| nonce.len() == 16) => Ok(key.to_string()),
+        _ => Err(Response::new(400).with_body("Invalid Sec-WebSocket-Key")),
+    }
+}
+
+/// `Sec-WebSocket-Accept` for the client's `Sec-WebSocket-Key`
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    BASE64.encode(sha1.finalize())
+}
+
+impl WebSocket {
+    pub(crate) fn new(stream: Upgraded) -> WebSocket {
+        WebSocket {
+            stream,
+            buffer: Vec::new(),
+            fragments: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    /// Close the session with `1009` when a client sends a bigger message
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// Block until the next message arrives
+    pub fn recv(&mut self) -> Result<Message> {
+        loop {
+            if let Some(message) = self.read_message(None)? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for the next message
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Message>> {
+        self.read_message(Some(Instant::now() + timeout))
+    }
+
+    pub fn send(&mut self, message: Message) -> Result<()> {
+        match message {
+            Message::Text(text) => self.write_frame(Opcode::Text, true, text.as_bytes()),
+            Message::Binary(data) => self.write_frame(Opcode::Binary, true, &data),
+            Message::Ping(data) => self.write_control(Opcode::Ping, &data),
+            Message::Pong(data) => self.write_control(Opcode::Pong, &data),
+            Message::Close(frame) => {
+                self.write_control(Opcode::Close, &close_payload(frame.as_ref()))
+            }
+        }
+    }
+
+    /// Send a text or binary message as a series of frames carrying at
+    /// most `fragment_size` bytes each
+    pub fn send_fragmented(&mut self, message: Message, fragment_size: usize) -> Result<()> {
+        let (mut opcode, payload) = match message {
+            Message::Text(text) => (Opcode::Text, text.into_bytes()),
+            Message::Binary(data) => (Opcode::Binary, data),
+            _ => anyhow::bail!("Only text and binary messages can be fragmented"),
+        };
+        if payload.is_empty() {
+            return self.write_frame(opcode, true, &payload);
+        }
+
+        let mut fragments = payload.chunks(fragment_size.max(1)).peekable();
+        while let Some(fragment) = fragments.next() {
+            self.write_frame(opcode, fragments.peek().is_none(), fragment)?;
+            opcode = Opcode::Continuation;
+        }
+        Ok(())
+    }
+
+    /// Start the closing handshake and wait a few seconds for the client
+    /// to answer it. Messages arriving in the meantime are dropped.
+    pub fn close(mut self, code: u16, reason: &str) -> Result<()> {
+        if !self.close_sent {
+            self.send(Message::Close(Some(CloseFrame {
+                code,
+                reason: reason.to_string(),
+            })))?;
+        }
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        while !self.close_received {
+            if self.read_message(Some(deadline))?.is_none() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `None` once `deadline` has passed without a whole message
+    fn read_message(&mut self, deadline: Option<Instant>) -> Result<Option<Message>> {
+        if self.close_received {
+            anyhow::bail!("WebSocket is closed");
+        }
+        loop {
+            let message = match parse_frame(&self.buffer, self.max_message_size) {
+                Ok(Some((frame, used))) => {
+                    self.buffer.drain(..used);
+                    self.on_frame(frame)
+                }
+                Ok(None) => {
+                    if !self.fill(deadline)? {
+                        return Ok(None);
+                    }
+                    continue;
+                }
+                Err(e) => Err(e),
+            };
+
+            match message {
+                Ok(Some(message)) => {
+                    self.answer(&message)?;
+                    return Ok(Some(message));
+                }
+                Ok(None) => continue,
+                Err(e) => return Err(self.fail(e)),
+            }
+        }
+    }
+
+    /// Apply a frame to the message being received, returning the message
+    /// once it is complete
+    fn on_frame(&mut self, frame: Frame) -> std::result::Result<Option<Message>, ProtocolError> {
+        match frame.opcode {
+            Opcode::Ping => Ok(Some(Message::Ping(frame.payload))),
+            Opcode::Pong => Ok(Some(Message::Pong(frame.payload))),
+            Opcode::Close => Ok(Some(Message::Close(parse_close(&frame.payload)?))),
+            Opcode::Text | Opcode::Binary if self.fragments.is_some() => Err(ProtocolError::new(
+                CloseFrame::PROTOCOL_ERROR,
+                "Expected a continuation frame",
+            )),
+            Opcode::Text | Opcode::Binary if !frame.fin => {
+                self.fragments = Some((frame.opcode, frame.payload));
+                Ok(None)
+            }
+            Opcode::Text | Opcode::Binary => to_message(frame.opcode, frame.payload).map(Some),
+            Opcode::Continuation => {
+                let Some((_, payload)) = self.fragments.as_mut() else {
+                    return Err(ProtocolError::new(
+                        CloseFrame::PROTOCOL_ERROR,
+                        "Unexpected continuation frame",
+                    ));
+                };
+                if payload.len() + frame.payload.len() > self.max_message_size {
+                    return Err(ProtocolError::new(
+                        CloseFrame::MESSAGE_TOO_BIG,
+                        "Message too big",
+                    ));
+                }
+                payload.extend(frame.payload);
+                if !frame.fin {
+                    return Ok(None);
+                }
+                let (opcode, payload) =
+                    self.fragments.take().expect("fragments were just extended");
+                to_message(opcode, payload).map(Some)
+            }
+        }
+    }
+
+    /// Reply to control messages the way the protocol requires
+    fn answer(&mut self, message: &Message) -> Result<()> {
+        match message {
+            Message::Ping(data) if !self.close_sent => self.write_control(Opcode::Pong, data),
+            Message::Close(frame) => {
+                self.close_received = true;
+                if self.close_sent {
+                    return Ok(());
+                }
+                // echo the status code back, as RFC 6455 section 5.5.1 suggests
+                let echo = frame.as_ref().map(|frame| CloseFrame {
+                    code: frame.code,
+                    reason: String::new(),
+                });
+                self.write_control(Opcode::Close, &close_payload(echo.as_ref()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Close the session after the client broke the protocol
+    fn fail(&mut self, error: ProtocolError) -> anyhow::Error {
+        if !self.close_sent {
+            let frame = CloseFrame {
+                code: error.code,
+                reason: error.reason.to_string(),
+            };
+            _ = self.write_control(Opcode::Close, &close_payload(Some(&frame)));
+        }
+        // nothing the client sends after this is read
+        self.close_received = true;
+        anyhow::anyhow!("WebSocket protocol error: {}", error.reason)
+    }
+
+    /// Read more bytes into the buffer. Returns false once `deadline` passed.
+    fn fill(&mut self, deadline: Option<Instant>) -> Result<bool> {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Ok(false);
+                }
+                Some(left)
+            }
+            None => None,
+        };
+        self.stream.set_read_timeout(timeout)?;
+
+        let mut chunk = [0; 4096];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => anyhow::bail!("Connection closed without a close frame"),
+                Ok(n) => {
+                    self.buffer.extend_from_slice(&chunk[..n]);
+                    return Ok(true);
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(false)
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn write_control(&mut self, opcode: Opcode, payload: &[u8]) -> Result<()> {
+        if payload.len() > 125 {
+            anyhow::bail!("Control frame payloads are limited to 125 bytes");
+        }
+        self.write_frame(opcode, true, payload)
+    }
+
+    fn write_frame(&mut self, opcode: Opcode, fin: bool, payload: &[u8]) -> Result<()> {
+        if self.close_sent {
+            anyhow::bail!("WebSocket is closed");
+        }
+        if opcode == Opcode::Close {
+            self.close_sent = true;
+        }
+        self.stream.write_all(&encode_frame(opcode, fin, payload))?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        // a session that ends without the closing handshake still tells the client
+        if !self.close_sent {
+            let frame = CloseFrame {
+                code: CloseFrame::NORMAL,
+                reason: String::new(),
+            };
+            _ = self.write_control(Opcode::Close, &close_payload(Some(&frame)));
+        }
+    }
+}
+
+fn to_message(opcode: Opcode, payload: Vec<u8>) -> std::result::Result<Message, ProtocolError> {
+    match opcode {
+        Opcode::Text => String::from_utf8(payload)
+            .map(Message::Text)
+            .map_err(|_| ProtocolError::new(CloseFrame::INVALID_PAYLOAD, "Text is not UTF-8")),
+        _ => Ok(Message::Binary(payload)),
+    }
+}
+
+fn parse_close(payload: &[u8]) -> std::result::Result<Option<CloseFrame>, ProtocolError> {
+    match payload {
+        [] => Ok(None),
+        [_] => Err(ProtocolError::new(
+            CloseFrame::PROTOCOL_ERROR,
+            "Close frame without a full status code",
+        )),
+        [high, low, reason @ ..] => {
+            let reason = std::str::from_utf8(reason).map_err(|_| {
+                ProtocolError::new(CloseFrame::INVALID_PAYLOAD, "Close reason is not UTF-8")
+            })?;
+            Ok(Some(CloseFrame {
+                code: u16::from_be_bytes([*high, *low]),
+                reason: reason.to_string(),
+            }))
+        }
+    }
+}
+
+fn close_payload(frame: Option<&CloseFrame>) -> Vec<u8> {
+    let Some(frame) = frame else {
+        return Vec::new();
+    };
+    let mut payload = frame.code.to_be_bytes().to_vec();
+    payload.extend_from_slice(frame.reason.as_bytes());
+    payload
+}
+
+/// Parse one client frame out of `buffer`. Returns `None` until the whole
+/// frame has arrived, otherwise the frame and the number of bytes it used.
+fn parse_frame(
+    buffer: &[u8],
+    max_size: usize,
+) -> std::result::Result<Option<(Frame, usize)>, ProtocolError> {
+    let [first, second, ..] = *buffer else {
+        return Ok(None);
+    };
+
+    let fin = first & 0x80 != 0;
+    if first & 0x70 != 0 {
+        return Err(ProtocolError::new(
+            CloseFrame::PROTOCOL_ERROR,
+            "Reserved bits are set",
+        ));
+    }
+    let opcode = Opcode::from_bits(first & 0x0f)
+        .ok_or_else(|| ProtocolError::new(CloseFrame::PROTOCOL_ERROR, "Unknown opcode"))?;
+    if second & 0x80 == 0 {
+        return Err(ProtocolError::new(
+            CloseFrame::PROTOCOL_ERROR,
+            "Client frames must be masked",
+        ));
+    }
+
+    let (len, header_len) = match second & 0x7f {
+        126 => match buffer.get(2..4) {
+            Some(bytes) => (u16::from_be_bytes([bytes[0], bytes[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match buffer.get(2..10) {
+            Some(bytes) => (u64::from_be_bytes(bytes.try_into().expect("8 bytes")), 10),
+            None => return Ok(None),
+        },
+        len => (len as u64, 2),
+    };
+    if opcode.is_control() && (!fin || len > 125) {
+        return Err(ProtocolError::new(
+            CloseFrame::PROTOCOL_ERROR,
+            "Invalid control frame",
+        ));
+    }
+    if len > max_size as u64 {
+        return Err(ProtocolError::new(
+            CloseFrame::MESSAGE_TOO_BIG,
+            "Message too big",
+        ));
+    }
+
+    let len = len as usize;
+    let payload_start = header_len + 4;
+    let Some(payload) = buffer.get(payload_start..payload_start + len) else {
+        return Ok(None);
+    };
+    let mask = &buffer[header_len..payload_start];
+    let payload = payload
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+
+    Ok(Some((
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+        payload_start + len,
+    )))
+}
+
+/// Frames sent by the server are never masked
+fn encode_frame(opcode: Opcode, fin: bool, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(if fin { 0x80 } else { 0 } | opcode as u8);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // the example from RFC 6455 section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_handshake_is_validated() {
+        let mut req = Request::GET("/ws");
+        assert_eq!(handshake_key(&req).unwrap_err().status(), 426);
+
+        req.add_header("Upgrade", "websocket");
+        req.add_header("Connection", "keep-alive, Upgrade");
+        req.add_header("Sec-WebSocket-Version", "13");
+        assert_eq!(handshake_key(&req).unwrap_err().status(), 400);
+
+        req.add_header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(handshake_key(&req).unwrap(), "dGhlIHNhbXBsZSBub25jZQ==");
+    }
+
+    #[test]
+    fn test_parse_masked_frame() {
+        // a masked "Hello" from RFC 6455 section 5.7
+        let raw = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        assert!(parse_frame(&raw[..6], 1024).unwrap().is_none());
+
+        let (frame, used) = parse_frame(&raw, 1024).unwrap().unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, Opcode::Text);
+        assert_eq!(frame.payload, b"Hello");
+        assert_eq!(used, raw.len());
+
+        let too_big = parse_frame(&raw, 4).unwrap_err();
+        assert_eq!(too_big.code, CloseFrame::MESSAGE_TOO_BIG);
+    }
+
+    #[test]
+    fn test_unmasked_frame_is_rejected() {
+        let raw = [0x81, 0x05, b'H', b'e', b'l', b'l', b'o'];
+        let error = parse_frame(&raw, 1024).unwrap_err();
+        assert_eq!(error.code, CloseFrame::PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn test_encode_frame_lengths() {
+        assert_eq!(
+            encode_frame(Opcode::Text, true, b"Hi"),
+            [0x81, 0x02, b'H', b'i']
+        );
+
+        let frame = encode_frame(Opcode::Binary, false, &[0; 126]);
+        assert_eq!(frame[..4], [0x02, 126, 0, 126]);
+
+        let frame = encode_frame(Opcode::Binary, true, &[0; 65536]);
+        assert_eq!(frame[..10], [0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+    }
+}
@@ -0,0 +1,128 @@
+#![cfg(feature = "tokio")]
+
+use anyhow::Result;
+use common::TempDir;
+use crag_web::static_files::ServeDir;
+use crag_web::{async_server::Server, handler, request::Request, response::Response};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+mod common;
+
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[tokio::test]
+async fn test_async_server_over_tcp() -> Result<()> {
+    let server = Server::build()
+        .register_handler(Request::GET("/hello"), |_req| async {
+            Ok(Response::Ok("Hello, Crag-Web!"))
+        })
+        .register_handler(Request::POST("/echo", ""), |req| async move {
+            Ok(Response::Ok(req.body().len().to_string()))
+        })
+        .register_error_handler(|req| async { handler::default_error_404_handler(req) })?
+        .max_body_size(MAX_BODY_SIZE)
+        .finalize(("127.0.0.1", 12363))
+        .await?;
+    tokio::spawn(async move { server.run().await.unwrap() });
+
+    let r = reqwest::get("http://127.0.0.1:12363/hello").await?;
+    assert!(r.status().is_success());
+    assert_eq!(r.text().await?, "Hello, Crag-Web!");
+
+    // a head trickling in a byte at a time, then a large body in pieces
+    let body = vec![b'x'; 256 * 1024];
+    let head = format!(
+        "POST /echo HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    );
+    let mut stream = TcpStream::connect("127.0.0.1:12363").await?;
+    for byte in head.as_bytes() {
+        stream.write_all(&[*byte]).await?;
+    }
+    for piece in body.chunks(1000) {
+        stream.write_all(piece).await?;
+    }
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    assert!(response.ends_with(&body.len().to_string()), "{response}");
+
+    let mut stream = TcpStream::connect("127.0.0.1:12363").await?;
+    stream
+        .write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 999999999999\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+
+    // a chunked body that never ends is refused once it grows too large;
+    // the byte past the limit comes last, so all of it is read by then
+    let mut request = b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+    for _ in 0..MAX_BODY_SIZE / 1024 {
+        request.extend_from_slice(format!("400\r\n{}\r\n", "x".repeat(1024)).as_bytes());
+    }
+    request.extend_from_slice(b"1\r\nx");
+    let mut stream = TcpStream::connect("127.0.0.1:12363").await?;
+    stream.write_all(&request).await?;
+    let mut response = String::new();
+    tokio::time::timeout(
+        std::time::Duration::from_secs(10),
+        stream.read_to_string(&mut response),
+    )
+    .await??;
+    assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_async_server_streams_bodies_and_serves_mounts() -> Result<()> {
+    let dir = TempDir::new("async-bodies")?;
+    let path = dir.join("file.bin");
+    let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&path, &contents)?;
+    std::fs::create_dir(dir.join("public"))?;
+    std::fs::write(dir.join("public/blue.css"), "body { color: blue }")?;
+
+    let server = Server::build()
+        .mount("/static", ServeDir::new(dir.join("public")))
+        .register_handler(Request::GET("/file"), move |_req| {
+            let path = path.clone();
+            async move { Ok(Response::file(path)?) }
+        })
+        .register_handler(Request::GET("/chunks"), |_req| async {
+            Ok(Response::new(200)
+                .with_body_chunks(["hello", "", ", world"])
+                .with_trailers(|| vec![("X-Checksum".to_string(), "42".to_string())]))
+        })
+        .register_error_handler(|req| async { handler::default_error_404_handler(req) })?
+        .finalize(("127.0.0.1", 12364))
+        .await?;
+    tokio::spawn(async move { server.run().await.unwrap() });
+
+    let r = reqwest::get("http://127.0.0.1:12364/file").await?;
+    assert!(r.status().is_success());
+    assert_eq!(r.content_length(), Some(contents.len() as u64));
+    assert!(r.bytes().await? == contents[..]);
+
+    let r = reqwest::get("http://127.0.0.1:12364/static/blue.css").await?;
+    assert!(r.status().is_success());
+    assert_eq!(r.text().await?, "body { color: blue }");
+    let r = reqwest::get("http://127.0.0.1:12364/static/missing.css").await?;
+    assert_eq!(r.status(), 404);
+
+    let mut stream = TcpStream::connect("127.0.0.1:12364").await?;
+    stream.write_all(b"GET /chunks HTTP/1.1\r\n\r\n").await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    assert!(
+        response.contains("Transfer-Encoding: chunked\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.ends_with("\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\nX-Checksum: 42\r\n\r\n"),
+        "{response}"
+    );
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::Result;
+use crag_web::cache::ResponseCache;
+use crag_web::compression::Compression;
+use crag_web::{handler, request, response, server::Server};
+
+#[tokio::test]
+async fn test_cache() -> Result<()> {
+    let renders = Arc::new(AtomicUsize::new(0));
+    let cache = ResponseCache::new();
+    let invalidate = cache.clone();
+
+    let counter = renders.clone();
+    let server = Server::build()
+        .compression(Compression::new())
+        .cache(cache.clone())
+        .register_handler(request::Request::GET(String::from("/report")), move |req| {
+            let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            let page = format!("<p>render {n} of {}</p>\n", req.uri()).repeat(100);
+            Ok(response::Response::Ok(page).with_header("Cache-Control", "max-age=60"))
+        })
+        .register_handler(
+            request::Request::POST(String::from("/report"), String::new()),
+            move |_| {
+                invalidate.invalidate_prefix("/report");
+                Ok(response::Response::new(204))
+            },
+        )
+        .register_error_handler(handler::default_error_404_handler)?
+        .finalize(("127.0.0.1", 12362), 2)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let client = reqwest::Client::new();
+    let get = |path: &str| client.get(format!("http://127.0.0.1:12362{path}")).send();
+
+    let r = get("/report").await?;
+    assert_eq!(r.status(), 200);
+    assert!(r.headers().get("age").is_none());
+    let etag = r.headers()["etag"].to_str()?.to_string();
+    let first = r.text().await?;
+    assert!(first.starts_with("<p>render 1 of /report</p>"), "{first}");
+
+    // served from the cache, compressed like any other response
+    let r = get("/report").await?;
+    assert_eq!(r.headers()["age"], "0");
+    assert_eq!(r.text().await?, first);
+    let r = client
+        .get("http://127.0.0.1:12362/report")
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await?;
+    assert_eq!(r.headers()["content-encoding"], "gzip");
+    assert_eq!(renders.load(Ordering::SeqCst), 1);
+    let r = client
+        .get("http://127.0.0.1:12362/report")
+        .header("If-None-Match", &etag)
+        .send()
+        .await?;
+    assert_eq!(r.status(), 304);
+    assert_eq!(renders.load(Ordering::SeqCst), 1);
+
+    // the query is part of the key
+    let r = get("/report?month=5").await?;
+    assert!(r
+        .text()
+        .await?
+        .starts_with("<p>render 2 of /report?month=5</p>"));
+    assert_eq!(cache.len(), 2);
+
+    let r = client.post("http://127.0.0.1:12362/report").send().await?;
+    assert_eq!(r.status(), 204);
+    assert!(cache.is_empty());
+    let r = get("/report").await?;
+    assert!(r.text().await?.starts_with("<p>render 3 of /report</p>"));
+
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use std::fs;
+use std::io::Read;
+use std::thread;
+
+use anyhow::Result;
+use common::TempDir;
+use crag_web::compression::Compression;
+use crag_web::static_files::ServeDir;
+use crag_web::{handler, request, response, server::Server};
+
+mod common;
+
+#[tokio::test]
+async fn test_compression() -> Result<()> {
+    let root = TempDir::new("compression")?;
+    let script = "console.log('crag');\n".repeat(200);
+    fs::write(root.join("app.js"), &script)?;
+    fs::write(root.join("app.js.br"), b"precompressed br")?;
+    fs::write(root.join("app.js.gz"), b"precompressed gz")?;
+    fs::write(root.join("logo.png"), vec![0; 4096])?;
+
+    let css = "body { color: blue }\n".repeat(200);
+    let body = css.clone();
+    let server = Server::build()
+        .compression(Compression::new())
+        .mount("/static", ServeDir::new(&*root))
+        .register_handler(
+            request::Request::GET(String::from("/site.css")),
+            move |_| {
+                Ok(response::Response::new(200)
+                    .with_header("Content-Type", "text/css")
+                    .with_body(body.clone()))
+            },
+        )
+        .register_error_handler(handler::default_error_404_handler)?
+        .finalize(("127.0.0.1", 12358), 2)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let client = reqwest::Client::new();
+    let get = |path: &str, accept_encoding: &str| {
+        client
+            .get(format!("http://127.0.0.1:12358{path}"))
+            .header("Accept-Encoding", accept_encoding)
+            .send()
+    };
+
+    let r = get("/site.css", "gzip;q=0.5, br;q=0.8").await?;
+    assert_eq!(r.headers()["content-encoding"], "br");
+    assert_eq!(r.headers()["vary"], "Accept-Encoding");
+    let etag = r.headers()["etag"].to_str()?.to_string();
+    assert!(etag.starts_with("W/"), "{etag}");
+    let mut decompressed = String::new();
+    brotli::Decompressor::new(&r.bytes().await?[..], 4096).read_to_string(&mut decompressed)?;
+    assert_eq!(decompressed, css);
+
+    let r = get("/site.css", "gzip").await?;
+    assert_eq!(r.headers()["content-encoding"], "gzip");
+    let mut decompressed = String::new();
+    flate2::read::GzDecoder::new(&r.bytes().await?[..]).read_to_string(&mut decompressed)?;
+    assert_eq!(decompressed, css);
+
+    // revalidating the compressed response, which still says what it
+    // stands in for
+    let r = client
+        .get("http://127.0.0.1:12358/site.css")
+        .header("Accept-Encoding", "br")
+        .header("If-None-Match", &etag)
+        .send()
+        .await?;
+    assert_eq!(r.status(), 304);
+    assert_eq!(r.headers()["etag"], etag.as_str());
+    assert_eq!(r.headers()["vary"], "Accept-Encoding");
+
+    let r = get("/site.css", "gzip").await?;
+    let etag = r.headers()["etag"].to_str()?.to_string();
+    let r = client
+        .get("http://127.0.0.1:12358/site.css")
+        .header("Accept-Encoding", "gzip")
+        .header("If-None-Match", &etag)
+        .send()
+        .await?;
+    assert_eq!(r.status(), 304);
+    assert_eq!(r.headers()["etag"], etag.as_str());
+    assert!(etag.starts_with("W/"), "{etag}");
+    assert_eq!(r.headers()["vary"], "Accept-Encoding");
+    assert!(r.headers().get("content-encoding").is_none());
+
+    let r = get("/site.css", "identity").await?;
+    assert!(r.headers().get("content-encoding").is_none());
+    assert_eq!(r.headers()["vary"], "Accept-Encoding");
+    assert_eq!(r.text().await?, css);
+
+    // a range is cut from the uncompressed body, and resuming from the
+    // strong ETag of the whole works for clients that take compression
+    let r = get("/site.css", "identity").await?;
+    let strong = r.headers()["etag"].to_str()?.to_string();
+    assert!(!strong.starts_with("W/"), "{strong}");
+    let r = client
+        .get("http://127.0.0.1:12358/site.css")
+        .header("Accept-Encoding", "gzip")
+        .header("Range", "bytes=5-9")
+        .header("If-Range", &strong)
+        .send()
+        .await?;
+    assert_eq!(r.status(), 206);
+    assert_eq!(r.headers()["etag"], strong.as_str());
+    assert!(r.headers().get("content-encoding").is_none());
+    assert_eq!(r.text().await?, &css[5..10]);
+    let r = client
+        .get("http://127.0.0.1:12358/site.css")
+        .header("Accept-Encoding", "gzip")
+        .header("Range", "bytes=5-9")
+        // the representation changed, so the whole of it is sent
+        .header("If-Range", "\"stale\"")
+        .send()
+        .await?;
+    assert_eq!(r.status(), 200);
+    assert_eq!(r.headers()["content-encoding"], "gzip");
+
+    // precompressed files are sent as they are
+    let r = get("/static/app.js", "gzip, br").await?;
+    assert_eq!(r.headers()["content-encoding"], "br");
+    assert_eq!(
+        r.headers()["content-type"],
+        "text/javascript; charset=utf-8"
+    );
+    assert_eq!(r.headers()["vary"], "Accept-Encoding");
+    assert_eq!(r.text().await?, "precompressed br");
+    let r = get("/static/app.js", "gzip").await?;
+    assert_eq!(r.headers()["content-encoding"], "gzip");
+    assert_eq!(r.text().await?, "precompressed gz");
+    // deflate is only done on the fly
+    let r = get("/static/app.js", "deflate").await?;
+    assert_eq!(r.headers()["content-encoding"], "deflate");
+    let mut decompressed = String::new();
+    flate2::read::ZlibDecoder::new(&r.bytes().await?[..]).read_to_string(&mut decompressed)?;
+    assert_eq!(decompressed, script);
+
+    let r = get("/static/logo.png", "gzip, br").await?;
+    assert!(r.headers().get("content-encoding").is_none());
+    assert_eq!(r.bytes().await?.len(), 4096);
+
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use crag_web::{
+    handler, request, response,
+    server::{Backend, Server},
+};
+
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// A chunked body that never ends, with one byte more than `MAX_BODY_SIZE`
+/// in its chunks. The last byte is what makes it too large, so the server
+/// has read all of it by the time it answers.
+fn unterminated_chunked_request() -> Vec<u8> {
+    let mut request = b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+    for _ in 0..MAX_BODY_SIZE / 1024 {
+        request.extend_from_slice(b"400\r\n");
+        request.extend_from_slice(&[b'x'; 1024]);
+        request.extend_from_slice(b"\r\n");
+    }
+    request.extend_from_slice(b"1\r\nx");
+    request
+}
+
+#[tokio::test]
+async fn test_event_loop_backend() -> Result<()> {
+    let server = Server::build()
+        .backend(Backend::EventLoop)
+        .register_handler(request::Request::GET(String::from("/hello")), |_| {
+            Ok(response::Response::Ok("Hello, Crag-Web!".to_string()))
+        })
+        .register_handler(
+            request::Request::POST(String::from("/echo"), String::default()),
+            |req| Ok(response::Response::Ok(req.body().to_string())),
+        )
+        .register_handler(request::Request::GET(String::from("/error")), |_| {
+            Err(anyhow::anyhow!("error"))
+        })
+        .register_handler(request::Request::GET(String::from("/panic")), |_| {
+            panic!("handler failed")
+        })
+        .register_error_handler(handler::default_error_404_handler)?
+        .max_body_size(MAX_BODY_SIZE)
+        // a single pool thread: slow clients must not hold on to it
+        .finalize(("127.0.0.1", 12346), 1)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    // a client that sends half a request and then goes quiet
+    let mut slow = TcpStream::connect("127.0.0.1:12346")?;
+    slow.write_all(b"GET /hello HTTP/1.1\r\nHost: 127.0.0.1\r\n")?;
+
+    let r = reqwest::get("http://127.0.0.1:12346/hello").await?;
+    assert!(r.status().is_success());
+    assert_eq!(r.text().await?, "Hello, Crag-Web!");
+
+    let r = reqwest::Client::new()
+        .post("http://127.0.0.1:12346/echo")
+        .body("ping")
+        .send()
+        .await?;
+    assert!(r.status().is_success());
+    assert_eq!(r.text().await?, "ping");
+
+    // more than is read in one go, so the rest is read on a later turn
+    let body = "x".repeat(MAX_BODY_SIZE / 2);
+    let r = reqwest::Client::new()
+        .post("http://127.0.0.1:12346/echo")
+        .body(body.clone())
+        .send()
+        .await?;
+    assert!(r.status().is_success());
+    assert_eq!(r.text().await?, body);
+
+    let r = reqwest::get("http://127.0.0.1:12346/bad").await?;
+    assert!(r.status().is_client_error());
+
+    let r = reqwest::get("http://127.0.0.1:12346/error").await?;
+    assert!(r.status().is_server_error());
+
+    // a panicking handler still gets its connection answered
+    let r = reqwest::get("http://127.0.0.1:12346/panic").await?;
+    assert!(r.status().is_server_error());
+
+    // clients that close their side right after the request still get an
+    // answer, even when the request and the close are read together
+    let half_closed = (0..20)
+        .map(|_| {
+            let mut stream = TcpStream::connect("127.0.0.1:12346")?;
+            stream.write_all(b"GET /hello HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n")?;
+            stream.shutdown(Shutdown::Write)?;
+            Ok(stream)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for mut stream in half_closed {
+        let mut answer = String::new();
+        stream.read_to_string(&mut answer)?;
+        assert!(answer.starts_with("HTTP/1.1 200"), "{answer}");
+        assert!(answer.ends_with("Hello, Crag-Web!"), "{answer}");
+    }
+
+    // requests the client got wrong are answered as such
+    let mut too_large = TcpStream::connect("127.0.0.1:12346")?;
+    too_large.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 999999999999\r\n\r\n")?;
+    let mut answer = String::new();
+    too_large.read_to_string(&mut answer)?;
+    assert!(answer.starts_with("HTTP/1.1 413"), "{answer}");
+
+    // a chunked body is refused once it grows too large, without waiting
+    // for an end that never comes
+    let mut endless = TcpStream::connect("127.0.0.1:12346")?;
+    endless.set_read_timeout(Some(Duration::from_secs(10)))?;
+    endless.write_all(&unterminated_chunked_request())?;
+    let mut answer = String::new();
+    endless.read_to_string(&mut answer)?;
+    assert!(answer.starts_with("HTTP/1.1 413"), "{answer}");
+
+    let mut bad_length = TcpStream::connect("127.0.0.1:12346")?;
+    bad_length.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: -1\r\n\r\n")?;
+    let mut answer = String::new();
+    bad_length.read_to_string(&mut answer)?;
+    assert!(answer.starts_with("HTTP/1.1 400"), "{answer}");
+
+    // one byte past the limit, so all of it is read before the answer
+    let mut head = b"GET /hello HTTP/1.1\r\nX-Filler: ".to_vec();
+    head.resize(64 * 1024 + 1, b'a');
+    let mut too_long = TcpStream::connect("127.0.0.1:12346")?;
+    too_long.write_all(&head)?;
+    let mut answer = String::new();
+    too_long.read_to_string(&mut answer)?;
+    assert!(answer.starts_with("HTTP/1.1 431"), "{answer}");
+
+    // the slow client still gets its answer once it finishes the request
+    slow.write_all(b"\r\n")?;
+    let mut answer = String::new();
+    slow.read_to_string(&mut answer)?;
+    assert!(answer.ends_with("Hello, Crag-Web!"), "{answer}");
+
+    Ok(())
+}
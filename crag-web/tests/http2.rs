@@ -0,0 +1,169 @@
+#![cfg(feature = "http2")]
+
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use crag_web::{handler, request, response, server::Server};
+use reqwest::Version;
+
+#[tokio::test]
+async fn test_h2c_prior_knowledge() -> Result<()> {
+    // both requests have to be in flight at once to get past the barrier
+    let barrier = Arc::new(Barrier::new(2));
+    let server = Server::build()
+        .register_handler(request::Request::GET(String::from("/hello")), |_| {
+            Ok(response::Response::Ok("Hello, Crag-Web!".to_string()))
+        })
+        .register_handler(
+            request::Request::GET(String::from("/together")),
+            move |_| {
+                barrier.wait();
+                Ok(response::Response::Ok("together".to_string()))
+            },
+        )
+        .register_handler(
+            request::Request::POST(String::from("/echo"), String::default()),
+            |req| Ok(response::Response::Ok(req.body().to_string())),
+        )
+        .register_handler(request::Request::GET(String::from("/error")), |_| {
+            Err(anyhow::anyhow!("error"))
+        })
+        .register_streaming_handler(
+            request::Request::POST(String::from("/upload"), String::default()),
+            |_, mut body| {
+                let announced = body.content_length();
+                let received = std::io::copy(&mut body, &mut std::io::sink())?;
+                Ok(response::Response::Ok(format!(
+                    "{received} of {announced:?}"
+                )))
+            },
+        )
+        .register_handler(request::Request::GET(String::from("/stream")), |_| {
+            // bigger than the default flow control window
+            let chunks = (0..100).map(|_| vec![b'x'; 1024]);
+            Ok(response::Response::new(200).with_body_chunks(chunks))
+        })
+        .register_handler(request::Request::GET(String::from("/seek")), |_| {
+            let digits = std::io::Cursor::new(b"0123456789".to_vec());
+            Ok(response::Response::new(200).with_body_seekable(digits)?)
+        })
+        .register_error_handler(handler::default_error_404_handler)?
+        .finalize(("127.0.0.1", 12350), 2)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let client = reqwest::Client::builder()
+        .http2_prior_knowledge()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+
+    let r = client.get("http://127.0.0.1:12350/hello").send().await?;
+    assert_eq!(r.version(), Version::HTTP_2);
+    assert!(r.status().is_success());
+    assert_eq!(r.text().await?, "Hello, Crag-Web!");
+
+    // multiplexed over the one connection the client keeps
+    let (a, b) = tokio::join!(
+        client.get("http://127.0.0.1:12350/together").send(),
+        client.get("http://127.0.0.1:12350/together").send(),
+    );
+    assert_eq!(a?.text().await?, "together");
+    assert_eq!(b?.text().await?, "together");
+
+    let r = client
+        .post("http://127.0.0.1:12350/echo")
+        .body("ping")
+        .send()
+        .await?;
+    assert_eq!(r.text().await?, "ping");
+
+    let r = client.get("http://127.0.0.1:12350/stream").send().await?;
+    assert_eq!(r.version(), Version::HTTP_2);
+    assert!(r.headers().get("content-length").is_none());
+    assert!(r.headers().get("transfer-encoding").is_none());
+    assert_eq!(r.bytes().await?, vec![b'x'; 100 * 1024]);
+
+    let r = client
+        .get("http://127.0.0.1:12350/seek")
+        .header("Range", "bytes=-4")
+        .send()
+        .await?;
+    assert_eq!(r.status(), 206);
+    assert_eq!(r.headers()["content-length"], "4");
+    assert_eq!(r.headers()["content-range"], "bytes 6-9/10");
+    assert_eq!(r.text().await?, "6789");
+
+    // larger than the stream's flow control window
+    let r = client
+        .post("http://127.0.0.1:12350/upload")
+        .body(vec![0; 1024 * 1024])
+        .send()
+        .await?;
+    assert_eq!(r.version(), Version::HTTP_2);
+    assert_eq!(r.text().await?, "1048576 of Some(1048576)");
+
+    let r = client.get("http://127.0.0.1:12350/bad").send().await?;
+    assert!(r.status().is_client_error());
+
+    let r = client.get("http://127.0.0.1:12350/error").send().await?;
+    assert!(r.status().is_server_error());
+
+    // HTTP/1.1 clients are still served on the same port
+    let r = reqwest::get("http://127.0.0.1:12350/hello").await?;
+    assert_eq!(r.version(), Version::HTTP_11);
+    assert_eq!(r.text().await?, "Hello, Crag-Web!");
+
+    Ok(())
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn test_h2_over_tls() -> Result<()> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+    let server = Server::build()
+        .tls(cert.cert.pem(), cert.signing_key.serialize_pem())?
+        .register_handler(request::Request::GET(String::from("/hello")), |_| {
+            Ok(response::Response::Ok("Hello, Crag-Web!".to_string()))
+        })
+        .register_error_handler(handler::default_error_404_handler)?
+        .finalize(("127.0.0.1", 12351), 2)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let client = |http1_only: bool| -> Result<reqwest::Client> {
+        let builder = reqwest::Client::builder()
+            .use_rustls_tls()
+            .tls_built_in_root_certs(false)
+            .add_root_certificate(reqwest::Certificate::from_der(cert.cert.der())?)
+            .resolve("localhost", ([127, 0, 0, 1], 12351).into());
+        let builder = if http1_only {
+            builder.http1_only()
+        } else {
+            builder
+        };
+        Ok(builder.build()?)
+    };
+
+    // h2 is picked through ALPN
+    let r = client(false)?
+        .get("https://localhost:12351/hello")
+        .send()
+        .await?;
+    assert_eq!(r.version(), Version::HTTP_2);
+    assert_eq!(r.text().await?, "Hello, Crag-Web!");
+
+    let r = client(true)?
+        .get("https://localhost:12351/hello")
+        .send()
+        .await?;
+    assert_eq!(r.version(), Version::HTTP_11);
+    assert_eq!(r.text().await?, "Hello, Crag-Web!");
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use std::fs;
+use std::thread;
+
+use anyhow::Result;
+use common::TempDir;
+use crag_web::static_files::ServeDir;
+use crag_web::{handler, server::Server};
+
+mod common;
+
+/// Large enough to be streamed from the file rather than read into memory
+const CLIP_SIZE: usize = 3 * 1024 * 1024 / 2;
+
+#[tokio::test]
+async fn test_range_requests() -> Result<()> {
+    let root = TempDir::new("ranges")?;
+    let clip: Vec<u8> = (0..CLIP_SIZE).map(|i| (i % 251) as u8).collect();
+    fs::write(root.join("clip.mp4"), &clip)?;
+
+    let server = Server::build()
+        .mount("/clips", ServeDir::new(&*root))
+        .register_error_handler(handler::default_error_404_handler)?
+        .finalize(("127.0.0.1", 12357), 2)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let client = reqwest::Client::new();
+    let get = |range: &str| {
+        client
+            .get("http://127.0.0.1:12357/clips/clip.mp4")
+            .header("Range", range)
+            .send()
+    };
+
+    let r = client
+        .get("http://127.0.0.1:12357/clips/clip.mp4")
+        .send()
+        .await?;
+    assert_eq!(r.status(), 200);
+    assert_eq!(r.headers()["accept-ranges"], "bytes");
+    assert_eq!(
+        r.headers()["content-length"],
+        CLIP_SIZE.to_string().as_str()
+    );
+    let last_modified = r.headers()["last-modified"].to_str()?.to_string();
+    assert_eq!(r.bytes().await?, clip);
+
+    // resuming a download
+    let r = get("bytes=1048576-").await?;
+    assert_eq!(r.status(), 206);
+    assert_eq!(
+        r.headers()["content-range"],
+        format!("bytes 1048576-{}/{CLIP_SIZE}", CLIP_SIZE - 1).as_str()
+    );
+    assert_eq!(r.headers()["content-type"], "video/mp4");
+    assert_eq!(r.bytes().await?, clip[1048576..]);
+
+    let r = get("bytes=-10").await?;
+    assert_eq!(r.status(), 206);
+    assert_eq!(r.bytes().await?, clip[CLIP_SIZE - 10..]);
+
+    let r = get("bytes=0-3, 1000-1001").await?;
+    assert_eq!(r.status(), 206);
+    let content_type = r.headers()["content-type"].to_str()?.to_string();
+    let boundary = content_type
+        .strip_prefix("multipart/byteranges; boundary=")
+        .expect(&content_type);
+    let mut expected = format!(
+        "--{boundary}\r\nContent-Type: video/mp4\r\nContent-Range: bytes 0-3/{CLIP_SIZE}\r\n\r\n"
+    )
+    .into_bytes();
+    expected.extend_from_slice(&clip[0..4]);
+    expected.extend_from_slice(
+        format!(
+            "\r\n--{boundary}\r\nContent-Type: video/mp4\r\nContent-Range: bytes 1000-1001/{CLIP_SIZE}\r\n\r\n"
+        )
+        .as_bytes(),
+    );
+    expected.extend_from_slice(&clip[1000..1002]);
+    expected.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    assert_eq!(r.bytes().await?, expected);
+
+    let r = get(&format!("bytes={CLIP_SIZE}-")).await?;
+    assert_eq!(r.status(), 416);
+    assert_eq!(
+        r.headers()["content-range"],
+        format!("bytes */{CLIP_SIZE}").as_str()
+    );
+
+    // only resumed if the file is still the one the client has a part of
+    let r = client
+        .get("http://127.0.0.1:12357/clips/clip.mp4")
+        .header("Range", "bytes=0-9")
+        .header("If-Range", &last_modified)
+        .send()
+        .await?;
+    assert_eq!(r.status(), 206);
+    let r = client
+        .get("http://127.0.0.1:12357/clips/clip.mp4")
+        .header("Range", "bytes=0-9")
+        .header("If-Range", "Sun, 06 Nov 1994 08:49:37 GMT")
+        .send()
+        .await?;
+    assert_eq!(r.status(), 200);
+    assert_eq!(r.bytes().await?.len(), CLIP_SIZE);
+
+    Ok(())
+}
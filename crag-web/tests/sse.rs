@@ -0,0 +1,86 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use crag_web::sse::{Event, EventStream};
+use crag_web::{handler, request, server::Server};
+
+fn get(path: &str, headers: &str) -> Result<String> {
+    let mut stream = TcpStream::connect("127.0.0.1:12354")?;
+    stream.write_all(format!("GET {path} HTTP/1.1\r\n{headers}\r\n").as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[test]
+fn test_event_stream() -> Result<()> {
+    let server = Server::build()
+        .register_handler(request::Request::GET(String::from("/events")), |req| {
+            let (events, response) = EventStream::new(&req).open();
+            // the handler pushes the first event itself, a producer the rest
+            events.send(Event::new("hello").with_event("greeting"))?;
+            thread::spawn(move || {
+                let last = events
+                    .last_event_id()
+                    .and_then(|id| id.parse().ok())
+                    .unwrap_or(0);
+                for id in last + 1..=last + 3 {
+                    let event = Event::new(format!("tick {id}")).with_id(id.to_string());
+                    if events.send(event).is_err() {
+                        break;
+                    }
+                }
+            });
+            Ok(response)
+        })
+        .register_handler(request::Request::GET(String::from("/quiet")), |req| {
+            let (events, response) = EventStream::new(&req)
+                .heartbeat(Some(Duration::from_millis(20)))
+                .retry(Duration::from_secs(3))
+                .open();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                drop(events);
+            });
+            Ok(response)
+        })
+        .register_error_handler(handler::default_error_404_handler)?
+        .finalize(("127.0.0.1", 12354), 1)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let response = get("/events", "")?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(
+        response.contains("Content-Type: text/event-stream\r\n"),
+        "{response}"
+    );
+    assert!(!response.contains("Content-Length"), "{response}");
+    assert!(
+        response.ends_with(
+            "\r\n\r\nevent: greeting\ndata: hello\n\n\
+             id: 1\ndata: tick 1\n\nid: 2\ndata: tick 2\n\nid: 3\ndata: tick 3\n\n"
+        ),
+        "{response}"
+    );
+
+    // a reconnecting client resumes after the last event it saw
+    let response = get("/events", "Last-Event-ID: 2\r\n")?;
+    assert!(
+        response.ends_with("id: 3\ndata: tick 3\n\nid: 4\ndata: tick 4\n\nid: 5\ndata: tick 5\n\n"),
+        "{response}"
+    );
+
+    let response = get("/quiet", "")?;
+    assert!(
+        response.contains("\r\n\r\nretry: 3000\n\n:\n\n"),
+        "{response}"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,377 @@
+use std::fs;
+use std::thread;
+
+use anyhow::Result;
+use common::TempDir;
+use crag_web::embed::{EmbeddedDir, EmbeddedFile};
+use crag_web::static_files::ServeDir;
+use crag_web::{handler, request, response, server::Server};
+use reqwest::redirect::Policy;
+
+mod common;
+
+/// A site to serve, fresh for every run
+fn site() -> Result<TempDir> {
+    let root = TempDir::new("static")?;
+    fs::create_dir_all(root.join("public/css"))?;
+    fs::create_dir_all(root.join("public/docs"))?;
+    fs::write(root.join("public/index.html"), "<h1>home</h1>")?;
+    fs::write(root.join("public/css/blue.css"), "body { color: blue }")?;
+    fs::write(root.join("public/docs/index.html"), "<h1>docs</h1>")?;
+    fs::write(root.join("public/my notes.txt"), "notes")?;
+    fs::write(root.join("secret.txt"), "secret")?;
+    Ok(root)
+}
+
+#[tokio::test]
+async fn test_serve_dir() -> Result<()> {
+    let root = site()?;
+    let server = Server::build()
+        .mount(
+            "/",
+            ServeDir::new(root.join("public")).index_file("index.html"),
+        )
+        .mount("/assets", ServeDir::new(root.join("public/css")))
+        .register_handler(request::Request::GET(String::from("/docs/api")), |_| {
+            Ok(response::Response::Ok("generated".to_string()))
+        })
+        .register_error_handler(handler::default_error_404_handler)?
+        .finalize(("127.0.0.1", 12356), 2)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let client = reqwest::Client::builder()
+        .redirect(Policy::none())
+        .build()?;
+    let get = |path: &str| client.get(format!("http://127.0.0.1:12356{path}")).send();
+
+    let r = get("/").await?;
+    assert!(r.status().is_success());
+    assert_eq!(r.headers()["content-type"], "text/html; charset=utf-8");
+    assert_eq!(r.text().await?, "<h1>home</h1>");
+
+    let r = get("/css/blue.css").await?;
+    assert_eq!(r.headers()["content-type"], "text/css; charset=utf-8");
+    assert_eq!(r.text().await?, "body { color: blue }");
+
+    // the longer prefix wins
+    let r = get("/assets/blue.css").await?;
+    assert_eq!(r.text().await?, "body { color: blue }");
+
+    let r = get("/my%20notes.txt").await?;
+    assert_eq!(r.text().await?, "notes");
+
+    // directories get their index, once the path ends with a slash
+    let r = get("/docs?page=2").await?;
+    assert_eq!(r.status(), 301);
+    assert_eq!(r.headers()["location"], "/docs/?page=2");
+    let r = get("/docs/").await?;
+    assert_eq!(r.text().await?, "<h1>docs</h1>");
+
+    // registered handlers take precedence over files
+    let r = get("/docs/api").await?;
+    assert_eq!(r.text().await?, "generated");
+
+    let r = get("/missing.css").await?;
+    assert_eq!(r.status(), 404);
+    // a path that goes on past a file
+    let r = get("/css/blue.css/x").await?;
+    assert_eq!(r.status(), 404);
+    let r = get("/assets/").await?;
+    assert_eq!(r.status(), 404);
+
+    // no way out of the root, however the path is spelled
+    for path in [
+        "/../secret.txt",
+        "/%2e%2e/secret.txt",
+        "/css/..%2f..%2fsecret.txt",
+    ] {
+        let r = get(path).await?;
+        assert_eq!(r.status(), 404, "{path}");
+    }
+
+    // revalidating what the client has cached
+    let r = get("/css/blue.css").await?;
+    let etag = r.headers()["etag"].to_str()?.to_string();
+    let last_modified = r.headers()["last-modified"].to_str()?.to_string();
+    assert!(etag.starts_with("W/\""), "{etag}");
+    let r = client
+        .get("http://127.0.0.1:12356/css/blue.css")
+        .header("If-None-Match", &etag)
+        .send()
+        .await?;
+    assert_eq!(r.status(), 304);
+    assert_eq!(r.headers()["etag"], etag.as_str());
+    assert!(r.headers().get("content-length").is_none());
+    assert_eq!(r.text().await?, "");
+    let r = client
+        .get("http://127.0.0.1:12356/css/blue.css")
+        .header("If-Modified-Since", &last_modified)
+        .send()
+        .await?;
+    assert_eq!(r.status(), 304);
+    let r = client
+        .get("http://127.0.0.1:12356/css/blue.css")
+        .header("If-None-Match", "W/\"stale\"")
+        .header("If-Modified-Since", &last_modified)
+        .send()
+        .await?;
+    assert_eq!(r.status(), 200);
+    let r = client
+        .get("http://127.0.0.1:12356/css/blue.css")
+        .header("If-Unmodified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")
+        .send()
+        .await?;
+    assert_eq!(r.status(), 412);
+
+    // handler responses get a strong ETag from their body
+    let r = get("/docs/api").await?;
+    let etag = r.headers()["etag"].to_str()?.to_string();
+    assert!(etag.starts_with('"'), "{etag}");
+    let r = client
+        .get("http://127.0.0.1:12356/docs/api")
+        .header("If-None-Match", &etag)
+        .send()
+        .await?;
+    assert_eq!(r.status(), 304);
+    let r = client
+        .get("http://127.0.0.1:12356/docs/api")
+        .header("If-Match", "\"something else\"")
+        .send()
+        .await?;
+    assert_eq!(r.status(), 412);
+
+    // only GET requests are served
+    let r = client
+        .post("http://127.0.0.1:12356/css/blue.css")
+        .send()
+        .await?;
+    assert_eq!(r.status(), 404);
+
+    Ok(())
+}
+
+/// What `embed::Embed` would generate for a small site
+static EMBEDDED: EmbeddedDir = EmbeddedDir {
+    files: &[
+        EmbeddedFile {
+            path: "docs/index.html",
+            contents: b"<h1>docs</h1>",
+            content_type: "text/html; charset=utf-8",
+            etag: "\"docs\"",
+            brotli: None,
+            gzip: None,
+        },
+        EmbeddedFile {
+            path: "site.css",
+            contents: b"body { color: blue }",
+            content_type: "text/css; charset=utf-8",
+            etag: "\"css\"",
+            brotli: Some(b"brotli bytes"),
+            gzip: Some(b"gzip bytes"),
+        },
+    ],
+};
+
+#[tokio::test]
+async fn test_serve_embedded() -> Result<()> {
+    let server = Server::build()
+        .mount("/", ServeDir::embedded(&EMBEDDED).index_file("index.html"))
+        .register_error_handler(handler::default_error_404_handler)?
+        .finalize(("127.0.0.1", 12359), 2)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let client = reqwest::Client::builder()
+        .redirect(Policy::none())
+        .build()?;
+    let get = |path: &str, accept_encoding: &str| {
+        client
+            .get(format!("http://127.0.0.1:12359{path}"))
+            .header("Accept-Encoding", accept_encoding)
+            .send()
+    };
+
+    let r = get("/site.css", "identity").await?;
+    assert_eq!(r.headers()["content-type"], "text/css; charset=utf-8");
+    assert_eq!(r.headers()["etag"], "\"css\"");
+    assert_eq!(r.headers()["vary"], "Accept-Encoding");
+    assert_eq!(r.text().await?, "body { color: blue }");
+
+    let r = get("/site.css", "gzip, br").await?;
+    assert_eq!(r.headers()["content-encoding"], "br");
+    assert_eq!(r.headers()["etag"], "\"css-br\"");
+    assert_eq!(r.text().await?, "brotli bytes");
+
+    let r = client
+        .get("http://127.0.0.1:12359/site.css")
+        .header("If-None-Match", "\"css\"")
+        .send()
+        .await?;
+    assert_eq!(r.status(), 304);
+
+    let r = get("/docs", "identity").await?;
+    assert_eq!(r.status(), 301);
+    assert_eq!(r.headers()["location"], "/docs/");
+    let r = get("/docs/", "identity").await?;
+    assert_eq!(r.text().await?, "<h1>docs</h1>");
+
+    for path in ["/missing.css", "/doc", "/", "/docs/..%2fsite.css"] {
+        let r = get(path, "identity").await?;
+        assert_eq!(r.status(), 404, "{path}");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_directory_listing() -> Result<()> {
+    let root = TempDir::new("listing")?;
+    fs::create_dir_all(root.join("artifacts/nightly"))?;
+    fs::create_dir_all(root.join("artifacts/site"))?;
+    fs::write(root.join("artifacts/build.log"), "ok")?;
+    fs::write(root.join("artifacts/<b>&.txt"), "escaped")?;
+    fs::write(root.join("artifacts/crag.tar"), vec![0; 2048])?;
+    fs::write(root.join("artifacts/.secret"), "hidden")?;
+    fs::write(root.join("artifacts/nightly/crag.tar"), "tar")?;
+    fs::write(root.join("artifacts/site/index.html"), "<h1>site</h1>")?;
+
+    let server = Server::build()
+        .mount(
+            "/builds",
+            ServeDir::new(root.join("artifacts"))
+                .index_file("index.html")
+                .list_directories(true),
+        )
+        .register_error_handler(handler::default_error_404_handler)?
+        .finalize(("127.0.0.1", 12360), 2)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let client = reqwest::Client::builder()
+        .redirect(Policy::none())
+        .build()?;
+    let get = |path: &str| client.get(format!("http://127.0.0.1:12360{path}")).send();
+
+    let r = get("/builds").await?;
+    assert_eq!(r.status(), 301);
+    assert_eq!(r.headers()["location"], "/builds/");
+
+    let r = get("/builds/").await?;
+    assert_eq!(r.status(), 200);
+    assert_eq!(r.headers()["content-type"], "text/html; charset=utf-8");
+    let page = r.text().await?;
+    assert!(page.contains("<a href=\"nightly/\">nightly/</a>"), "{page}");
+    assert!(
+        page.contains("<a href=\"%3Cb%3E%26.txt\">&lt;b&gt;&amp;.txt</a>"),
+        "{page}"
+    );
+    assert!(page.contains("<td class=\"size\">2.0 KiB</td>"), "{page}");
+    assert!(
+        page.contains("<a href=\"?sort=size&amp;order=asc\">Size</a>"),
+        "{page}"
+    );
+    assert!(!page.contains("secret"), "{page}");
+    // the root of the mount has no parent to go to
+    assert!(!page.contains("../"), "{page}");
+    // directories first, then by name
+    let position = |name: &str| page.find(name).unwrap();
+    assert!(position(">nightly/<") < position(">site/<"));
+    assert!(position(">site/<") < position(">&lt;b&gt;"));
+    assert!(position(">build.log<") < position(">crag.tar<"));
+
+    let r = get("/builds/nightly/").await?;
+    assert!(r.text().await?.contains("<a href=\"../\">../</a>"));
+
+    // an index file still takes precedence
+    let r = get("/builds/site/").await?;
+    assert_eq!(r.text().await?, "<h1>site</h1>");
+
+    let r = get("/builds/?format=json&sort=size&order=desc").await?;
+    assert_eq!(r.headers()["content-type"], "application/json");
+    let listing = r.text().await?;
+    assert!(
+        listing.starts_with("{\"path\":\"/builds/\",\"entries\":["),
+        "{listing}"
+    );
+    let names: Vec<&str> = listing
+        .match_indices("\"name\":\"")
+        .map(|(i, prefix)| {
+            let name = &listing[i + prefix.len()..];
+            &name[..name.find('"').unwrap()]
+        })
+        .collect();
+    assert_eq!(
+        names,
+        ["site", "nightly", "crag.tar", "<b>&.txt", "build.log"]
+    );
+    assert!(listing.contains("\"name\":\"crag.tar\",\"type\":\"file\",\"size\":2048,\"modified\":"));
+
+    let r = client
+        .get("http://127.0.0.1:12360/builds/nightly/")
+        .header("Accept", "application/json")
+        .send()
+        .await?;
+    assert!(r.text().await?.contains("\"name\":\"crag.tar\""));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fallback_file() -> Result<()> {
+    let root = TempDir::new("fallback")?;
+    fs::create_dir_all(root.join("dist/assets"))?;
+    fs::write(root.join("dist/index.html"), "<div id=\"app\"></div>")?;
+    fs::write(root.join("dist/assets/app.js"), "render()")?;
+
+    let server = Server::build()
+        .mount(
+            "/app",
+            ServeDir::new(root.join("dist"))
+                .index_file("index.html")
+                .fallback_file("index.html"),
+        )
+        .register_error_handler(handler::default_error_404_handler)?
+        .finalize(("127.0.0.1", 12361), 2)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let get = |path: &str| reqwest::get(format!("http://127.0.0.1:12361{path}"));
+
+    for path in [
+        "/app/",
+        "/app/users/42",
+        "/app/settings/profile/",
+        "/app/assets",
+        "/app/assets/app.js/edit",
+    ] {
+        let r = get(path).await?;
+        assert_eq!(r.status(), 200, "{path}");
+        assert_eq!(r.headers()["content-type"], "text/html; charset=utf-8");
+        assert_eq!(r.text().await?, "<div id=\"app\"></div>", "{path}");
+    }
+
+    let r = get("/app/assets/app.js").await?;
+    assert_eq!(r.status(), 200);
+    assert_eq!(r.text().await?, "render()");
+
+    // missing assets are still missing
+    for path in [
+        "/app/assets/missing.js",
+        "/app/users/42.json",
+        "/app/..%2fsecret",
+    ] {
+        assert_eq!(get(path).await?.status(), 404, "{path}");
+    }
+    // and so is everything outside the mount
+    assert_eq!(get("/other/route").await?.status(), 404);
+
+    Ok(())
+}
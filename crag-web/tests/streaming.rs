@@ -0,0 +1,160 @@
+use std::io::{Cursor, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+use anyhow::Result;
+use crag_web::{handler, request, response, server::Server};
+
+fn get(path: &str) -> Result<String> {
+    send(&format!("GET {path} HTTP/1.1\r\n\r\n"))
+}
+
+fn send(request: &str) -> Result<String> {
+    let mut stream = TcpStream::connect("127.0.0.1:12355")?;
+    stream.write_all(request.as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[tokio::test]
+async fn test_streamed_bodies() -> Result<()> {
+    let server = Server::build()
+        .register_handler(request::Request::GET(String::from("/report.csv")), |_| {
+            let rows = (1..=3).map(|n| format!("{n},{}\n", n * n));
+            Ok(response::Response::new(200)
+                .with_header("Content-Type", "text/csv")
+                .with_body_chunks(rows)
+                .with_trailers(|| vec![("X-Rows".to_string(), "3".to_string())]))
+        })
+        .register_handler(request::Request::GET(String::from("/reader")), |_| {
+            let data = "crag".repeat(10_000);
+            Ok(response::Response::new(200).with_body_reader(Cursor::new(data)))
+        })
+        .register_handler(request::Request::GET(String::from("/broken")), |_| {
+            let chunks = ["partial".to_string()]
+                .into_iter()
+                .map(Ok)
+                .chain([Err(std::io::Error::other("source went away"))]);
+            Ok(response::Response::new(200).with_body_reader(FromChunks(chunks)))
+        })
+        .register_handler(
+            request::Request::POST(String::from("/upload"), String::default()),
+            |req| {
+                let checksum = req.trailer("X-Checksum").unwrap_or("none");
+                Ok(response::Response::Ok(format!("{} {checksum}", req.body())))
+            },
+        )
+        .register_streaming_handler(
+            request::Request::POST(String::from("/artifacts"), String::default()),
+            |_, mut body| {
+                // counted as it arrives, nothing is held on to
+                let mut size = 0;
+                let mut sum = 0u64;
+                let mut buf = [0; 8192];
+                loop {
+                    let n = body.read(&mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    size += n;
+                    sum += buf[..n].iter().map(|&b| u64::from(b)).sum::<u64>();
+                }
+                let checksum = body.trailer("X-Checksum").unwrap_or("none");
+                Ok(response::Response::Ok(format!("{size} {sum} {checksum}")))
+            },
+        )
+        .register_error_handler(handler::default_error_404_handler)?
+        .max_body_size(64)
+        .finalize(("127.0.0.1", 12355), 1)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let response = get("/report.csv")?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(
+        response.contains("Transfer-Encoding: chunked\r\n"),
+        "{response}"
+    );
+    assert!(!response.contains("Content-Length"), "{response}");
+    assert!(
+        response
+            .ends_with("\r\n\r\n4\r\n1,1\n\r\n4\r\n2,4\n\r\n4\r\n3,9\n\r\n0\r\nX-Rows: 3\r\n\r\n"),
+        "{response}"
+    );
+
+    let r = reqwest::get("http://127.0.0.1:12355/reader").await?;
+    assert!(r.status().is_success());
+    assert_eq!(r.text().await?, "crag".repeat(10_000));
+
+    // a failing source leaves the body unterminated instead of ending it
+    // as if it was complete
+    let response = get("/broken")?;
+    assert!(response.ends_with("\r\n\r\n7\r\npartial\r\n"), "{response}");
+
+    // an upload from a client that didn't know its length up front
+    let response = send(
+        "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+         5;ext=1\r\nhello\r\n0\r\nX-Checksum: 42\r\n\r\n",
+    )?;
+    assert!(response.ends_with("\r\n\r\nhello 42"), "{response}");
+
+    let too_large = format!(
+        "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n41\r\n{}\r\n0\r\n\r\n",
+        "x".repeat(0x41)
+    );
+    let too_large = send(&too_large)?;
+    assert!(too_large.starts_with("HTTP/1.1 413"), "{too_large}");
+
+    // the way requests get smuggled past proxies
+    let smuggled = send(
+        "POST /upload HTTP/1.1\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n\
+         0\r\n\r\nGET /report.csv HTTP/1.1\r\n\r\n",
+    )?;
+    assert!(smuggled.starts_with("HTTP/1.1 400"), "{smuggled}");
+
+    // streamed bodies aren't held to the limit of buffered ones
+    let artifact = vec![3u8; 1024 * 1024];
+    let r = reqwest::Client::new()
+        .post("http://127.0.0.1:12355/artifacts")
+        .body(artifact)
+        .send()
+        .await?;
+    assert_eq!(
+        r.text().await?,
+        format!("{} {} none", 1024 * 1024, 3 * 1024 * 1024)
+    );
+
+    let mut stream = TcpStream::connect("127.0.0.1:12355")?;
+    stream.write_all(
+        b"POST /artifacts HTTP/1.1\r\nTransfer-Encoding: chunked\r\nExpect: 100-continue\r\n\r\n",
+    )?;
+    // the body is only sent once the server asked for it
+    let mut interim = [0; 25];
+    stream.read_exact(&mut interim)?;
+    assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+    stream.write_all(b"3\r\n\x01\x02\x03\r\n0\r\nX-Checksum: 6\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.ends_with("\r\n\r\n3 6 6"), "{response}");
+
+    Ok(())
+}
+
+/// A reader over chunks that may fail
+struct FromChunks<I>(I);
+
+impl<I: Iterator<Item = std::io::Result<String>>> Read for FromChunks<I> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.0.next() {
+            Some(chunk) => {
+                let chunk = chunk?;
+                buf[..chunk.len()].copy_from_slice(chunk.as_bytes());
+                Ok(chunk.len())
+            }
+            None => Ok(0),
+        }
+    }
+}
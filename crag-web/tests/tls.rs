@@ -0,0 +1,227 @@
+#![cfg(feature = "tls")]
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use crag_web::tls::{ClientAuth, SubjectAltName};
+use crag_web::{handler, request, response, server::Server};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+
+fn self_signed(name: &str) -> Result<rcgen::CertifiedKey<rcgen::KeyPair>> {
+    Ok(rcgen::generate_simple_self_signed(vec![name.to_string()])?)
+}
+
+/// Send a GET over TLS, trusting only `trusted`, and return the raw response
+fn https_get(
+    port: u16,
+    server_name: &str,
+    path: &str,
+    trusted: &CertificateDer<'static>,
+    client_cert: Option<(CertificateDer<'static>, PrivateKeyDer<'static>)>,
+) -> Result<String> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(trusted.clone())?;
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_root_certificates(roots);
+    let config = match client_cert {
+        Some((cert, key)) => config.with_client_auth_cert(vec![cert], key)?,
+        None => config.with_no_client_auth(),
+    };
+
+    let name = ServerName::try_from(server_name.to_string())?;
+    let conn = rustls::ClientConnection::new(Arc::new(config), name)?;
+    let mut stream = rustls::StreamOwned::new(conn, TcpStream::connect(("127.0.0.1", port))?);
+
+    stream.write_all(format!("GET {path} HTTP/1.1\r\n\r\n").as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[test]
+fn test_tls_with_sni() -> Result<()> {
+    let default_cert = self_signed("localhost")?;
+    let api_cert = self_signed("api.test")?;
+
+    let server = Server::build()
+        .tls(
+            default_cert.cert.pem(),
+            default_cert.signing_key.serialize_pem(),
+        )?
+        .tls_sni(
+            "api.test",
+            api_cert.cert.pem(),
+            api_cert.signing_key.serialize_pem(),
+        )?
+        .register_handler(request::Request::GET(String::from("/hello")), |_| {
+            Ok(response::Response::Ok("Hello, Crag-Web!".to_string()))
+        })
+        .register_error_handler(handler::default_error_404_handler)?
+        .finalize(("127.0.0.1", 12347), 2)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    // each client only trusts the certificate it expects to be served
+    let response = https_get(12347, "localhost", "/hello", default_cert.cert.der(), None)?;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    assert!(response.ends_with("Hello, Crag-Web!"), "{response}");
+
+    let response = https_get(12347, "api.test", "/hello", api_cert.cert.der(), None)?;
+    assert!(response.ends_with("Hello, Crag-Web!"), "{response}");
+
+    // api.test is not served the default certificate
+    assert!(https_get(12347, "api.test", "/hello", default_cert.cert.der(), None).is_err());
+    // unknown names get the default certificate, which isn't valid for them
+    assert!(https_get(12347, "other.test", "/hello", default_cert.cert.der(), None).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_tls_handshake_timeout() -> Result<()> {
+    let cert = self_signed("localhost")?;
+    let server = Server::build()
+        .tls(cert.cert.pem(), cert.signing_key.serialize_pem())?
+        .tls_handshake_timeout(Duration::from_millis(200))
+        .register_handler(request::Request::GET(String::from("/hello")), |_| {
+            Ok(response::Response::Ok("Hello, Crag-Web!".to_string()))
+        })
+        .register_error_handler(handler::default_error_404_handler)?
+        // a single pool thread, which the silent client must not keep
+        .finalize(("127.0.0.1", 12364), 1)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    // connects but never starts the handshake
+    let mut silent = TcpStream::connect("127.0.0.1:12364")?;
+    silent.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut buffer = [0; 16];
+    assert_eq!(silent.read(&mut buffer)?, 0);
+
+    let response = https_get(12364, "localhost", "/hello", cert.cert.der(), None)?;
+    assert!(response.ends_with("Hello, Crag-Web!"), "{response}");
+    Ok(())
+}
+
+#[test]
+fn test_tls_rejects_bad_pem() -> Result<()> {
+    let result = Server::build().tls("not a certificate", "not a key");
+    assert!(result.is_err());
+    Ok(())
+}
+
+/// A CA plus a client certificate it signed for `service-a`
+struct ClientPki {
+    ca_pem: String,
+    client_cert: CertificateDer<'static>,
+    client_key: Vec<u8>,
+}
+
+fn client_pki() -> Result<ClientPki> {
+    let ca_key = rcgen::KeyPair::generate()?;
+    let mut ca_params = rcgen::CertificateParams::new(vec![])?;
+    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, "Test CA");
+    let ca_cert = ca_params.self_signed(&ca_key)?;
+    let issuer = rcgen::Issuer::new(ca_params, ca_key);
+
+    let client_key = rcgen::KeyPair::generate()?;
+    let mut client_params = rcgen::CertificateParams::new(vec!["service-a.internal".to_string()])?;
+    client_params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, "service-a");
+    client_params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+    let client_cert = client_params.signed_by(&client_key, &issuer)?;
+
+    Ok(ClientPki {
+        ca_pem: ca_cert.pem(),
+        client_cert: client_cert.der().clone(),
+        client_key: client_key.serialize_der(),
+    })
+}
+
+fn whoami(req: request::Request) -> anyhow::Result<response::Response> {
+    let body = match req.peer_certificate() {
+        Some(cert) => {
+            let dns_names: Vec<&str> = cert
+                .subject_alt_names()
+                .iter()
+                .filter_map(|san| match san {
+                    SubjectAltName::Dns(name) => Some(name.as_str()),
+                    _ => None,
+                })
+                .collect();
+            format!("{} {}", cert.subject(), dns_names.join(","))
+        }
+        None => "anonymous".to_string(),
+    };
+    Ok(response::Response::Ok(body))
+}
+
+#[test]
+fn test_mutual_tls() -> Result<()> {
+    let server_cert = self_signed("localhost")?;
+    let pki = client_pki()?;
+    let client_cert = || -> Result<_> {
+        Ok(Some((
+            pki.client_cert.clone(),
+            PrivateKeyDer::try_from(pki.client_key.clone()).map_err(anyhow::Error::msg)?,
+        )))
+    };
+
+    let optional = Server::build()
+        .tls(
+            server_cert.cert.pem(),
+            server_cert.signing_key.serialize_pem(),
+        )?
+        .tls_client_auth(&pki.ca_pem, ClientAuth::Optional)?
+        .register_handler(request::Request::GET(String::from("/whoami")), whoami)
+        .register_error_handler(handler::default_error_404_handler)?
+        .finalize(("127.0.0.1", 12348), 2)?;
+    let required = Server::build()
+        .tls(
+            server_cert.cert.pem(),
+            server_cert.signing_key.serialize_pem(),
+        )?
+        .tls_client_auth(&pki.ca_pem, ClientAuth::Required)?
+        .register_handler(request::Request::GET(String::from("/whoami")), whoami)
+        .register_error_handler(handler::default_error_404_handler)?
+        .finalize(("127.0.0.1", 12349), 2)?;
+    thread::spawn(move || optional.run().unwrap());
+    thread::spawn(move || required.run().unwrap());
+
+    let trusted = server_cert.cert.der();
+
+    let response = https_get(12348, "localhost", "/whoami", trusted, client_cert()?)?;
+    assert!(
+        response.ends_with("CN=service-a service-a.internal"),
+        "{response}"
+    );
+
+    let response = https_get(12348, "localhost", "/whoami", trusted, None)?;
+    assert!(response.ends_with("anonymous"), "{response}");
+
+    let response = https_get(12349, "localhost", "/whoami", trusted, client_cert()?)?;
+    assert!(
+        response.ends_with("CN=service-a service-a.internal"),
+        "{response}"
+    );
+
+    // the handshake fails without a client certificate
+    assert!(https_get(12349, "localhost", "/whoami", trusted, None).is_err());
+
+    Ok(())
+}
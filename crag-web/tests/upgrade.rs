@@ -0,0 +1,83 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+use anyhow::Result;
+use crag_web::{handler, request, response, server::Server};
+
+/// Upgrades to a line based protocol that echoes every line in reverse
+fn reverse(req: request::Request) -> anyhow::Result<response::Response> {
+    if !req.wants_upgrade("reverse") {
+        return Ok(response::Response::new(426).with_header("Upgrade", "reverse"));
+    }
+    Ok(response::Response::upgrade("reverse", |stream| {
+        let mut lines = BufReader::new(stream);
+        let mut line = String::new();
+        while let Ok(1..) = lines.read_line(&mut line) {
+            let reversed: String = line.trim_end().chars().rev().collect();
+            if lines
+                .get_mut()
+                .write_all(format!("{reversed}\n").as_bytes())
+                .is_err()
+            {
+                break;
+            }
+            line.clear();
+        }
+    }))
+}
+
+#[test]
+fn test_upgrade_to_custom_protocol() -> Result<()> {
+    let server = Server::build()
+        .register_handler(request::Request::GET(String::from("/reverse")), reverse)
+        .register_error_handler(handler::default_error_404_handler)?
+        .finalize(("127.0.0.1", 12353), 1)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let mut stream = TcpStream::connect("127.0.0.1:12353")?;
+    // the first line goes out with the request, before the 101 arrived
+    stream.write_all(
+        b"GET /reverse HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: reverse\r\n\r\nhello\n",
+    )?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        head.push_str(&line);
+        if line == "\r\n" {
+            break;
+        }
+    }
+    assert!(
+        head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+        "{head}"
+    );
+    assert!(head.contains("Upgrade: reverse\r\n"), "{head}");
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    assert_eq!(line, "olleh\n");
+
+    stream.write_all(b"crag-web\n")?;
+    line.clear();
+    reader.read_line(&mut line)?;
+    assert_eq!(line, "bew-garc\n");
+
+    // the pool thread was handed back, plain requests still get through
+    let mut plain = TcpStream::connect("127.0.0.1:12353")?;
+    plain.write_all(b"GET /reverse HTTP/1.1\r\n\r\n")?;
+    let mut response = String::new();
+    plain.read_to_string(&mut response)?;
+    assert!(
+        response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"),
+        "{response}"
+    );
+
+    Ok(())
+}
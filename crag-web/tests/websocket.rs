@@ -0,0 +1,107 @@
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use crag_web::websocket::Message;
+use crag_web::{handler, request, response, server::Server};
+use tungstenite::protocol::frame::coding::{Data, OpCode};
+use tungstenite::protocol::frame::Frame;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::WebSocket;
+
+fn connect(path: &str) -> Result<WebSocket<MaybeTlsStream<TcpStream>>> {
+    let (socket, response) = tungstenite::connect(format!("ws://127.0.0.1:12352{path}"))?;
+    assert_eq!(response.status(), 101);
+    Ok(socket)
+}
+
+#[tokio::test]
+async fn test_websocket() -> Result<()> {
+    let server = Server::build()
+        .register_handler(request::Request::GET(String::from("/hello")), |_| {
+            Ok(response::Response::Ok("Hello, Crag-Web!".to_string()))
+        })
+        .register_websocket("/echo", |_req, mut ws| loop {
+            match ws.recv()? {
+                Message::Text(text) if text == "fragment me" => {
+                    ws.send_fragmented(Message::Text("a fragmented reply".to_string()), 4)?
+                }
+                Message::Text(text) => ws.send(Message::Text(text.to_uppercase()))?,
+                Message::Binary(data) => ws.send(Message::Binary(data))?,
+                Message::Close(_) => return Ok(()),
+                Message::Ping(_) | Message::Pong(_) => {}
+            }
+        })
+        .register_websocket("/ticks", |_req, mut ws| {
+            // a dashboard style session: push updates until the client leaves
+            for tick in 0.. {
+                match ws.recv_timeout(Duration::from_millis(10))? {
+                    Some(Message::Close(_)) => break,
+                    Some(_) | None => ws.send(Message::Text(format!("tick {tick}")))?,
+                }
+            }
+            Ok(())
+        })
+        .register_error_handler(handler::default_error_404_handler)?
+        // a single pool thread: sessions must not hold on to it
+        .finalize(("127.0.0.1", 12352), 1)?;
+
+    let _server_join = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let mut echo = connect("/echo")?;
+    let mut ticks = connect("/ticks")?;
+
+    // plain requests are still served while both sessions are open
+    let r = reqwest::get("http://127.0.0.1:12352/hello").await?;
+    assert_eq!(r.text().await?, "Hello, Crag-Web!");
+
+    echo.send(tungstenite::Message::text("hello"))?;
+    assert_eq!(echo.read()?, tungstenite::Message::text("HELLO"));
+
+    echo.send(tungstenite::Message::binary(vec![1, 2, 3]))?;
+    assert_eq!(echo.read()?, tungstenite::Message::binary(vec![1, 2, 3]));
+
+    echo.send(tungstenite::Message::Ping(b"are you there".to_vec()))?;
+    assert_eq!(
+        echo.read()?,
+        tungstenite::Message::Pong(b"are you there".to_vec())
+    );
+
+    // a message the client splits into frames arrives in one piece
+    echo.send(tungstenite::Message::Frame(Frame::message(
+        b"frag".to_vec(),
+        OpCode::Data(Data::Text),
+        false,
+    )))?;
+    echo.send(tungstenite::Message::Frame(Frame::message(
+        b"mented".to_vec(),
+        OpCode::Data(Data::Continue),
+        true,
+    )))?;
+    assert_eq!(echo.read()?, tungstenite::Message::text("FRAGMENTED"));
+
+    // and so does one the server splits
+    echo.send(tungstenite::Message::text("fragment me"))?;
+    assert_eq!(
+        echo.read()?,
+        tungstenite::Message::text("a fragmented reply")
+    );
+
+    echo.close(None)?;
+    assert!(matches!(echo.read()?, tungstenite::Message::Close(_)));
+
+    for _ in 0..3 {
+        let tick = ticks.read()?;
+        assert!(tick.to_text()?.starts_with("tick "), "{tick}");
+    }
+    ticks.close(None)?;
+
+    // not a handshake
+    let r = reqwest::get("http://127.0.0.1:12352/echo").await?;
+    assert_eq!(r.status(), 426);
+
+    Ok(())
+}
@@ -0,0 +1,14 @@
+use std::path::PathBuf;
+
+use crag_web::embed::Embed;
+
+/// Compile the site into the binary, so it runs from anywhere
+fn main() -> anyhow::Result<()> {
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
+    for dir in ["css", "html", "images", "scripts"] {
+        Embed::new(format!("static/{dir}"))
+            .precompress(true)
+            .generate(out_dir.join(format!("{dir}.rs")))?;
+    }
+    Ok(())
+}